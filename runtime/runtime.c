@@ -11,9 +11,21 @@ static const char* bool_str(int32_t x) { return x ? "TRUE" : "FALSE"; }
 #define MEM_CELLS 134217728
 static int32_t mem_cells[MEM_CELLS];
 static int32_t g_here_bytes = 0;
+// Static data image from the compiler: (byte address, value) pairs.
+static const int32_t* g_data_image = NULL;
+static int32_t g_data_image_len = 0;
 
 static int g_pushback = -1;
 
+// ALLOCATE / FREE / RESIZE heap state (see the block layout below).
+#define IOR_ALLOCATE (-59)
+#define IOR_FREE (-60)
+#define IOR_RESIZE (-61)
+static int32_t g_heap_last = -1;
+static int32_t g_heap_end = 0;
+static int32_t g_heap_freed = 0;
+static int32_t g_ior = 0;
+
 static const float KP_PI_F = 3.14159265358979323846f;
 static const float KP_DEG2RAD_F = 3.14159265358979323846f / 180.0f;
 static const float KP_RAD2DEG_F = 180.0f / 3.14159265358979323846f;
@@ -50,16 +62,35 @@ static int next_token(char* buf, size_t cap) {
   return 1;
 }
 
+void rt_div_zero(const char* word) {
+  fflush(stdout);
+  fprintf(stderr, "runtime error: division by zero in word %s\n", word);
+  exit(1);
+}
+
 static int32_t clamp_idx(int32_t idx) {
   if (idx < 0) return 0;
   if (idx >= MEM_CELLS) return MEM_CELLS - 1;
   return idx;
 }
 
+void rt_set_data_image(const int32_t* pairs, int32_t n) {
+  g_data_image = pairs;
+  g_data_image_len = n;
+}
+
 void rt_heap_reset(int32_t base) {
+  int32_t i;
   if (base < 0) base = 0;
   if (base > MEM_CELLS * 4) base = MEM_CELLS * 4;
   g_here_bytes = base;
+  g_heap_last = -1;
+  g_heap_end = 0;
+  g_heap_freed = 0;
+  g_ior = 0;
+  for (i = 0; i < g_data_image_len; i++) {
+    mem_cells[clamp_idx(g_data_image[2 * i] / 4)] = g_data_image[2 * i + 1];
+  }
 }
 int32_t rt_here(void) { return g_here_bytes; }
 void rt_allot(int32_t n) {
@@ -68,6 +99,177 @@ void rt_allot(int32_t n) {
   if (next > (int64_t)MEM_CELLS * 4) next = (int64_t)MEM_CELLS * 4;
   g_here_bytes = (int32_t)next;
 }
+void rt_comma(int32_t v) {
+  mem_cells[clamp_idx(g_here_bytes / 4)] = v;
+  rt_allot(4);
+}
+
+// Heap blocks are carved from HERE. Each payload follows a two-cell header:
+// its size in bytes (bit 0 set once freed) and the header of the block
+// carved before it, so the chain runs newest first in falling addresses.
+static int32_t heap_prev(int32_t h) {
+  int32_t prev = mem_cells[h / 4 + 1];
+  return prev >= 0 && prev < h && (prev & 3) == 0 ? prev : -1;
+}
+
+// Header of the block whose payload starts at `addr`, or -1. above[0] and
+// above[1] get the two headers chained before it (-1 past the newest).
+static int32_t heap_find(int32_t addr, int32_t above[2]) {
+  int32_t h;
+  above[0] = above[1] = -1;
+  for (h = g_heap_last; h >= 0 && h + 8 >= addr; h = heap_prev(h)) {
+    if (h + 8 == addr) return h;
+    above[1] = above[0];
+    above[0] = h;
+  }
+  return -1;
+}
+
+static int32_t heap_block(int32_t addr) {
+  int32_t above[2];
+  return heap_find(addr, above);
+}
+
+// Chains `h` after `newer` (as the newest block when newer is -1).
+static void heap_link(int32_t newer, int32_t h) {
+  if (newer >= 0) mem_cells[newer / 4 + 1] = h;
+  else g_heap_last = h;
+}
+
+int32_t rt_allocate(int32_t n) {
+  int64_t size = ((int64_t)(uint32_t)n + 3) & ~(int64_t)3;
+  int32_t h, next = -1;
+  for (h = g_heap_last; h >= 0; next = h, h = heap_prev(h)) {
+    int32_t info = mem_cells[h / 4];
+    int32_t have = info & ~3;
+    if (!(info & 1) || have < size) continue;
+    if (have - size >= 12) {
+      int32_t rest = h + 8 + (int32_t)size;
+      mem_cells[rest / 4] = (have - (int32_t)size - 8) | 1;
+      mem_cells[rest / 4 + 1] = h;
+      if (next >= 0) mem_cells[next / 4 + 1] = rest;
+      else g_heap_last = rest;
+      have = (int32_t)size;
+      g_heap_freed++;
+    }
+    mem_cells[h / 4] = have;
+    g_heap_freed--;
+    g_ior = 0;
+    return h + 8;
+  }
+  h = (int32_t)(((int64_t)g_here_bytes + 3) & ~(int64_t)3);
+  if (h < g_heap_end || (int64_t)h + 8 + size > (int64_t)MEM_CELLS * 4) {
+    g_ior = IOR_ALLOCATE;
+    return 0;
+  }
+  mem_cells[h / 4] = (int32_t)size;
+  mem_cells[h / 4 + 1] = g_heap_last;
+  g_heap_last = h;
+  g_heap_end = h + 8 + (int32_t)size;
+  g_here_bytes = g_heap_end;
+  g_ior = 0;
+  return h + 8;
+}
+
+// A freed block absorbs a free block that starts right after it and is
+// absorbed by one that ends right before it.
+int32_t rt_free(int32_t addr) {
+  int32_t above[2], h = heap_find(addr, above), below, size;
+  if (h < 0 || (mem_cells[h / 4] & 1)) return IOR_FREE;
+  size = mem_cells[h / 4];
+  g_heap_freed++;
+  if (above[0] >= 0 && (mem_cells[above[0] / 4] & 1) && h + 8 + size == above[0]) {
+    size += 8 + (mem_cells[above[0] / 4] & ~3);
+    heap_link(above[1], h);
+    above[0] = above[1];
+    g_heap_freed--;
+  }
+  below = heap_prev(h);
+  if (below >= 0 && (mem_cells[below / 4] & 1) && below + 8 + (mem_cells[below / 4] & ~3) == h) {
+    mem_cells[below / 4] += 8 + size;
+    heap_link(above[0], below);
+    g_heap_freed--;
+    return 0;
+  }
+  mem_cells[h / 4] = size | 1;
+  return 0;
+}
+
+int32_t rt_resize(int32_t addr, int32_t n) {
+  int32_t h = heap_block(addr);
+  int32_t have, to, i;
+  if (h < 0 || (mem_cells[h / 4] & 1)) {
+    g_ior = IOR_RESIZE;
+    return addr;
+  }
+  have = mem_cells[h / 4];
+  if ((uint32_t)n <= (uint32_t)have) {
+    g_ior = 0;
+    return addr;
+  }
+  to = rt_allocate(n);
+  if (g_ior != 0) {
+    g_ior = IOR_RESIZE;
+    return addr;
+  }
+  for (i = 0; i < have / 4; i++) mem_cells[to / 4 + i] = mem_cells[addr / 4 + i];
+  rt_free(addr);
+  return to;
+}
+
+int32_t rt_ior(void) { return g_ior; }
+
+// Pascal new / dispose: the same heap, but failures trap.
+int32_t rt_pnew(int32_t n) {
+  int32_t p = rt_allocate(n);
+  if (g_ior != 0) {
+    fflush(stdout);
+    fprintf(stderr, "runtime error: out of memory in new (%d bytes)\n", n);
+    exit(1);
+  }
+  return p;
+}
+
+void rt_pdispose(int32_t addr) {
+  if (rt_free(addr) != 0) {
+    fflush(stdout);
+    fprintf(stderr, "runtime error: dispose of invalid pointer %d\n", addr);
+    exit(1);
+  }
+}
+
+// --check-memory: called before each fetch (kind 0) or store (1) with its
+// byte address, and before FREE (2) or RESIZE (3) with the pointer.
+void rt_mem_check(int32_t addr, int32_t kind, const char* word) {
+  static const char* const kinds[] = {"fetch", "store", "free", "resize"};
+  const char* why = NULL;
+  int32_t h;
+  if (kind >= 2) {
+    h = heap_block(addr);
+    if (h < 0) why = "invalid";
+    else if (mem_cells[h / 4] & 1) why = kind == 2 ? "double" : "use-after-free";
+  } else if (addr < 0 || addr >= MEM_CELLS * 4) {
+    why = "out-of-range";
+  } else if (addr & 3) {
+    why = "misaligned";
+  } else if (g_heap_freed > 0) {
+    // Only the block below addr can hold it; stop once every free block is passed.
+    int32_t left = g_heap_freed;
+    for (h = g_heap_last; h >= 0 && left > 0; h = heap_prev(h)) {
+      int32_t info = mem_cells[h / 4];
+      if (h + 8 <= addr) {
+        if ((info & 1) && addr - (h + 8) < (info & ~3)) why = "use-after-free";
+        break;
+      }
+      if (info & 1) left--;
+    }
+  }
+  if (!why) return;
+  fflush(stdout);
+  fprintf(stderr, "runtime error: %s %s at address %d in word %s\n", why, kinds[kind & 3], addr,
+          word);
+  exit(1);
+}
 
 static float bits_to_f32(int32_t bits) {
   union {
@@ -96,6 +298,9 @@ static int is_inf_bits_u32(uint32_t u) { return fexp_raw_u32(u) == 0xFFu && ffra
 static int is_finite_bits_u32(uint32_t u) { return fexp_raw_u32(u) != 0xFFu; }
 
 void pwrite_i32(int32_t x) { printf("%d", x); }
+void pwrite_u32(int32_t x) { printf("%u", (uint32_t)x); }
+void pwrite_i64(int64_t x) { printf("%lld", (long long)x); }
+void pwrite_i64_r(int64_t x, int32_t width) { printf("%*lld", (int)width, (long long)x); }
 void pwrite_bool(int32_t x) { printf("%s", bool_str(x)); }
 void pwrite_char(int32_t x) { putchar((unsigned char)(x & 0xFF)); }
 void pwrite_hex(int32_t x) { printf("%08X", (uint32_t)x); }
@@ -106,9 +311,9 @@ int32_t pbool(int32_t x);
 int32_t pread_i32(void) {
   char tok[256];
   char* end = NULL;
-  long v;
+  long long v;
   if (!next_token(tok, sizeof(tok))) return 0;
-  v = strtol(tok, &end, 10);
+  v = strtoll(tok, &end, 10);
   if (end == tok || *end != '\0') return 0;
   return (int32_t)v;
 }
@@ -120,10 +325,10 @@ int32_t pread_bool(void) {
 int32_t pread_char(void) {
   char tok[256];
   char* end = NULL;
-  long v;
+  long long v;
   if (!next_token(tok, sizeof(tok))) return 0;
   if (tok[0] != '\0' && tok[1] == '\0') return (unsigned char)tok[0];
-  v = strtol(tok, &end, 10);
+  v = strtoll(tok, &end, 10);
   if (end == tok || *end != '\0') return 0;
   return (int32_t)v;
 }
@@ -0,0 +1,1040 @@
+/* Token-threaded VM for `kforthc --emit=bytecode` images.
+ *
+ *   cc runtime/vm.c runtime/runtime.c -o kfvm -lm
+ *   ./kfvm out.kfb
+ *
+ * The image layout and token numbers are defined in src/bytecode.rs. The
+ * loader checks the image and resolves its imports against the runtime.c
+ * services below; words then run on a heap-allocated frame stack, so deep
+ * recursion does not use the C stack. */
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+/* runtime.c */
+void rt_div_zero(const char* word);
+void rt_mem_check(int32_t addr, int32_t kind, const char* word);
+void rt_set_data_image(const int32_t* pairs, int32_t n);
+void rt_heap_reset(int32_t base);
+int32_t rt_here(void);
+void rt_allot(int32_t n);
+void rt_comma(int32_t v);
+int32_t rt_allocate(int32_t n);
+int32_t rt_free(int32_t addr);
+int32_t rt_resize(int32_t addr, int32_t n);
+int32_t rt_ior(void);
+int32_t rt_pnew(int32_t n);
+void rt_pdispose(int32_t addr);
+void pwrite_i32(int32_t x);
+void pwrite_u32(int32_t x);
+void pwrite_i64(int64_t x);
+void pwrite_i64_r(int64_t x, int32_t width);
+void pwrite_bool(int32_t x);
+void pwrite_char(int32_t x);
+void pwrite_hex(int32_t x);
+void pwriteln(void);
+void pwrite_str(const char* s);
+void pwrite_f32_bits(int32_t bits);
+int32_t pread_i32(void);
+int32_t pread_bool(void);
+int32_t pread_char(void);
+int32_t pread_f32_bits(void);
+void preadln(void);
+int32_t pvar_get(int32_t id);
+void pvar_set(int32_t v, int32_t id);
+int32_t pfield_get(int32_t obj, int32_t off);
+void pfield_set(int32_t v, int32_t obj, int32_t off);
+int32_t pbool(int32_t x);
+int32_t fadd_bits(int32_t a, int32_t b);
+int32_t fsub_bits(int32_t a, int32_t b);
+int32_t fmul_bits(int32_t a, int32_t b);
+int32_t fdiv_bits(int32_t a, int32_t b);
+int32_t fnegate_bits(int32_t a);
+int32_t fabs_bits(int32_t a);
+int32_t feq_bits(int32_t a, int32_t b);
+int32_t flt_bits(int32_t a, int32_t b);
+int32_t fle_bits(int32_t a, int32_t b);
+int32_t fzero_bits(int32_t a);
+int32_t finf_bits(int32_t a);
+int32_t fnan_bits(int32_t a);
+int32_t ffinite_bits(int32_t a);
+int32_t s_to_f_bits(int32_t a);
+int32_t f_bits_to_s(int32_t a);
+int32_t q16_16_to_f_bits(int32_t a);
+int32_t f_bits_to_q16_16(int32_t a);
+int32_t fround_i32_bits(int32_t a);
+int32_t kp_fabs_f32_bits(int32_t a);
+int32_t kp_fsqrt_f32_bits(int32_t a);
+int32_t kp_fsin_f32_bits(int32_t a);
+int32_t kp_fcos_f32_bits(int32_t a);
+int32_t kp_fpow_f32_i32_bits(int32_t a, int32_t n);
+int32_t kp_ffloor_f32_bits(int32_t a);
+int32_t kp_fceil_f32_bits(int32_t a);
+int32_t kp_fx_sqrt_i32(int32_t x);
+int32_t kp_fx_sin_deg_i32(int32_t a);
+int32_t kp_fx_cos_deg_i32(int32_t a);
+int32_t kp_fx_tan_deg_i32(int32_t a);
+int32_t kp_fx_asin_fixed_i32(int32_t v);
+int32_t kp_fx_acos_fixed_i32(int32_t v);
+int32_t kp_fx_atan_fixed_i32(int32_t v);
+int32_t kp_fx_ln_i32(int32_t x);
+int32_t kp_fx_log_i32(int32_t x);
+
+typedef void (*vm_fn)(void);
+
+static const struct {
+  const char* name;
+  vm_fn fn;
+} kVmServices[] = {
+    {"rt_here", (vm_fn)rt_here},
+    {"rt_allot", (vm_fn)rt_allot},
+    {"rt_comma", (vm_fn)rt_comma},
+    {"rt_allocate", (vm_fn)rt_allocate},
+    {"rt_free", (vm_fn)rt_free},
+    {"rt_resize", (vm_fn)rt_resize},
+    {"rt_ior", (vm_fn)rt_ior},
+    {"rt_pnew", (vm_fn)rt_pnew},
+    {"rt_pdispose", (vm_fn)rt_pdispose},
+    {"pwrite_i32", (vm_fn)pwrite_i32},
+    {"pwrite_u32", (vm_fn)pwrite_u32},
+    {"pwrite_i64", (vm_fn)pwrite_i64},
+    {"pwrite_i64_r", (vm_fn)pwrite_i64_r},
+    {"pwrite_bool", (vm_fn)pwrite_bool},
+    {"pwrite_char", (vm_fn)pwrite_char},
+    {"pwrite_hex", (vm_fn)pwrite_hex},
+    {"pwriteln", (vm_fn)pwriteln},
+    {"pwrite_f32_bits", (vm_fn)pwrite_f32_bits},
+    {"pread_i32", (vm_fn)pread_i32},
+    {"pread_bool", (vm_fn)pread_bool},
+    {"pread_char", (vm_fn)pread_char},
+    {"pread_f32_bits", (vm_fn)pread_f32_bits},
+    {"preadln", (vm_fn)preadln},
+    {"pvar_get", (vm_fn)pvar_get},
+    {"pvar_set", (vm_fn)pvar_set},
+    {"pfield_get", (vm_fn)pfield_get},
+    {"pfield_set", (vm_fn)pfield_set},
+    {"pbool", (vm_fn)pbool},
+    {"fadd_bits", (vm_fn)fadd_bits},
+    {"fsub_bits", (vm_fn)fsub_bits},
+    {"fmul_bits", (vm_fn)fmul_bits},
+    {"fdiv_bits", (vm_fn)fdiv_bits},
+    {"fnegate_bits", (vm_fn)fnegate_bits},
+    {"fabs_bits", (vm_fn)fabs_bits},
+    {"feq_bits", (vm_fn)feq_bits},
+    {"flt_bits", (vm_fn)flt_bits},
+    {"fle_bits", (vm_fn)fle_bits},
+    {"fzero_bits", (vm_fn)fzero_bits},
+    {"finf_bits", (vm_fn)finf_bits},
+    {"fnan_bits", (vm_fn)fnan_bits},
+    {"ffinite_bits", (vm_fn)ffinite_bits},
+    {"s_to_f_bits", (vm_fn)s_to_f_bits},
+    {"f_bits_to_s", (vm_fn)f_bits_to_s},
+    {"q16_16_to_f_bits", (vm_fn)q16_16_to_f_bits},
+    {"f_bits_to_q16_16", (vm_fn)f_bits_to_q16_16},
+    {"fround_i32_bits", (vm_fn)fround_i32_bits},
+    {"kp_fabs_f32_bits", (vm_fn)kp_fabs_f32_bits},
+    {"kp_fsqrt_f32_bits", (vm_fn)kp_fsqrt_f32_bits},
+    {"kp_fsin_f32_bits", (vm_fn)kp_fsin_f32_bits},
+    {"kp_fcos_f32_bits", (vm_fn)kp_fcos_f32_bits},
+    {"kp_fpow_f32_i32_bits", (vm_fn)kp_fpow_f32_i32_bits},
+    {"kp_ffloor_f32_bits", (vm_fn)kp_ffloor_f32_bits},
+    {"kp_fceil_f32_bits", (vm_fn)kp_fceil_f32_bits},
+    {"kp_fx_sqrt_i32", (vm_fn)kp_fx_sqrt_i32},
+    {"kp_fx_sin_deg_i32", (vm_fn)kp_fx_sin_deg_i32},
+    {"kp_fx_cos_deg_i32", (vm_fn)kp_fx_cos_deg_i32},
+    {"kp_fx_tan_deg_i32", (vm_fn)kp_fx_tan_deg_i32},
+    {"kp_fx_asin_fixed_i32", (vm_fn)kp_fx_asin_fixed_i32},
+    {"kp_fx_acos_fixed_i32", (vm_fn)kp_fx_acos_fixed_i32},
+    {"kp_fx_atan_fixed_i32", (vm_fn)kp_fx_atan_fixed_i32},
+    {"kp_fx_ln_i32", (vm_fn)kp_fx_ln_i32},
+    {"kp_fx_log_i32", (vm_fn)kp_fx_log_i32},
+};
+
+/* Import argument modes (kir::ExternArgMode). */
+enum {
+  MODE_POP_I32_VOID,
+  MODE_VOID,
+  MODE_RET_I32_PUSH,
+  MODE_POP_I32_RET_I32_PUSH,
+  MODE_STR_VOID,
+  MODE_POP2_I32_VOID,
+  MODE_POP2_I32_RET_I32_PUSH,
+  MODE_POP3_I32_VOID,
+  MODE_POP_I64_VOID,
+  MODE_POP_I64_I32_VOID,
+  MODE_COUNT
+};
+
+enum {
+  OP_EXIT = 0x00,
+  OP_LIT = 0x01,
+  OP_LIT8 = 0x02,
+  OP_CALL = 0x03,
+  OP_TAIL = 0x04,
+  OP_JUMP = 0x05,
+  OP_BRANCH0 = 0x06,
+  OP_SVC = 0x07,
+  OP_TYPE = 0x08,
+  OP_PICKC = 0x09,
+  OP_ROLLC = 0x0A,
+  OP_MEMCHK = 0x0B,
+  /* Primitives, in the order of PRIMITIVES in src/bytecode.rs. */
+  OP_DUP = 0x10,
+  OP_DROP,
+  OP_SWAP,
+  OP_OVER,
+  OP_ROT,
+  OP_MINUS_ROT,
+  OP_NIP,
+  OP_TUCK,
+  OP_QDUP,
+  OP_TWO_DUP,
+  OP_TWO_DROP,
+  OP_TWO_SWAP,
+  OP_TWO_OVER,
+  OP_PICK,
+  OP_ROLL,
+  OP_DEPTH,
+  OP_TO_R,
+  OP_R_FROM,
+  OP_R_FETCH,
+  OP_TWO_TO_R,
+  OP_TWO_R_FROM,
+  OP_TWO_R_FETCH,
+  OP_ADD,
+  OP_SUB,
+  OP_MUL,
+  OP_DIV,
+  OP_MOD,
+  OP_DIV_MOD,
+  OP_STAR_SLASH,
+  OP_STAR_SLASH_MOD,
+  OP_FM_MOD,
+  OP_SM_REM,
+  OP_NEGATE,
+  OP_ABS,
+  OP_MIN,
+  OP_MAX,
+  OP_ONE_PLUS,
+  OP_ONE_MINUS,
+  OP_TWO_STAR,
+  OP_TWO_SLASH,
+  OP_AND,
+  OP_OR,
+  OP_XOR,
+  OP_INVERT,
+  OP_LSHIFT,
+  OP_RSHIFT,
+  OP_ARSHIFT,
+  OP_CELLS,
+  OP_CELL_PLUS,
+  OP_EQ,
+  OP_NE,
+  OP_LT,
+  OP_LE,
+  OP_GT,
+  OP_GE,
+  OP_ULT,
+  OP_UGT,
+  OP_ZERO_EQ,
+  OP_ZERO_LT,
+  OP_UDIV,
+  OP_UMOD,
+  OP_UM_STAR,
+  OP_UM_SLASH_MOD,
+  OP_S_TO_D,
+  OP_D_ADD,
+  OP_D_SUB,
+  OP_D_NEGATE,
+  OP_D_ABS,
+  OP_D_EQ,
+  OP_D_LT,
+  OP_D_ZERO_EQ,
+  OP_M_STAR,
+  OP_M_PLUS,
+  OP_D_TWO_STAR,
+  OP_D_TWO_SLASH,
+  OP_TWO_FETCH,
+  OP_TWO_STORE,
+  OP_END
+};
+
+typedef struct {
+  uint32_t name;
+  uint32_t size;
+  const uint8_t* code;
+} VmWord;
+
+typedef struct {
+  vm_fn fn;
+  uint8_t mode;
+} VmImport;
+
+static struct {
+  uint32_t flags;
+  int32_t here;
+  uint32_t entry;
+  const char* strings;
+  uint32_t nstrings;
+  VmImport* imports;
+  uint32_t nimports;
+  int32_t* inits;
+  uint32_t ninits;
+  VmWord* words;
+  uint32_t nwords;
+} img;
+
+static int32_t ds[1024];
+static int32_t sp;
+/* Shared by all active words; each releases what it used on exit. */
+static int32_t rs[65536];
+static int32_t rsp;
+
+/* Images are checked for structure only, so every stack access is bounds
+   checked: a bad image traps like a stack error in the interpreter. */
+static const char* cur_word;
+
+static void stack_error(const char* what) {
+  fflush(stdout);
+  fprintf(stderr, "runtime error: %s in word %s\n", what, cur_word);
+  exit(1);
+}
+
+/* The top n data stack cells exist (ds[sp - n] .. ds[sp - 1]). */
+static void need(int32_t n) {
+  if (sp < n) stack_error("data stack underflow");
+}
+
+static void rneed(int32_t n) {
+  if (rsp < n) stack_error("return stack underflow");
+}
+
+static void push(int32_t v) {
+  if (sp >= 1024) stack_error("data stack overflow");
+  ds[sp++] = v;
+}
+
+static int32_t pop(void) {
+  need(1);
+  return ds[--sp];
+}
+
+/* Cell n below the top (0 = top). */
+static int32_t pick(int32_t n) {
+  if (n < 0 || n >= sp) stack_error("data stack underflow");
+  return ds[sp - 1 - n];
+}
+
+static void rpush(int32_t v) {
+  if (rsp >= 65536) stack_error("return stack overflow");
+  rs[rsp++] = v;
+}
+
+static int32_t rpop(void) {
+  rneed(1);
+  return rs[--rsp];
+}
+
+static uint32_t u16_at(const uint8_t* p) { return (uint32_t)p[0] | (uint32_t)p[1] << 8; }
+
+static uint32_t u32_at(const uint8_t* p) {
+  return (uint32_t)p[0] | (uint32_t)p[1] << 8 | (uint32_t)p[2] << 16 | (uint32_t)p[3] << 24;
+}
+
+static void bad_image(const char* what) {
+  fprintf(stderr, "kfvm: bad image: %s\n", what);
+  exit(2);
+}
+
+/* Loader */
+
+static const uint8_t* rd_p;
+static const uint8_t* rd_end;
+
+static const uint8_t* take(uint32_t n) {
+  const uint8_t* p = rd_p;
+  if ((size_t)(rd_end - rd_p) < n) bad_image("truncated");
+  rd_p += n;
+  return p;
+}
+
+static uint32_t rd_u32(void) { return u32_at(take(4)); }
+
+/* Checked count of entries of `size` bytes each. */
+static uint32_t rd_count(uint32_t size) {
+  uint32_t n = rd_u32();
+  if ((size_t)(rd_end - rd_p) / size < n) bad_image("truncated");
+  return n;
+}
+
+static const char* string_at(uint32_t off) {
+  if (off >= img.nstrings) bad_image("string offset out of range");
+  return img.strings + off;
+}
+
+/* Operand size of a token, or -1. */
+static int operand_size(uint8_t op) {
+  switch (op) {
+    case OP_LIT8:
+      return 1;
+    case OP_CALL:
+    case OP_TAIL:
+    case OP_SVC:
+      return 2;
+    case OP_LIT:
+    case OP_JUMP:
+    case OP_BRANCH0:
+    case OP_TYPE:
+    case OP_PICKC:
+    case OP_ROLLC:
+      return 4;
+    case OP_MEMCHK:
+      return 5;
+    default:
+      return op == OP_EXIT || (op >= OP_DUP && op < OP_END) ? 0 : -1;
+  }
+}
+
+static void verify_word(const VmWord* w) {
+  uint32_t pc = 0;
+  uint8_t last = OP_LIT;
+  while (pc < w->size) {
+    uint8_t op = w->code[pc++];
+    int n = operand_size(op);
+    const uint8_t* arg = w->code + pc;
+    if (n < 0) bad_image("unknown token");
+    if (w->size - pc < (uint32_t)n) bad_image("truncated instruction");
+    if ((op == OP_CALL || op == OP_TAIL) && u16_at(arg) >= img.nwords) bad_image("word index out of range");
+    if (op == OP_SVC && u16_at(arg) >= img.nimports) bad_image("import index out of range");
+    if ((op == OP_JUMP || op == OP_BRANCH0) && u32_at(arg) >= w->size) bad_image("jump out of range");
+    if (op == OP_TYPE) string_at(u32_at(arg));
+    if (op == OP_MEMCHK && arg[0] > 7) bad_image("bad memory check");
+    pc += (uint32_t)n;
+    last = op;
+  }
+  if (last != OP_EXIT && last != OP_JUMP && last != OP_TAIL) bad_image("word falls off its end");
+}
+
+static void load(const char* path) {
+  FILE* f = fopen(path, "rb");
+  uint8_t* buf;
+  long len;
+  uint32_t i;
+  if (!f) {
+    fprintf(stderr, "kfvm: cannot open %s\n", path);
+    exit(2);
+  }
+  if (fseek(f, 0, SEEK_END) != 0 || (len = ftell(f)) < 0 || fseek(f, 0, SEEK_SET) != 0) bad_image("unreadable");
+  buf = malloc(len > 0 ? (size_t)len : 1);
+  if (!buf || fread(buf, 1, (size_t)len, f) != (size_t)len) bad_image("unreadable");
+  fclose(f);
+  rd_p = buf;
+  rd_end = buf + len;
+
+  if (memcmp(take(4), "KFBC", 4) != 0) bad_image("not a kforthc bytecode image");
+  if (rd_u32() != 1) bad_image("unsupported version");
+  img.flags = rd_u32();
+  img.here = (int32_t)rd_u32();
+  img.entry = rd_u32();
+
+  img.nstrings = rd_count(1);
+  img.strings = (const char*)take(img.nstrings);
+  if (img.nstrings > 0 && img.strings[img.nstrings - 1] != '\0') bad_image("unterminated string");
+
+  img.nimports = rd_count(5);
+  img.imports = calloc(img.nimports + 1, sizeof *img.imports);
+  for (i = 0; i < img.nimports; i++) {
+    const char* name = string_at(rd_u32());
+    size_t k;
+    img.imports[i].mode = *take(1);
+    if (img.imports[i].mode >= MODE_COUNT || img.imports[i].mode == MODE_STR_VOID) bad_image("bad import mode");
+    for (k = 0; k < sizeof kVmServices / sizeof kVmServices[0]; k++) {
+      if (strcmp(kVmServices[k].name, name) == 0) img.imports[i].fn = kVmServices[k].fn;
+    }
+    if (!img.imports[i].fn) {
+      fprintf(stderr, "kfvm: unknown runtime service: %s\n", name);
+      exit(2);
+    }
+  }
+
+  img.ninits = rd_count(8);
+  img.inits = calloc(2 * (size_t)img.ninits + 1, sizeof *img.inits);
+  for (i = 0; i < 2 * img.ninits; i++) img.inits[i] = (int32_t)rd_u32();
+
+  img.nwords = rd_count(8);
+  img.words = calloc(img.nwords + 1, sizeof *img.words);
+  for (i = 0; i < img.nwords; i++) {
+    img.words[i].name = rd_u32();
+    string_at(img.words[i].name);
+    img.words[i].size = rd_count(1);
+    img.words[i].code = take(img.words[i].size);
+  }
+  if (rd_p != rd_end) bad_image("trailing bytes");
+  if (img.entry >= img.nwords) bad_image("entry out of range");
+  for (i = 0; i < img.nwords; i++) verify_word(&img.words[i]);
+}
+
+/* Primitives */
+
+static int32_t flag(int c) { return c ? -1 : 0; }
+
+/* Doubles are ( lo hi ) with the high cell on top. */
+static void push2(int64_t d) {
+  push((int32_t)(uint32_t)(uint64_t)d);
+  push((int32_t)(uint32_t)((uint64_t)d >> 32));
+}
+
+static int64_t pop2(void) {
+  uint64_t hi = (uint32_t)pop();
+  uint64_t lo = (uint32_t)pop();
+  return (int64_t)((hi << 32) | lo);
+}
+
+/* Symmetric or floored; MIN / -1 wraps. */
+static void sdivrem(int64_t d, int64_t n, int floored, const char* word, int64_t* r, int64_t* q) {
+  if (n == 0) rt_div_zero(word);
+  if (n == -1) {
+    *q = (int64_t)(0 - (uint64_t)d);
+    *r = 0;
+  } else {
+    *q = d / n;
+    *r = d % n;
+  }
+  if (floored && *r != 0 && (*r ^ n) < 0) {
+    *q -= 1;
+    *r += n;
+  }
+}
+
+static void udivrem(uint64_t d, uint64_t n, const char* word, uint64_t* r, uint64_t* q) {
+  if (n == 0) rt_div_zero(word);
+  *q = d / n;
+  *r = d % n;
+}
+
+static void roll(int32_t u) {
+  int32_t top = sp - 1, k, v;
+  if (u < 0 || u >= sp) stack_error("data stack underflow");
+  k = top - u;
+  v = ds[k];
+  for (; k < top; k++) ds[k] = ds[k + 1];
+  ds[top] = v;
+}
+
+static void service(const VmImport* im) {
+  int32_t a, b, c;
+  int64_t d;
+  switch (im->mode) {
+    case MODE_POP_I32_VOID:
+      a = pop();
+      ((void (*)(int32_t))im->fn)(a);
+      break;
+    case MODE_VOID:
+      ((void (*)(void))im->fn)();
+      break;
+    case MODE_RET_I32_PUSH:
+      a = ((int32_t(*)(void))im->fn)();
+      push(a);
+      break;
+    case MODE_POP_I32_RET_I32_PUSH:
+      a = pop();
+      a = ((int32_t(*)(int32_t))im->fn)(a);
+      push(a);
+      break;
+    case MODE_POP2_I32_VOID:
+      b = pop();
+      a = pop();
+      ((void (*)(int32_t, int32_t))im->fn)(a, b);
+      break;
+    case MODE_POP2_I32_RET_I32_PUSH:
+      b = pop();
+      a = pop();
+      a = ((int32_t(*)(int32_t, int32_t))im->fn)(a, b);
+      push(a);
+      break;
+    case MODE_POP3_I32_VOID:
+      c = pop();
+      b = pop();
+      a = pop();
+      ((void (*)(int32_t, int32_t, int32_t))im->fn)(a, b, c);
+      break;
+    case MODE_POP_I64_VOID:
+      d = pop2();
+      ((void (*)(int64_t))im->fn)(d);
+      break;
+    case MODE_POP_I64_I32_VOID:
+      a = pop();
+      d = pop2();
+      ((void (*)(int64_t, int32_t))im->fn)(d, a);
+      break;
+  }
+}
+
+/* Interpreter */
+
+typedef struct {
+  uint32_t word;
+  uint32_t pc;
+  int32_t rbase;
+} VmFrame;
+
+static void run(void) {
+  size_t cap = 64, fp = 0;
+  VmFrame* frames = malloc(cap * sizeof *frames);
+  uint32_t w = img.entry, pc = 0;
+  int32_t rbase = 0;
+  const uint8_t* code = img.words[w].code;
+  const int floored = (img.flags & 1) != 0;
+  if (!frames) abort();
+  for (;;) {
+    const char* word = img.strings + img.words[w].name;
+    cur_word = word;
+    uint8_t op = code[pc++];
+    int32_t a, b, n;
+    int64_t d, e, r, q;
+    uint64_t ur, uq;
+    switch (op) {
+      case OP_EXIT:
+        rsp = rbase;
+        if (fp == 0) {
+          free(frames);
+          return;
+        }
+        fp--;
+        w = frames[fp].word;
+        pc = frames[fp].pc;
+        rbase = frames[fp].rbase;
+        code = img.words[w].code;
+        break;
+      case OP_LIT:
+        push((int32_t)u32_at(code + pc));
+        pc += 4;
+        break;
+      case OP_LIT8:
+        push((int8_t)code[pc]);
+        pc += 1;
+        break;
+      case OP_CALL:
+        if (fp == cap) {
+          cap *= 2;
+          frames = realloc(frames, cap * sizeof *frames);
+          if (!frames) abort();
+        }
+        frames[fp].word = w;
+        frames[fp].pc = pc + 2;
+        frames[fp].rbase = rbase;
+        fp++;
+        w = u16_at(code + pc);
+        pc = 0;
+        rbase = rsp;
+        code = img.words[w].code;
+        break;
+      case OP_TAIL:
+        rsp = rbase;
+        w = u16_at(code + pc);
+        pc = 0;
+        code = img.words[w].code;
+        break;
+      case OP_JUMP:
+        pc = u32_at(code + pc);
+        break;
+      case OP_BRANCH0:
+        pc = pop() == 0 ? u32_at(code + pc) : pc + 4;
+        break;
+      case OP_SVC:
+        service(&img.imports[u16_at(code + pc)]);
+        pc += 2;
+        break;
+      case OP_TYPE:
+        pwrite_str(img.strings + u32_at(code + pc));
+        pc += 4;
+        break;
+      case OP_PICKC:
+        n = (int32_t)u32_at(code + pc);
+        pc += 4;
+        push(pick(n));
+        break;
+      case OP_ROLLC:
+        roll((int32_t)u32_at(code + pc));
+        pc += 4;
+        break;
+      case OP_MEMCHK:
+        /* flags: bits 0-1 access kind, bit 2 second + top (else top + offset) */
+        need(code[pc] & 4 ? 2 : 1);
+        a = code[pc] & 4 ? ds[sp - 2] : (int32_t)u32_at(code + pc + 1);
+        rt_mem_check((int32_t)((uint32_t)a + (uint32_t)ds[sp - 1]), code[pc] & 3, word);
+        pc += 5;
+        break;
+
+      case OP_DUP:
+        need(1);
+        a = ds[sp - 1];
+        push(a);
+        break;
+      case OP_DROP:
+        need(1);
+        sp--;
+        break;
+      case OP_SWAP:
+        need(2);
+        a = ds[sp - 1];
+        ds[sp - 1] = ds[sp - 2];
+        ds[sp - 2] = a;
+        break;
+      case OP_OVER:
+        need(2);
+        a = ds[sp - 2];
+        push(a);
+        break;
+      case OP_ROT:
+        roll(2);
+        break;
+      case OP_MINUS_ROT:
+        need(3);
+        a = ds[sp - 1];
+        ds[sp - 1] = ds[sp - 2];
+        ds[sp - 2] = ds[sp - 3];
+        ds[sp - 3] = a;
+        break;
+      case OP_NIP:
+        a = pop();
+        need(1);
+        ds[sp - 1] = a;
+        break;
+      case OP_TUCK:
+        b = pop();
+        a = pop();
+        push(b);
+        push(a);
+        push(b);
+        break;
+      case OP_QDUP:
+        need(1);
+        a = ds[sp - 1];
+        if (a != 0) push(a);
+        break;
+      case OP_TWO_DUP:
+        need(2);
+        a = ds[sp - 2];
+        b = ds[sp - 1];
+        push(a);
+        push(b);
+        break;
+      case OP_TWO_DROP:
+        need(2);
+        sp -= 2;
+        break;
+      case OP_TWO_SWAP:
+        roll(3);
+        roll(3);
+        break;
+      case OP_TWO_OVER:
+        need(4);
+        a = ds[sp - 4];
+        b = ds[sp - 3];
+        push(a);
+        push(b);
+        break;
+      case OP_PICK:
+        n = pop();
+        push(pick(n));
+        break;
+      case OP_ROLL:
+        n = pop();
+        roll(n);
+        break;
+      case OP_DEPTH:
+        push(sp);
+        break;
+      case OP_TO_R:
+        rpush(pop());
+        break;
+      case OP_R_FROM:
+        push(rpop());
+        break;
+      case OP_R_FETCH:
+        rneed(1);
+        push(rs[rsp - 1]);
+        break;
+      case OP_TWO_TO_R:
+        b = pop();
+        a = pop();
+        rpush(a);
+        rpush(b);
+        break;
+      case OP_TWO_R_FROM:
+        b = rpop();
+        a = rpop();
+        push(a);
+        push(b);
+        break;
+      case OP_TWO_R_FETCH:
+        rneed(2);
+        push(rs[rsp - 2]);
+        push(rs[rsp - 1]);
+        break;
+      case OP_ADD:
+        b = pop();
+        a = pop();
+        push((int32_t)((uint32_t)a + (uint32_t)b));
+        break;
+      case OP_SUB:
+        b = pop();
+        a = pop();
+        push((int32_t)((uint32_t)a - (uint32_t)b));
+        break;
+      case OP_MUL:
+        b = pop();
+        a = pop();
+        push((int32_t)((uint32_t)a * (uint32_t)b));
+        break;
+      case OP_DIV:
+      case OP_MOD:
+      case OP_DIV_MOD:
+        b = pop();
+        a = pop();
+        sdivrem(a, b, floored, word, &r, &q);
+        if (op != OP_DIV) push((int32_t)r);
+        if (op != OP_MOD) push((int32_t)q);
+        break;
+      case OP_STAR_SLASH:
+      case OP_STAR_SLASH_MOD:
+        n = pop();
+        b = pop();
+        a = pop();
+        sdivrem((int64_t)a * b, n, floored, word, &r, &q);
+        if (op == OP_STAR_SLASH_MOD) push((int32_t)r);
+        push((int32_t)q);
+        break;
+      case OP_FM_MOD:
+      case OP_SM_REM:
+        n = pop();
+        d = pop2();
+        sdivrem(d, n, op == OP_FM_MOD, word, &r, &q);
+        push((int32_t)r);
+        push((int32_t)q);
+        break;
+      case OP_NEGATE:
+        need(1);
+        ds[sp - 1] = (int32_t)(0u - (uint32_t)ds[sp - 1]);
+        break;
+      case OP_ABS:
+        need(1);
+        a = ds[sp - 1];
+        ds[sp - 1] = a < 0 ? (int32_t)(0u - (uint32_t)a) : a;
+        break;
+      case OP_MIN:
+        b = pop();
+        a = pop();
+        push(a < b ? a : b);
+        break;
+      case OP_MAX:
+        b = pop();
+        a = pop();
+        push(a > b ? a : b);
+        break;
+      case OP_ONE_PLUS:
+        need(1);
+        ds[sp - 1] = (int32_t)((uint32_t)ds[sp - 1] + 1u);
+        break;
+      case OP_ONE_MINUS:
+        need(1);
+        ds[sp - 1] = (int32_t)((uint32_t)ds[sp - 1] - 1u);
+        break;
+      case OP_TWO_STAR:
+        need(1);
+        ds[sp - 1] = (int32_t)((uint32_t)ds[sp - 1] << 1);
+        break;
+      case OP_TWO_SLASH:
+        need(1);
+        ds[sp - 1] >>= 1;
+        break;
+      case OP_AND:
+        b = pop();
+        need(1);
+        ds[sp - 1] &= b;
+        break;
+      case OP_OR:
+        b = pop();
+        need(1);
+        ds[sp - 1] |= b;
+        break;
+      case OP_XOR:
+        b = pop();
+        need(1);
+        ds[sp - 1] ^= b;
+        break;
+      case OP_INVERT:
+        need(1);
+        ds[sp - 1] = ~ds[sp - 1];
+        break;
+      case OP_LSHIFT:
+        b = pop();
+        need(1);
+        ds[sp - 1] = (int32_t)((uint32_t)ds[sp - 1] << (b & 31));
+        break;
+      case OP_RSHIFT:
+        b = pop();
+        need(1);
+        ds[sp - 1] = (int32_t)((uint32_t)ds[sp - 1] >> (b & 31));
+        break;
+      case OP_ARSHIFT:
+        b = pop();
+        need(1);
+        ds[sp - 1] >>= (b & 31);
+        break;
+      case OP_CELLS:
+        need(1);
+        ds[sp - 1] = (int32_t)((uint32_t)ds[sp - 1] * 4u);
+        break;
+      case OP_CELL_PLUS:
+        need(1);
+        ds[sp - 1] = (int32_t)((uint32_t)ds[sp - 1] + 4u);
+        break;
+      case OP_EQ:
+        b = pop();
+        need(1);
+        ds[sp - 1] = flag(ds[sp - 1] == b);
+        break;
+      case OP_NE:
+        b = pop();
+        need(1);
+        ds[sp - 1] = flag(ds[sp - 1] != b);
+        break;
+      case OP_LT:
+        b = pop();
+        need(1);
+        ds[sp - 1] = flag(ds[sp - 1] < b);
+        break;
+      case OP_LE:
+        b = pop();
+        need(1);
+        ds[sp - 1] = flag(ds[sp - 1] <= b);
+        break;
+      case OP_GT:
+        b = pop();
+        need(1);
+        ds[sp - 1] = flag(ds[sp - 1] > b);
+        break;
+      case OP_GE:
+        b = pop();
+        need(1);
+        ds[sp - 1] = flag(ds[sp - 1] >= b);
+        break;
+      case OP_ULT:
+        b = pop();
+        need(1);
+        ds[sp - 1] = flag((uint32_t)ds[sp - 1] < (uint32_t)b);
+        break;
+      case OP_UGT:
+        b = pop();
+        need(1);
+        ds[sp - 1] = flag((uint32_t)ds[sp - 1] > (uint32_t)b);
+        break;
+      case OP_ZERO_EQ:
+        need(1);
+        ds[sp - 1] = flag(ds[sp - 1] == 0);
+        break;
+      case OP_ZERO_LT:
+        need(1);
+        ds[sp - 1] = flag(ds[sp - 1] < 0);
+        break;
+      case OP_UDIV:
+      case OP_UMOD:
+        b = pop();
+        a = pop();
+        udivrem((uint32_t)a, (uint32_t)b, word, &ur, &uq);
+        push((int32_t)(uint32_t)(op == OP_UDIV ? uq : ur));
+        break;
+      case OP_UM_STAR:
+        b = pop();
+        a = pop();
+        push2((int64_t)((uint64_t)(uint32_t)a * (uint32_t)b));
+        break;
+      case OP_UM_SLASH_MOD:
+        n = pop();
+        d = pop2();
+        udivrem((uint64_t)d, (uint32_t)n, word, &ur, &uq);
+        push((int32_t)(uint32_t)ur);
+        push((int32_t)(uint32_t)uq);
+        break;
+      case OP_S_TO_D:
+        push2(pop());
+        break;
+      case OP_D_ADD:
+        e = pop2();
+        d = pop2();
+        push2((int64_t)((uint64_t)d + (uint64_t)e));
+        break;
+      case OP_D_SUB:
+        e = pop2();
+        d = pop2();
+        push2((int64_t)((uint64_t)d - (uint64_t)e));
+        break;
+      case OP_D_NEGATE:
+        push2((int64_t)(0 - (uint64_t)pop2()));
+        break;
+      case OP_D_ABS:
+        d = pop2();
+        push2(d < 0 ? (int64_t)(0 - (uint64_t)d) : d);
+        break;
+      case OP_D_EQ:
+        e = pop2();
+        d = pop2();
+        push(flag(d == e));
+        break;
+      case OP_D_LT:
+        e = pop2();
+        d = pop2();
+        push(flag(d < e));
+        break;
+      case OP_D_ZERO_EQ:
+        push(flag(pop2() == 0));
+        break;
+      case OP_M_STAR:
+        b = pop();
+        a = pop();
+        push2((int64_t)a * b);
+        break;
+      case OP_M_PLUS:
+        n = pop();
+        d = pop2();
+        push2((int64_t)((uint64_t)d + (uint64_t)(int64_t)n));
+        break;
+      case OP_D_TWO_STAR:
+        push2((int64_t)((uint64_t)pop2() << 1));
+        break;
+      case OP_D_TWO_SLASH:
+        push2(pop2() >> 1);
+        break;
+      case OP_TWO_FETCH:
+        a = pop();
+        push(pvar_get((int32_t)((uint32_t)a + 4u)));
+        push(pvar_get(a));
+        break;
+      case OP_TWO_STORE:
+        a = pop();
+        b = pop();
+        n = pop();
+        pvar_set(b, a);
+        pvar_set(n, (int32_t)((uint32_t)a + 4u));
+        break;
+      default:
+        fprintf(stderr, "kfvm: bad token 0x%02X in word %s\n", op, word);
+        exit(2);
+    }
+  }
+}
+
+int main(int argc, char** argv) {
+  if (argc != 2) {
+    fprintf(stderr, "usage: %s <image.kfb>\n", argv[0]);
+    return 2;
+  }
+  load(argv[1]);
+  if (img.ninits > 0) rt_set_data_image(img.inits, (int32_t)img.ninits);
+  rt_heap_reset(img.here);
+  run();
+  return 0;
+}
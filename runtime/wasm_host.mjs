@@ -0,0 +1,356 @@
+// Host for `kforthc --emit=wat` modules:
+//   wat2wasm out.wat -o out.wasm && node runtime/wasm_host.mjs out.wasm
+// Implements the imported "kforth" services with runtime.c semantics.
+import fs from "node:fs";
+import { Worker, isMainThread } from "node:worker_threads";
+
+// Node's main thread has about 1 MB of stack, too little for deep
+// recursion, so the module runs in a worker with a native-sized stack.
+// process.exit in the worker ends only the worker; its code becomes ours.
+const STACK_MB = 64;
+if (isMainThread) {
+  new Worker(new URL(import.meta.url), {
+    argv: process.argv.slice(2),
+    resourceLimits: { stackSizeMb: STACK_MB },
+  }).on("exit", (code) => process.exit(code));
+}
+
+const F = Math.fround;
+const KP_PI_F = F(3.14159265358979323846);
+const KP_DEG2RAD_F = F(KP_PI_F / F(180));
+const KP_RAD2DEG_F = F(F(180) / KP_PI_F);
+const KP_FIX_SCALE_F = F(10000);
+const MEM_CELLS = 134217728;
+
+const f32 = new Float32Array(1);
+const u32 = new Uint32Array(f32.buffer);
+const bitsToF = (b) => ((u32[0] = b), f32[0]);
+const fToBits = (f) => ((f32[0] = f), u32[0] | 0);
+
+// (int32_t)x as x86 converts it: NaN and out of range give INT32_MIN.
+const f2i = (x) => (x >= -2147483648 && x < 2147483648 ? Math.trunc(x) | 0 : -2147483648);
+const roundF = (x) => (x >= 0 ? f2i(F(x + F(0.5))) : f2i(F(x - F(0.5))));
+const fixFromFloat = (x) => roundF(F(x * KP_FIX_SCALE_F));
+
+let memory;
+let cells;
+let here;
+let out = [];
+let outBytes = 0;
+
+function write(s) {
+  const b = typeof s === "string" ? Buffer.from(s, "latin1") : s;
+  out.push(b);
+  outBytes += b.length;
+  if (outBytes >= 65536) flush();
+}
+
+function flush() {
+  if (outBytes > 0) fs.writeSync(1, Buffer.concat(out));
+  out = [];
+  outBytes = 0;
+}
+
+function cString(ptr) {
+  const bytes = new Uint8Array(memory.buffer);
+  let end = ptr;
+  while (bytes[end] !== 0) end++;
+  return Buffer.from(bytes.subarray(ptr, end));
+}
+
+// printf("%.4f"): exact binary value, ties to even.
+function fixed4(bits) {
+  const u = bits >>> 0;
+  const exp = (u >>> 23) & 0xff;
+  const frac = u & 0x7fffff;
+  const m = BigInt(exp === 0 ? frac : frac | 0x800000);
+  const e = exp === 0 ? -149 : exp - 150;
+  let num = m * 10000n;
+  let den = 1n;
+  if (e >= 0) num <<= BigInt(e);
+  else den <<= BigInt(-e);
+  let q = num / den;
+  const r2 = 2n * (num % den);
+  if (r2 > den || (r2 === den && (q & 1n) === 1n)) q += 1n;
+  const s = q.toString().padStart(5, "0");
+  return (u >>> 31 ? "-" : "") + s.slice(0, -4) + "." + s.slice(-4);
+}
+
+// Whitespace-delimited tokens over stdin with one byte of pushback.
+const input = isMainThread ? null : fs.readFileSync(0);
+let inPos = 0;
+let pushback = -1;
+
+function getc() {
+  if (pushback >= 0) {
+    const c = pushback;
+    pushback = -1;
+    return c;
+  }
+  return inPos < input.length ? input[inPos++] : -1;
+}
+
+const isSpace = (c) => c === 32 || (c >= 9 && c <= 13);
+
+function token() {
+  let c;
+  do c = getc();
+  while (c >= 0 && isSpace(c));
+  if (c < 0) return null;
+  const bytes = [];
+  while (c >= 0 && !isSpace(c)) {
+    if (bytes.length < 255) bytes.push(c);
+    c = getc();
+  }
+  if (c >= 0) pushback = c;
+  return Buffer.from(bytes).toString("latin1");
+}
+
+// strtoll(tok, &end, 10) with *end == '\0', truncated to int32_t.
+function parseInt32(tok) {
+  if (tok === null || !/^[+-]?[0-9]+$/.test(tok)) return 0;
+  let v = BigInt(tok);
+  if (v > 0x7fffffffffffffffn) v = 0x7fffffffffffffffn;
+  if (v < -0x8000000000000000n) v = -0x8000000000000000n;
+  return Number(BigInt.asIntN(32, v));
+}
+
+function parseF32Bits(tok) {
+  if (tok === null) return 0;
+  if (/^[+-]?([0-9]+\.?[0-9]*|\.[0-9]+)([eE][+-]?[0-9]+)?$/.test(tok)) return fToBits(F(Number(tok)));
+  const neg = tok[0] === "-";
+  const word = tok.replace(/^[+-]/, "").toLowerCase();
+  if (word === "inf" || word === "infinity") return fToBits(neg ? -Infinity : Infinity);
+  if (word === "nan") return neg ? 0xffc00000 | 0 : 0x7fc00000;
+  return 0;
+}
+
+// ALLOCATE / FREE / RESIZE over the module's cells, laid out as in runtime.c:
+// each payload follows [size | freed bit, previous header].
+const IOR_ALLOCATE = -59;
+const IOR_FREE = -60;
+const IOR_RESIZE = -61;
+const heap = { last: -1, end: 0, freed: 0, ior: 0 };
+
+const cell = (addr) => Math.min(Math.max(Math.trunc(addr / 4), 0), MEM_CELLS - 1);
+const load = (addr) => cells[cell(addr)];
+const store = (addr, v) => (cells[cell(addr)] = v);
+
+function heapPrev(h) {
+  const prev = load(h + 4);
+  return prev >= 0 && prev < h && (prev & 3) === 0 ? prev : -1;
+}
+
+// Header of the block whose payload starts at `addr` (or -1), and the two
+// headers chained before it.
+function heapFind(addr) {
+  const above = [-1, -1];
+  for (let h = heap.last; h >= 0 && h + 8 >= addr; h = heapPrev(h)) {
+    if (h + 8 === addr) return { h, above };
+    above[1] = above[0];
+    above[0] = h;
+  }
+  return { h: -1, above };
+}
+
+const heapBlock = (addr) => heapFind(addr).h;
+
+function heapLink(newer, h) {
+  if (newer >= 0) store(newer + 4, h);
+  else heap.last = h;
+}
+
+// Only the block below addr can hold it; stop once every free block is passed.
+function inFreedBlock(addr) {
+  let left = heap.freed;
+  for (let h = heap.last; h >= 0 && left > 0; h = heapPrev(h)) {
+    const info = load(h);
+    if (h + 8 <= addr) return (info & 1) !== 0 && addr - (h + 8) < (info & ~3);
+    if (info & 1) left--;
+  }
+  return false;
+}
+
+function allocate(n) {
+  const size = Math.ceil((n >>> 0) / 4) * 4;
+  let next = -1;
+  for (let h = heap.last; h >= 0; next = h, h = heapPrev(h)) {
+    const info = load(h);
+    let have = info & ~3;
+    if (!(info & 1) || have < size) continue;
+    if (have - size >= 12) {
+      const rest = h + 8 + size;
+      store(rest, (have - size - 8) | 1);
+      store(rest + 4, h);
+      if (next >= 0) store(next + 4, rest);
+      else heap.last = rest;
+      have = size;
+      heap.freed++;
+    }
+    store(h, have);
+    heap.freed--;
+    heap.ior = 0;
+    return h + 8;
+  }
+  const h = Math.ceil(here.value / 4) * 4;
+  if (h < heap.end || h + 8 + size > MEM_CELLS * 4) {
+    heap.ior = IOR_ALLOCATE;
+    return 0;
+  }
+  store(h, size);
+  store(h + 4, heap.last);
+  heap.last = h;
+  heap.end = h + 8 + size;
+  here.value = heap.end;
+  heap.ior = 0;
+  return h + 8;
+}
+
+// Merges with free blocks directly above and below, as rt_free does.
+function free(addr) {
+  const { h, above } = heapFind(addr);
+  if (h < 0 || load(h) & 1) return IOR_FREE;
+  let size = load(h);
+  heap.freed++;
+  if (above[0] >= 0 && load(above[0]) & 1 && h + 8 + size === above[0]) {
+    size += 8 + (load(above[0]) & ~3);
+    heapLink(above[1], h);
+    above[0] = above[1];
+    heap.freed--;
+  }
+  const below = heapPrev(h);
+  if (below >= 0 && load(below) & 1 && below + 8 + (load(below) & ~3) === h) {
+    store(below, load(below) + 8 + size);
+    heapLink(above[0], below);
+    heap.freed--;
+    return 0;
+  }
+  store(h, size | 1);
+  return 0;
+}
+
+function resize(addr, n) {
+  const h = heapBlock(addr);
+  if (h < 0 || load(h) & 1) {
+    heap.ior = IOR_RESIZE;
+    return addr;
+  }
+  const have = load(h);
+  if (n >>> 0 <= have >>> 0) {
+    heap.ior = 0;
+    return addr;
+  }
+  const to = allocate(n);
+  if (heap.ior !== 0) {
+    heap.ior = IOR_RESIZE;
+    return addr;
+  }
+  for (let i = 0; i < have / 4; i++) store(to + 4 * i, load(addr + 4 * i));
+  free(addr);
+  return to;
+}
+
+function trap(msg, ptr) {
+  flush();
+  fs.writeSync(2, Buffer.concat([Buffer.from(`runtime error: ${msg}`), ptr === undefined ? Buffer.alloc(0) : cString(ptr), Buffer.from("\n")]));
+  process.exit(1);
+}
+
+function degToRad(a) {
+  return F(F(a) * KP_DEG2RAD_F);
+}
+
+const kforth = {
+  pwrite_i32: (x) => write(String(x)),
+  pwrite_u32: (x) => write(String(x >>> 0)),
+  pwrite_i64: (d) => write(d.toString()),
+  pwrite_i64_r: (d, w) => {
+    const s = d.toString();
+    write(w < 0 ? s.padEnd(-w) : s.padStart(w));
+  },
+  pwrite_bool: (x) => write(x ? "TRUE" : "FALSE"),
+  pwrite_char: (x) => write(Buffer.from([x & 0xff])),
+  pwrite_hex: (x) => write((x >>> 0).toString(16).toUpperCase().padStart(8, "0")),
+  pwrite_str: (ptr) => write(cString(ptr)),
+  pwriteln: () => write("\n"),
+  pwrite_f32_bits: (bits) => {
+    const x = bitsToF(bits);
+    if (Number.isNaN(x)) write("nan");
+    else if (!Number.isFinite(x)) write(x < 0 ? "-inf" : "inf");
+    else write(fixed4(bits));
+  },
+  pread_i32: () => parseInt32(token()),
+  pread_bool: () => (parseInt32(token()) !== 0 ? 1 : 0),
+  pread_char: () => {
+    const tok = token();
+    if (tok !== null && tok.length === 1) return tok.charCodeAt(0);
+    return parseInt32(tok);
+  },
+  pread_f32_bits: () => parseF32Bits(token()),
+  preadln: () => {
+    let c;
+    do c = getc();
+    while (c >= 0 && c !== 10);
+  },
+  rt_div_zero: (ptr) => {
+    flush();
+    fs.writeSync(2, Buffer.concat([Buffer.from("runtime error: division by zero in word "), cString(ptr), Buffer.from("\n")]));
+    process.exit(1);
+  },
+  rt_mem_check: (addr, kind, ptr) => {
+    let why;
+    if (kind >= 2) {
+      const h = heapBlock(addr);
+      if (h < 0) why = "invalid";
+      else if (load(h) & 1) why = kind === 2 ? "double" : "use-after-free";
+      else return;
+    } else if (addr < 0 || addr >= MEM_CELLS * 4) why = "out-of-range";
+    else if (addr & 3) why = "misaligned";
+    else if (heap.freed > 0 && inFreedBlock(addr)) why = "use-after-free";
+    else return;
+    const kinds = ["fetch", "store", "free", "resize"];
+    trap(`${why} ${kinds[kind & 3]} at address ${addr} in word `, ptr);
+  },
+  rt_allocate: allocate,
+  rt_free: free,
+  rt_resize: resize,
+  rt_ior: () => heap.ior,
+  rt_pnew: (n) => {
+    const p = allocate(n);
+    if (heap.ior !== 0) trap(`out of memory in new (${n} bytes)`);
+    return p;
+  },
+  rt_pdispose: (addr) => {
+    if (free(addr) !== 0) trap(`dispose of invalid pointer ${addr}`);
+  },
+  kp_fsin_f32_bits: (a) => fToBits(F(Math.sin(bitsToF(a)))),
+  kp_fcos_f32_bits: (a) => fToBits(F(Math.cos(bitsToF(a)))),
+  kp_fpow_f32_i32_bits: (a, n) => fToBits(F(Math.pow(bitsToF(a), F(n)))),
+  kp_fx_sin_deg_i32: (a) => fixFromFloat(F(Math.sin(degToRad(a)))),
+  kp_fx_cos_deg_i32: (a) => fixFromFloat(F(Math.cos(degToRad(a)))),
+  kp_fx_tan_deg_i32: (a) => {
+    const r = degToRad(a);
+    if (Math.abs(F(Math.cos(r))) < F(1e-6)) return 0;
+    return fixFromFloat(F(Math.tan(r)));
+  },
+  kp_fx_asin_fixed_i32: (v) => {
+    const x = Math.min(Math.max(F(F(v) / KP_FIX_SCALE_F), -1), 1);
+    return roundF(F(F(Math.asin(x)) * KP_RAD2DEG_F));
+  },
+  kp_fx_acos_fixed_i32: (v) => {
+    const x = Math.min(Math.max(F(F(v) / KP_FIX_SCALE_F), -1), 1);
+    return roundF(F(F(Math.acos(x)) * KP_RAD2DEG_F));
+  },
+  kp_fx_atan_fixed_i32: (v) => roundF(F(F(Math.atan(F(F(v) / KP_FIX_SCALE_F))) * KP_RAD2DEG_F)),
+  kp_fx_ln_i32: (x) => (x <= 0 ? 0 : fixFromFloat(F(Math.log(F(x))))),
+  kp_fx_log_i32: (x) => (x <= 0 ? 0 : fixFromFloat(F(Math.log10(F(x))))),
+};
+
+if (!isMainThread) {
+  const { instance } = await WebAssembly.instantiate(fs.readFileSync(process.argv[2]), { kforth });
+  memory = instance.exports.memory;
+  cells = new Int32Array(memory.buffer, instance.exports.mem_base.value, MEM_CELLS);
+  here = instance.exports.here;
+  instance.exports.main();
+  flush();
+}
@@ -6,18 +6,11 @@ IR="${2:-out.ll}"
 OBJ="${3:-out.o}"
 BIN="${4:-a.out}"
 
-if command -v llc >/dev/null 2>&1; then
-  LLC=llc
-elif command -v llc-14 >/dev/null 2>&1; then
-  LLC=llc-14
-else
-  echo "error: llc not found (tried: llc, llc-14)" >&2
-  exit 1
-fi
+source "$(dirname "${BASH_SOURCE[0]}")/llc_env.sh"
 
 cargo build
-./target/debug/kforthc "$INPUT" "$IR"
-"$LLC" -filetype=obj "$IR" -o "$OBJ"
+./target/debug/kforthc "${KFORTHC_LLVM_FLAGS[@]}" "$INPUT" "$IR"
+"$LLC" "${LLC_FLAGS[@]}" -filetype=obj "$IR" -o "$OBJ"
 clang -no-pie "$OBJ" runtime/runtime.c -o "$BIN" -lm
 
 "./$BIN"
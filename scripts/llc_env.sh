@@ -0,0 +1,28 @@
+# Sourced by the scripts that compile through llc. Sets LLC (the LLC
+# environment variable, else llc, else llc-14), KFORTHC_LLVM_FLAGS for
+# kforthc and LLC_FLAGS for llc, as `kforthc test --emit=llvm` does.
+
+if [[ -z "${LLC:-}" ]]; then
+  if command -v llc >/dev/null 2>&1; then
+    LLC=llc
+  elif command -v llc-14 >/dev/null 2>&1; then
+    LLC=llc-14
+  else
+    echo "error: llc not found (tried: llc, llc-14)" >&2
+    exit 1
+  fi
+fi
+
+# Emit the pointer dialect of the llc in use; LLVM_VERSION overrides it
+# (llc-14 reads the opaque-pointer dialect with -opaque-pointers). If neither
+# is known, kforthc keeps its default dialect.
+LLC_MAJOR="$("$LLC" --version 2>/dev/null | sed -n 's/.*LLVM version \([0-9]*\).*/\1/p' | head -n1 || true)"
+LLVM_VERSION="${LLVM_VERSION:-$LLC_MAJOR}"
+KFORTHC_LLVM_FLAGS=()
+LLC_FLAGS=()
+if [[ -n "$LLVM_VERSION" ]]; then
+  KFORTHC_LLVM_FLAGS+=("--llvm-version=$LLVM_VERSION")
+  if [[ "$LLC_MAJOR" == 14 && "${LLVM_VERSION%%.*}" -ge 15 ]]; then
+    LLC_FLAGS+=(-opaque-pointers)
+  fi
+fi
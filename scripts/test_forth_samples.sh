@@ -0,0 +1,43 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+SAMPLES_DIR="samples/forth"
+BUILD_DIR="samples/build/forth"
+# CC, LLC and WAT2WASM name the tools `kforthc test` builds with.
+export CC="${CC:-clang}"
+# Cross testing: TARGET is passed as --target, RUNNER runs the binaries
+# (e.g. TARGET=aarch64 CC=aarch64-linux-gnu-gcc RUNNER="qemu-aarch64 -L /usr/aarch64-linux-gnu").
+TARGET="${TARGET:-}"
+mkdir -p "$BUILD_DIR"
+
+# --emit=wat needs wat2wasm (wabt) and node; it is skipped without them.
+WAT2WASM="${WAT2WASM:-wat2wasm}"
+export WAT2WASM
+WASM=0
+if command -v "$WAT2WASM" >/dev/null 2>&1 && command -v node >/dev/null 2>&1; then
+  WASM=1
+fi
+
+cargo build
+
+forth_test() {
+  ./target/debug/kforthc test --build-dir="$BUILD_DIR" "$@" "$SAMPLES_DIR"
+}
+
+forth_test --emit=llvm ${TARGET:+"--target=$TARGET"} ${LLVM_VERSION:+"--llvm-version=$LLVM_VERSION"}
+# The optimizer must not change behaviour: run unoptimized as well.
+forth_test --emit=llvm --no-opt ${TARGET:+"--target=$TARGET"} ${LLVM_VERSION:+"--llvm-version=$LLVM_VERSION"}
+# The C backend must behave like the LLVM one.
+forth_test --emit=c
+forth_test --emit=bytecode
+# The native x86-64 backend, when not cross testing.
+if [[ -z "$TARGET" && "$(uname -m)" == "x86_64" ]]; then
+  forth_test --emit=asm
+fi
+if [[ "$WASM" -eq 1 ]]; then
+  forth_test --emit=wat
+else
+  echo "forth samples --emit=wat: SKIP (wat2wasm or node not found)"
+fi
+
+echo "all forth samples: PASS"
@@ -19,21 +19,14 @@ if [[ ! -x "$KPASCAL_BIN" ]]; then
   exit 1
 fi
 
-if command -v llc >/dev/null 2>&1; then
-  LLC=llc
-elif command -v llc-14 >/dev/null 2>&1; then
-  LLC=llc-14
-else
-  echo "error: llc not found (tried: llc, llc-14)" >&2
-  exit 1
-fi
+source "$(dirname "${BASH_SOURCE[0]}")/llc_env.sh"
 
 cargo build >/dev/null
 
 name="23_recursive_fib_constraint"
 "$KPASCAL_BIN" < "$SAMPLES_DIR/$name.pas" > "$BUILD_DIR/$name.fth"
-./target/debug/kforthc "$BUILD_DIR/$name.fth" "$BUILD_DIR/$name.ll"
-"$LLC" -filetype=obj "$BUILD_DIR/$name.ll" -o "$BUILD_DIR/$name.o"
+./target/debug/kforthc "${KFORTHC_LLVM_FLAGS[@]}" "$BUILD_DIR/$name.fth" "$BUILD_DIR/$name.ll"
+"$LLC" "${LLC_FLAGS[@]}" -filetype=obj "$BUILD_DIR/$name.ll" -o "$BUILD_DIR/$name.o"
 clang -no-pie "$BUILD_DIR/$name.o" runtime/runtime.c -o "$BUILD_DIR/$name.out" -lm
 "$BUILD_DIR/$name.out" > "$BUILD_DIR/$name.actual"
 
@@ -27,19 +27,12 @@ if [[ ! -x "$KPASCAL_BIN" ]]; then
   exit 1
 fi
 
-if command -v llc >/dev/null 2>&1; then
-  LLC=llc
-elif command -v llc-14 >/dev/null 2>&1; then
-  LLC=llc-14
-else
-  echo "error: llc not found (tried: llc, llc-14)" >&2
-  exit 1
-fi
+source "$(dirname "${BASH_SOURCE[0]}")/llc_env.sh"
 
 cargo build
 "$KPASCAL_BIN" < "$PASCAL_SRC" > "$FORTH_OUT"
-./target/debug/kforthc "$FORTH_OUT" "$IR_OUT"
-"$LLC" -filetype=obj "$IR_OUT" -o "$OBJ_OUT"
+./target/debug/kforthc "${KFORTHC_LLVM_FLAGS[@]}" "$FORTH_OUT" "$IR_OUT"
+"$LLC" "${LLC_FLAGS[@]}" -filetype=obj "$IR_OUT" -o "$OBJ_OUT"
 clang -no-pie "$OBJ_OUT" runtime/runtime.c -o "$BIN_OUT" -lm
 printf '255\n1 Z\n7 8 9\nHELLO\n' | "./$BIN_OUT" > "$ACTUAL_OUT"
 
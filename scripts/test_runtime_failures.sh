@@ -11,14 +11,7 @@ if [[ ! -x "$KPASCAL_BIN" ]]; then
   exit 1
 fi
 
-if command -v llc >/dev/null 2>&1; then
-  LLC=llc
-elif command -v llc-14 >/dev/null 2>&1; then
-  LLC=llc-14
-else
-  echo "error: llc not found (tried: llc, llc-14)" >&2
-  exit 1
-fi
+source "$(dirname "${BASH_SOURCE[0]}")/llc_env.sh"
 
 cargo build >/dev/null
 
@@ -30,8 +23,8 @@ obj="$BUILD_DIR/$name.o"
 bin="$BUILD_DIR/$name.out"
 
 "$KPASCAL_BIN" < "$src" > "$forth"
-./target/debug/kforthc "$forth" "$ir"
-"$LLC" -filetype=obj "$ir" -o "$obj"
+./target/debug/kforthc "${KFORTHC_LLVM_FLAGS[@]}" "$forth" "$ir"
+"$LLC" "${LLC_FLAGS[@]}" -filetype=obj "$ir" -o "$obj"
 clang -no-pie "$obj" runtime/runtime.c -o "$bin" -lm
 
 set +e
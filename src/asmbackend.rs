@@ -0,0 +1,503 @@
+// `--emit=asm`: kIR as GNU assembler text for x86-64 Linux, assembled with
+// `as` and linked with runtime/runtime.c, without going through llc.
+//
+// Register use, all callee-saved so runtime calls keep them:
+//   %ebx  top of the data stack
+//   %r12  data stack pointer: the cells below the top are at -4(%r12),
+//         -8(%r12), ... (kf_stack holds one spare cell for an empty stack)
+//   %r13  return stack pointer into the 1024-cell frame of the current
+//         definition, for definitions that use one
+//   %r14  scratch across a runtime call
+// Definitions are native functions; calls keep %rsp 16-byte aligned.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::kir::{ExternArgMode, Func, Inst, MemAddr, Module, Op, Term};
+use crate::{llvm_word_sym, DivisionMode, RUNTIME_SYMBOLS};
+
+// Division helpers: %rax dividend, %rcx divisor, %rdi word name, %esi
+// floored flag; quotient in %rax, remainder in %rdx. MIN / -1 wraps.
+const HELPERS: &str = "\t.p2align 4
+kf_sdivrem:
+\ttest %rcx, %rcx
+\tjz kf_div_zero
+\tcmp $-1, %rcx
+\tjne 1f
+\tneg %rax
+\txor %edx, %edx
+\tret
+1:\tcqo
+\tidiv %rcx
+\ttest %esi, %esi
+\tjz 2f
+\ttest %rdx, %rdx
+\tjz 2f
+\tmov %rdx, %r8
+\txor %rcx, %r8
+\tjns 2f
+\tsub $1, %rax
+\tadd %rcx, %rdx
+2:\tret
+
+\t.p2align 4
+kf_udivrem:
+\ttest %rcx, %rcx
+\tjz kf_div_zero
+\txor %edx, %edx
+\tdiv %rcx
+\tret
+
+kf_div_zero:
+\tsub $8, %rsp
+\tcall rt_div_zero
+\tud2
+";
+
+// Push %eax.
+const PUSH_EAX: &str = "mov %ebx, (%r12)\nadd $4, %r12\nmov %eax, %ebx";
+// Drop the top cell.
+const DROP: &str = "sub $4, %r12\nmov (%r12), %ebx";
+// Pop a double into %rax.
+const POP64: &str = "mov -4(%r12), %eax\nmov %ebx, %edx\nshl $32, %rdx\nor %rdx, %rax\n\
+                     sub $8, %r12\nmov (%r12), %ebx";
+// Push the double in %rax.
+const PUSH64: &str =
+    "mov %ebx, (%r12)\nmov %eax, 4(%r12)\nadd $8, %r12\nshr $32, %rax\nmov %eax, %ebx";
+
+// ( a b -- c ): a in %eax, b in %ebx, the result goes to %ebx.
+fn binary(body: &str) -> String {
+    format!("sub $4, %r12\nmov (%r12), %eax\n{}", body)
+}
+
+fn compare(set: &str) -> String {
+    binary(&format!(
+        "cmp %ebx, %eax\n{} %al\nmovzbl %al, %ebx\nneg %ebx",
+        set
+    ))
+}
+
+// Replaces the top u+1 cells x_u ... x_0 by x_{u-1} ... x_0 x_u, with u
+// in %rcx.
+const ROLL_RCX: &str = "mov %ebx, (%r12)\nshl $2, %rcx\nmov %r12, %rdx\nsub %rcx, %rdx\n\
+                        mov (%rdx), %eax\n1:\ncmp %r12, %rdx\njae 2f\nmov 4(%rdx), %esi\n\
+                        mov %esi, (%rdx)\nadd $4, %rdx\njmp 1b\n2:\nmov %eax, %ebx";
+
+struct AsmEmitter<'m> {
+    out: String,
+    rodata: String,
+    symbols: HashMap<&'static str, &'static str>,
+    index: HashMap<&'m str, String>,
+    strings: HashMap<String, String>,
+    floored: bool,
+    debug: bool,
+}
+
+impl<'m> AsmEmitter<'m> {
+    fn line(&mut self, s: &str) {
+        self.out.push_str(s);
+        self.out.push('\n');
+    }
+
+    // Instructions, one per line, indented.
+    fn code(&mut self, s: &str) {
+        for l in s.lines() {
+            if l.ends_with(':') || l.starts_with('.') && !l.starts_with(".loc") {
+                self.line(l);
+            } else {
+                self.line(&format!("\t{}", l));
+            }
+        }
+    }
+
+    fn word_sym(&self, w: &str) -> Result<String, String> {
+        self.index
+            .get(w)
+            .cloned()
+            .ok_or_else(|| format!("Call to unknown word: {}", w))
+    }
+
+    // Label of a NUL-terminated copy of `s` in .rodata.
+    fn string(&mut self, s: &str) -> String {
+        if let Some(l) = self.strings.get(s) {
+            return l.clone();
+        }
+        let label = format!(".Lstr{}", self.strings.len());
+        let mut bytes: Vec<String> = s.bytes().map(|b| b.to_string()).collect();
+        bytes.push("0".into());
+        let _ = writeln!(self.rodata, "{}:", label);
+        for chunk in bytes.chunks(16) {
+            let _ = writeln!(self.rodata, "\t.byte {}", chunk.join(", "));
+        }
+        self.strings.insert(s.to_string(), label.clone());
+        label
+    }
+
+    fn runtime(&self, key: &str) -> Result<&'static str, String> {
+        self.symbols
+            .get(key)
+            .copied()
+            .ok_or_else(|| format!("Unknown extern service word: {}", key))
+    }
+
+    fn div(&self, name: &str, setup: &str, floored: bool, result: &str) -> String {
+        format!(
+            "{}\nlea {}(%rip), %rdi\nmov ${}, %esi\ncall kf_sdivrem\n{}",
+            setup, name, floored as i32, result
+        )
+    }
+
+    fn udiv(&self, name: &str, setup: &str, result: &str) -> String {
+        format!(
+            "{}\nlea {}(%rip), %rdi\ncall kf_udivrem\n{}",
+            setup, name, result
+        )
+    }
+
+    fn op(&mut self, op: Op, name: &str) -> String {
+        // ( a b ) as 64-bit dividend and divisor.
+        let signed2 = "movslq -4(%r12), %rax\nmovslq %ebx, %rcx";
+        let unsigned2 = "mov -4(%r12), %eax\nmov %ebx, %ecx";
+        // ( lo hi n ) as a double and a divisor.
+        let double_n = "mov -8(%r12), %eax\nmov -4(%r12), %edx\nshl $32, %rdx\nor %rdx, %rax";
+        let rem_quo = "sub $4, %r12\nmov %edx, -4(%r12)\nmov %eax, %ebx";
+        match op {
+            Op::Dup => "mov %ebx, (%r12)\nadd $4, %r12".into(),
+            Op::Drop => DROP.into(),
+            Op::Swap => "mov -4(%r12), %eax\nmov %ebx, -4(%r12)\nmov %eax, %ebx".into(),
+            Op::Over => format!("mov -4(%r12), %eax\n{}", PUSH_EAX),
+            Op::Rot => "mov -8(%r12), %eax\nmov -4(%r12), %ecx\nmov %ecx, -8(%r12)\n\
+                        mov %ebx, -4(%r12)\nmov %eax, %ebx"
+                .into(),
+            Op::MinusRot => "mov -8(%r12), %eax\nmov -4(%r12), %ecx\nmov %ebx, -8(%r12)\n\
+                             mov %eax, -4(%r12)\nmov %ecx, %ebx"
+                .into(),
+            Op::Nip => "sub $4, %r12".into(),
+            Op::Tuck => "mov -4(%r12), %eax\nmov %ebx, -4(%r12)\nmov %eax, (%r12)\nadd $4, %r12"
+                .into(),
+            Op::QDup => "test %ebx, %ebx\njz 1f\nmov %ebx, (%r12)\nadd $4, %r12\n1:".into(),
+            Op::TwoDup => "mov -4(%r12), %eax\nmov %ebx, (%r12)\nmov %eax, 4(%r12)\nadd $8, %r12"
+                .into(),
+            Op::TwoDrop => "sub $8, %r12\nmov (%r12), %ebx".into(),
+            Op::TwoSwap => "mov -12(%r12), %eax\nmov -8(%r12), %ecx\nmov -4(%r12), %edx\n\
+                            mov %edx, -12(%r12)\nmov %ebx, -8(%r12)\nmov %eax, -4(%r12)\nmov %ecx, %ebx"
+                .into(),
+            Op::TwoOver => "mov -12(%r12), %eax\nmov -8(%r12), %ecx\nmov %ebx, (%r12)\n\
+                            mov %eax, 4(%r12)\nadd $8, %r12\nmov %ecx, %ebx"
+                .into(),
+            Op::Pick => "movslq %ebx, %rax\nneg %rax\nmov -4(%r12,%rax,4), %ebx".into(),
+            Op::Roll => format!("movslq %ebx, %rcx\n{}\n{}", DROP, ROLL_RCX),
+            Op::Depth => format!(
+                "lea kf_stack(%rip), %rcx\nmov %r12, %rax\nsub %rcx, %rax\nshr $2, %rax\n{}",
+                PUSH_EAX
+            ),
+            Op::ToR => format!("mov %ebx, (%r13)\nadd $4, %r13\n{}", DROP),
+            Op::RFrom => format!("sub $4, %r13\nmov (%r13), %eax\n{}", PUSH_EAX),
+            Op::RFetch => format!("mov -4(%r13), %eax\n{}", PUSH_EAX),
+            Op::TwoToR => "mov -4(%r12), %eax\nmov %eax, (%r13)\nmov %ebx, 4(%r13)\nadd $8, %r13\n\
+                           sub $8, %r12\nmov (%r12), %ebx"
+                .into(),
+            Op::TwoRFrom => "sub $8, %r13\nmov %ebx, (%r12)\nmov (%r13), %eax\nmov %eax, 4(%r12)\n\
+                             add $8, %r12\nmov 4(%r13), %ebx"
+                .into(),
+            Op::TwoRFetch => "mov %ebx, (%r12)\nmov -8(%r13), %eax\nmov %eax, 4(%r12)\n\
+                              add $8, %r12\nmov -4(%r13), %ebx"
+                .into(),
+            Op::Add => binary("add %eax, %ebx"),
+            Op::Sub => binary("sub %ebx, %eax\nmov %eax, %ebx"),
+            Op::Mul => binary("imul %eax, %ebx"),
+            Op::Div => self.div(
+                name,
+                signed2,
+                self.floored,
+                "sub $4, %r12\nmov %eax, %ebx",
+            ),
+            Op::Mod => self.div(
+                name,
+                signed2,
+                self.floored,
+                "sub $4, %r12\nmov %edx, %ebx",
+            ),
+            Op::DivMod => self.div(
+                name,
+                signed2,
+                self.floored,
+                "mov %edx, -4(%r12)\nmov %eax, %ebx",
+            ),
+            Op::StarSlash | Op::StarSlashMod => self.div(
+                name,
+                "movslq -8(%r12), %rax\nmovslq -4(%r12), %rcx\nimul %rcx, %rax\nmovslq %ebx, %rcx",
+                self.floored,
+                if op == Op::StarSlash {
+                    "sub $8, %r12\nmov %eax, %ebx"
+                } else {
+                    rem_quo
+                },
+            ),
+            Op::FmMod | Op::SmRem => self.div(
+                name,
+                &format!("{}\nmovslq %ebx, %rcx", double_n),
+                op == Op::FmMod,
+                rem_quo,
+            ),
+            Op::Negate => "neg %ebx".into(),
+            Op::Abs => "mov %ebx, %eax\nneg %eax\ntest %ebx, %ebx\ncmovs %eax, %ebx".into(),
+            Op::Min => binary("cmp %ebx, %eax\ncmovl %eax, %ebx"),
+            Op::Max => binary("cmp %ebx, %eax\ncmovg %eax, %ebx"),
+            Op::OnePlus => "add $1, %ebx".into(),
+            Op::OneMinus => "sub $1, %ebx".into(),
+            Op::TwoStar => "add %ebx, %ebx".into(),
+            Op::TwoSlash => "sar $1, %ebx".into(),
+            Op::And => binary("and %eax, %ebx"),
+            Op::Or => binary("or %eax, %ebx"),
+            Op::Xor => binary("xor %eax, %ebx"),
+            Op::Invert => "not %ebx".into(),
+            // x86 takes 32-bit shift counts modulo 32 like the other backends.
+            Op::Lshift => binary("mov %ebx, %ecx\nshl %cl, %eax\nmov %eax, %ebx"),
+            Op::Rshift => binary("mov %ebx, %ecx\nshr %cl, %eax\nmov %eax, %ebx"),
+            Op::Arshift => binary("mov %ebx, %ecx\nsar %cl, %eax\nmov %eax, %ebx"),
+            Op::Cells => "shl $2, %ebx".into(),
+            Op::CellPlus => "add $4, %ebx".into(),
+            Op::Eq => compare("sete"),
+            Op::Ne => compare("setne"),
+            Op::Lt => compare("setl"),
+            Op::Le => compare("setle"),
+            Op::Gt => compare("setg"),
+            Op::Ge => compare("setge"),
+            Op::ULt => compare("setb"),
+            Op::UGt => compare("seta"),
+            Op::ZeroEq => "test %ebx, %ebx\nsete %al\nmovzbl %al, %ebx\nneg %ebx".into(),
+            Op::ZeroLt => "sar $31, %ebx".into(),
+            Op::UDiv => self.udiv(name, unsigned2, "sub $4, %r12\nmov %eax, %ebx"),
+            Op::UMod => self.udiv(name, unsigned2, "sub $4, %r12\nmov %edx, %ebx"),
+            Op::UmStar => "mov -4(%r12), %eax\nmov %ebx, %ecx\nimul %rcx, %rax\n\
+                           mov %eax, -4(%r12)\nshr $32, %rax\nmov %eax, %ebx"
+                .into(),
+            Op::MStar => "movslq -4(%r12), %rax\nmovslq %ebx, %rcx\nimul %rcx, %rax\n\
+                          mov %eax, -4(%r12)\nshr $32, %rax\nmov %eax, %ebx"
+                .into(),
+            Op::UmSlashMod => {
+                self.udiv(name, &format!("{}\nmov %ebx, %ecx", double_n), rem_quo)
+            }
+            Op::SToD => "mov %ebx, (%r12)\nadd $4, %r12\nsar $31, %ebx".into(),
+            Op::DAdd => format!("{}\nmov %rax, %rcx\n{}\nadd %rcx, %rax\n{}", POP64, POP64, PUSH64),
+            Op::DSub => format!("{}\nmov %rax, %rcx\n{}\nsub %rcx, %rax\n{}", POP64, POP64, PUSH64),
+            Op::DNegate => format!("{}\nneg %rax\n{}", POP64, PUSH64),
+            Op::DAbs => format!(
+                "{}\nmov %rax, %rdx\nneg %rdx\ntest %rax, %rax\ncmovs %rdx, %rax\n{}",
+                POP64, PUSH64
+            ),
+            Op::DEq | Op::DLt => format!(
+                "{}\nmov %rax, %rcx\n{}\ncmp %rcx, %rax\n{} %al\nmovzbl %al, %eax\nneg %eax\n{}",
+                POP64,
+                POP64,
+                if op == Op::DEq { "sete" } else { "setl" },
+                PUSH_EAX
+            ),
+            Op::DZeroEq => format!(
+                "{}\ntest %rax, %rax\nsete %al\nmovzbl %al, %eax\nneg %eax\n{}",
+                POP64, PUSH_EAX
+            ),
+            Op::MPlus => format!(
+                "movslq %ebx, %rcx\n{}\n{}\nadd %rcx, %rax\n{}",
+                DROP, POP64, PUSH64
+            ),
+            Op::DTwoStar => format!("{}\nshl $1, %rax\n{}", POP64, PUSH64),
+            Op::DTwoSlash => format!("{}\nsar $1, %rax\n{}", POP64, PUSH64),
+            Op::TwoFetch => "mov %ebx, %r14d\nlea 4(%rbx), %edi\ncall pvar_get\nmov %eax, %ebx\n\
+                             mov %r14d, %edi\ncall pvar_get\nmov %ebx, (%r12)\nadd $4, %r12\nmov %eax, %ebx"
+                .into(),
+            Op::TwoStore => "mov %ebx, %r14d\nmov -4(%r12), %edi\nmov %ebx, %esi\ncall pvar_set\n\
+                             mov -8(%r12), %edi\nlea 4(%r14), %esi\ncall pvar_set\n\
+                             sub $12, %r12\nmov (%r12), %ebx"
+                .into(),
+        }
+    }
+
+    fn inst(&mut self, inst: &Inst, name: &str) -> Result<String, String> {
+        Ok(match inst {
+            Inst::Lit(v) => format!("mov %ebx, (%r12)\nadd $4, %r12\nmov ${}, %ebx", v),
+            Inst::Op(op) => self.op(*op, name),
+            Inst::PickConst(0) => self.op(Op::Dup, name),
+            Inst::PickConst(n) => format!(
+                "mov ${}, %rax\nmov (%r12,%rax,4), %eax\n{}",
+                -(*n as i64),
+                PUSH_EAX
+            ),
+            Inst::RollConst(n) if *n <= 0 => String::new(),
+            Inst::RollConst(1) => self.op(Op::Swap, name),
+            Inst::RollConst(2) => self.op(Op::Rot, name),
+            Inst::RollConst(n) => format!("mov ${}, %rcx\n{}", n, ROLL_RCX),
+            Inst::Call(w) => format!("call {}", self.word_sym(w)?),
+            Inst::Service(svc) => {
+                let sym = self.runtime(svc.name)?;
+                match svc.mode {
+                    ExternArgMode::PopI32Void => format!("mov %ebx, %edi\n{}\ncall {}", DROP, sym),
+                    ExternArgMode::Void => format!("call {}", sym),
+                    ExternArgMode::RetI32Push => format!("call {}\n{}", sym, PUSH_EAX),
+                    ExternArgMode::PopI32RetI32Push => {
+                        format!("mov %ebx, %edi\ncall {}\nmov %eax, %ebx", sym)
+                    }
+                    ExternArgMode::Pop2I32Void => format!(
+                        "mov -4(%r12), %edi\nmov %ebx, %esi\nsub $8, %r12\nmov (%r12), %ebx\ncall {}",
+                        sym
+                    ),
+                    ExternArgMode::Pop2I32RetI32Push => format!(
+                        "mov -4(%r12), %edi\nmov %ebx, %esi\nsub $4, %r12\ncall {}\nmov %eax, %ebx",
+                        sym
+                    ),
+                    ExternArgMode::Pop3I32Void => format!(
+                        "mov -8(%r12), %edi\nmov -4(%r12), %esi\nmov %ebx, %edx\n\
+                         sub $12, %r12\nmov (%r12), %ebx\ncall {}",
+                        sym
+                    ),
+                    ExternArgMode::PopI64Void => {
+                        format!("{}\nmov %rax, %rdi\ncall {}", POP64, sym)
+                    }
+                    ExternArgMode::PopI64I32Void => format!(
+                        "mov %ebx, %esi\n{}\n{}\nmov %rax, %rdi\ncall {}",
+                        DROP, POP64, sym
+                    ),
+                    ExternArgMode::StrVoid => {
+                        return Err(format!("Service {} needs a string operand", svc.name))
+                    }
+                }
+            }
+            Inst::Type(s) => {
+                let sym = self.runtime("TYPE")?;
+                let label = self.string(s);
+                format!("lea {}(%rip), %rdi\ncall {}", label, sym)
+            }
+            Inst::Line(n) if self.debug => format!(".loc 1 {}", n),
+            Inst::Line(_) => String::new(),
+            Inst::MemCheck(c) => {
+                let addr = match c.addr {
+                    MemAddr::Top(k) => format!("lea {}(%rbx), %edi", k),
+                    MemAddr::Field => "mov -4(%r12), %edi\nadd %ebx, %edi".into(),
+                };
+                format!(
+                    "{}\nmov ${}, %esi\nlea {}(%rip), %rdx\ncall rt_mem_check",
+                    addr, c.access as i32, name
+                )
+            }
+        })
+    }
+
+    fn func(&mut self, f: &Func) -> Result<(), String> {
+        let sym = llvm_word_sym(&f.name);
+        let name = self.string(&f.name);
+        let rstack = f.uses_return_stack();
+        let label = |id: usize| format!(".L{}_{}", sym, f.label(id));
+        let start = format!(".L{}_start", sym);
+        // Entry leaves %rsp 16-byte aligned; a return stack frame is 4096
+        // bytes below the saved %r13.
+        let (prologue, epilogue) = if rstack {
+            ("push %r13\nsub $4096, %rsp", "add $4096, %rsp\npop %r13")
+        } else {
+            ("sub $8, %rsp", "add $8, %rsp")
+        };
+        self.line("");
+        self.line("\t.p2align 4");
+        self.line(&format!("\t.type {}, @function", sym));
+        self.line(&format!("{}:", sym));
+        self.code(prologue);
+        self.line(&format!("{}:", start));
+        if rstack {
+            self.code("mov %rsp, %r13");
+        }
+        for (id, block) in f.blocks.iter().enumerate() {
+            self.line(&format!("{}:", label(id)));
+            for inst in &block.insts {
+                let s = self.inst(inst, &name)?;
+                self.code(&s);
+            }
+            let fall = |t: usize| t == id + 1;
+            match &block.term {
+                Term::Return => self.code(&format!("{}\nret", epilogue)),
+                Term::Jump(t) if fall(*t) => {}
+                Term::Jump(t) => self.code(&format!("jmp {}", label(*t))),
+                Term::Branch { nonzero, zero } => {
+                    self.code(&format!(
+                        "mov %ebx, %eax\n{}\ntest %eax, %eax\njz {}",
+                        DROP,
+                        label(*zero)
+                    ));
+                    if !fall(*nonzero) {
+                        self.code(&format!("jmp {}", label(*nonzero)));
+                    }
+                }
+                Term::TailCall(w) if *w == f.name => self.code(&format!("jmp {}", start)),
+                Term::TailCall(w) => {
+                    let target = self.word_sym(w)?;
+                    self.code(&format!("{}\njmp {}", epilogue, target));
+                }
+            }
+        }
+        self.line(&format!("\t.size {}, .-{}", sym, sym));
+        Ok(())
+    }
+}
+
+pub fn emit(m: &Module, source: Option<&str>) -> Result<String, String> {
+    let mut e = AsmEmitter {
+        out: String::new(),
+        rodata: String::new(),
+        symbols: RUNTIME_SYMBOLS.iter().copied().collect(),
+        index: m
+            .funcs
+            .iter()
+            .map(|f| (f.name.as_str(), llvm_word_sym(&f.name)))
+            .collect(),
+        strings: HashMap::new(),
+        floored: m.division == DivisionMode::Floored,
+        debug: source.is_some(),
+    };
+    e.line("# Generated by kforthc: as out.s -o out.o && cc out.o runtime/runtime.c -lm");
+    if let Some(path) = source {
+        e.line(&format!("\t.file 1 \"{}\"", path.escape_default()));
+    }
+    e.line("\t.text");
+    e.out.push_str(HELPERS);
+    for f in &m.funcs {
+        e.func(f)?;
+    }
+    let entry = e.word_sym(&m.entry)?;
+
+    e.line("");
+    e.line("\t.globl main");
+    e.line("\t.type main, @function");
+    e.line("main:");
+    let mut main = String::from("push %rbx\npush %r12\npush %r13\npush %r14\nsub $8, %rsp\n");
+    if !m.static_inits.is_empty() {
+        let _ = writeln!(
+            main,
+            "lea kf_data_image(%rip), %rdi\nmov ${}, %esi\ncall rt_set_data_image",
+            m.static_inits.len()
+        );
+    }
+    let _ = write!(
+        main,
+        "mov ${}, %edi\ncall rt_heap_reset\nlea kf_stack(%rip), %r12\ncall {}\n\
+         xor %eax, %eax\nadd $8, %rsp\npop %r14\npop %r13\npop %r12\npop %rbx\nret",
+        m.here, entry
+    );
+    e.code(&main);
+    e.line("\t.size main, .-main");
+
+    e.line("");
+    e.line("\t.section .rodata");
+    if !m.static_inits.is_empty() {
+        e.line("\t.p2align 2");
+        e.line("kf_data_image:");
+        for (addr, v) in &m.static_inits {
+            e.line(&format!("\t.long {}, {}", addr, v));
+        }
+    }
+    let rodata = std::mem::take(&mut e.rodata);
+    e.out.push_str(&rodata);
+    // 1024 cells plus the spare cell under an empty stack.
+    e.line("\t.local kf_stack");
+    e.line("\t.comm kf_stack, 4100, 16");
+    e.line("\t.section .note.GNU-stack,\"\",@progbits");
+    Ok(e.out)
+}
@@ -0,0 +1,330 @@
+// `--emit=bytecode`: kIR as a compact token-threaded image, run by the VM
+// in runtime/vm.c (`kfvm out.kfb`).
+//
+// Image (little-endian): "KFBC", u32 version, u32 flags (bit 0: floored
+// division), i32 HERE, u32 entry word, then four tables, each a u32 count
+// followed by its entries:
+//   strings  bytes of NUL-terminated word names and TYPE literals
+//   imports  u32 runtime symbol (string offset), u8 argument mode
+//   inits    i32 address, i32 value (the static data image)
+//   words    u32 name (string offset), u32 code size, code
+// An instruction is a one-byte token and its operand. Word and import
+// indices are u16, jump targets u32 offsets into the word's code.
+
+use std::collections::HashMap;
+
+use crate::kir::{ExternArgMode, Func, Inst, MemAddr, Module, Op, Term};
+use crate::{DivisionMode, RUNTIME_SYMBOLS};
+
+const MAGIC: &[u8; 4] = b"KFBC";
+const VERSION: u32 = 1;
+const FLAG_FLOORED: u32 = 1;
+
+// Tokens; keep in sync with runtime/vm.c.
+const EXIT: u8 = 0x00;
+const LIT: u8 = 0x01; // i32
+const LIT8: u8 = 0x02; // i8
+const CALL: u8 = 0x03; // u16 word
+const TAIL: u8 = 0x04; // u16 word
+const JUMP: u8 = 0x05; // u32 offset
+const BRANCH0: u8 = 0x06; // u32 offset, taken when the popped cell is 0
+const SVC: u8 = 0x07; // u16 import
+const TYPE: u8 = 0x08; // u32 string offset
+const PICKC: u8 = 0x09; // i32
+const ROLLC: u8 = 0x0A; // i32
+const MEMCHK: u8 = 0x0B; // u8 flags (bits 0-1 access kind, bit 2 field), i32 offset
+const PRIM_BASE: u8 = 0x10;
+
+// Primitive tokens are PRIM_BASE + position.
+const PRIMITIVES: &[Op] = &[
+    Op::Dup,
+    Op::Drop,
+    Op::Swap,
+    Op::Over,
+    Op::Rot,
+    Op::MinusRot,
+    Op::Nip,
+    Op::Tuck,
+    Op::QDup,
+    Op::TwoDup,
+    Op::TwoDrop,
+    Op::TwoSwap,
+    Op::TwoOver,
+    Op::Pick,
+    Op::Roll,
+    Op::Depth,
+    Op::ToR,
+    Op::RFrom,
+    Op::RFetch,
+    Op::TwoToR,
+    Op::TwoRFrom,
+    Op::TwoRFetch,
+    Op::Add,
+    Op::Sub,
+    Op::Mul,
+    Op::Div,
+    Op::Mod,
+    Op::DivMod,
+    Op::StarSlash,
+    Op::StarSlashMod,
+    Op::FmMod,
+    Op::SmRem,
+    Op::Negate,
+    Op::Abs,
+    Op::Min,
+    Op::Max,
+    Op::OnePlus,
+    Op::OneMinus,
+    Op::TwoStar,
+    Op::TwoSlash,
+    Op::And,
+    Op::Or,
+    Op::Xor,
+    Op::Invert,
+    Op::Lshift,
+    Op::Rshift,
+    Op::Arshift,
+    Op::Cells,
+    Op::CellPlus,
+    Op::Eq,
+    Op::Ne,
+    Op::Lt,
+    Op::Le,
+    Op::Gt,
+    Op::Ge,
+    Op::ULt,
+    Op::UGt,
+    Op::ZeroEq,
+    Op::ZeroLt,
+    Op::UDiv,
+    Op::UMod,
+    Op::UmStar,
+    Op::UmSlashMod,
+    Op::SToD,
+    Op::DAdd,
+    Op::DSub,
+    Op::DNegate,
+    Op::DAbs,
+    Op::DEq,
+    Op::DLt,
+    Op::DZeroEq,
+    Op::MStar,
+    Op::MPlus,
+    Op::DTwoStar,
+    Op::DTwoSlash,
+    Op::TwoFetch,
+    Op::TwoStore,
+];
+
+fn mode_id(mode: ExternArgMode) -> u8 {
+    match mode {
+        ExternArgMode::PopI32Void => 0,
+        ExternArgMode::Void => 1,
+        ExternArgMode::RetI32Push => 2,
+        ExternArgMode::PopI32RetI32Push => 3,
+        ExternArgMode::StrVoid => 4,
+        ExternArgMode::Pop2I32Void => 5,
+        ExternArgMode::Pop2I32RetI32Push => 6,
+        ExternArgMode::Pop3I32Void => 7,
+        ExternArgMode::PopI64Void => 8,
+        ExternArgMode::PopI64I32Void => 9,
+    }
+}
+
+fn u16_index(k: usize, what: &str) -> Result<[u8; 2], String> {
+    u16::try_from(k)
+        .map(u16::to_le_bytes)
+        .map_err(|_| format!("Too many {} for --emit=bytecode (max 65536)", what))
+}
+
+struct Encoder<'m> {
+    symbols: HashMap<&'static str, &'static str>,
+    index: HashMap<&'m str, usize>,
+    strings: Vec<u8>,
+    string_at: HashMap<String, u32>,
+    // (symbol offset, mode) in import order.
+    imports: Vec<(u32, u8)>,
+    import_at: HashMap<&'static str, usize>,
+}
+
+impl<'m> Encoder<'m> {
+    fn string(&mut self, s: &str) -> u32 {
+        if let Some(&at) = self.string_at.get(s) {
+            return at;
+        }
+        let at = self.strings.len() as u32;
+        self.strings.extend_from_slice(s.as_bytes());
+        self.strings.push(0);
+        self.string_at.insert(s.to_string(), at);
+        at
+    }
+
+    fn word_index(&self, w: &str) -> Result<[u8; 2], String> {
+        let k = self
+            .index
+            .get(w)
+            .copied()
+            .ok_or_else(|| format!("Call to unknown word: {}", w))?;
+        u16_index(k, "words")
+    }
+
+    fn import(&mut self, key: &str, mode: ExternArgMode) -> Result<[u8; 2], String> {
+        let sym = *self
+            .symbols
+            .get(key)
+            .ok_or_else(|| format!("Unknown extern service word: {}", key))?;
+        let k = match self.import_at.get(sym) {
+            Some(&k) => k,
+            None => {
+                let name = self.string(sym);
+                self.imports.push((name, mode_id(mode)));
+                self.import_at.insert(sym, self.imports.len() - 1);
+                self.imports.len() - 1
+            }
+        };
+        u16_index(k, "runtime services")
+    }
+
+    fn inst(&mut self, code: &mut Vec<u8>, inst: &Inst) -> Result<(), String> {
+        match inst {
+            Inst::Lit(v) => match i8::try_from(*v) {
+                Ok(b) => code.extend([LIT8, b as u8]),
+                Err(_) => {
+                    code.push(LIT);
+                    code.extend(v.to_le_bytes());
+                }
+            },
+            Inst::Op(op) => {
+                let k = PRIMITIVES.iter().position(|p| p == op).unwrap();
+                code.push(PRIM_BASE + k as u8);
+            }
+            Inst::PickConst(n) => {
+                code.push(PICKC);
+                code.extend(n.to_le_bytes());
+            }
+            Inst::RollConst(n) if *n <= 0 => {}
+            Inst::RollConst(n) => {
+                code.push(ROLLC);
+                code.extend(n.to_le_bytes());
+            }
+            Inst::Call(w) => {
+                code.push(CALL);
+                code.extend(self.word_index(w)?);
+            }
+            Inst::Service(svc) => {
+                code.push(SVC);
+                code.extend(self.import(svc.name, svc.mode)?);
+            }
+            Inst::Type(s) => {
+                let at = self.string(s);
+                code.push(TYPE);
+                code.extend(at.to_le_bytes());
+            }
+            Inst::Line(_) => {}
+            Inst::MemCheck(c) => {
+                let (field, offset) = match c.addr {
+                    MemAddr::Top(k) => (0, k),
+                    MemAddr::Field => (4, 0),
+                };
+                code.extend([MEMCHK, c.access as u8 | field]);
+                code.extend(offset.to_le_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    fn word(&mut self, f: &Func) -> Result<Vec<u8>, String> {
+        let mut code = Vec::new();
+        let mut starts = vec![0u32; f.blocks.len()];
+        // (operand position, target block)
+        let mut fixups = Vec::new();
+        for (id, block) in f.blocks.iter().enumerate() {
+            starts[id] = code.len() as u32;
+            for inst in &block.insts {
+                self.inst(&mut code, inst)?;
+            }
+            let jump = match &block.term {
+                Term::Return => {
+                    code.push(EXIT);
+                    None
+                }
+                Term::Jump(t) => Some(*t),
+                Term::Branch { nonzero, zero } => {
+                    code.push(BRANCH0);
+                    fixups.push((code.len(), *zero));
+                    code.extend([0; 4]);
+                    Some(*nonzero)
+                }
+                Term::TailCall(w) => {
+                    code.push(TAIL);
+                    code.extend(self.word_index(w)?);
+                    None
+                }
+            };
+            // Fall through to the next block.
+            if let Some(t) = jump.filter(|&t| t != id + 1) {
+                code.push(JUMP);
+                fixups.push((code.len(), t));
+                code.extend([0; 4]);
+            }
+        }
+        for (at, t) in fixups {
+            code[at..at + 4].copy_from_slice(&starts[t].to_le_bytes());
+        }
+        Ok(code)
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, v: u32) {
+    out.extend(v.to_le_bytes());
+}
+
+pub fn emit(m: &Module) -> Result<Vec<u8>, String> {
+    let mut e = Encoder {
+        symbols: RUNTIME_SYMBOLS.iter().copied().collect(),
+        index: m
+            .funcs
+            .iter()
+            .enumerate()
+            .map(|(k, f)| (f.name.as_str(), k))
+            .collect(),
+        strings: Vec::new(),
+        string_at: HashMap::new(),
+        imports: Vec::new(),
+        import_at: HashMap::new(),
+    };
+    let mut words = Vec::new();
+    for f in &m.funcs {
+        let name = e.string(&f.name);
+        let code = e.word(f)?;
+        words.push((name, code));
+    }
+    let entry = e.word_index(&m.entry)?;
+
+    let mut out = MAGIC.to_vec();
+    put_u32(&mut out, VERSION);
+    let floored = m.division == DivisionMode::Floored;
+    put_u32(&mut out, if floored { FLAG_FLOORED } else { 0 });
+    out.extend(m.here.to_le_bytes());
+    put_u32(&mut out, u16::from_le_bytes(entry) as u32);
+
+    put_u32(&mut out, e.strings.len() as u32);
+    out.extend(&e.strings);
+    put_u32(&mut out, e.imports.len() as u32);
+    for (name, mode) in &e.imports {
+        put_u32(&mut out, *name);
+        out.push(*mode);
+    }
+    put_u32(&mut out, m.static_inits.len() as u32);
+    for (addr, v) in &m.static_inits {
+        out.extend(addr.to_le_bytes());
+        out.extend(v.to_le_bytes());
+    }
+    put_u32(&mut out, words.len() as u32);
+    for (name, code) in &words {
+        put_u32(&mut out, *name);
+        put_u32(&mut out, code.len() as u32);
+        out.extend(code);
+    }
+    Ok(out)
+}
@@ -0,0 +1,555 @@
+// `--emit=c`: kIR as one portable C file, linked with runtime/runtime.c.
+//
+// The data stack is a static array and every definition a C function with
+// a local return stack when it uses one. C has no guaranteed tail calls, so
+// a definition returns the table index of its tail callee (0 = none) and
+// kf_run keeps calling until a word really returns; self tail calls are a
+// `goto start`. Other calls invoke the callee directly and hand its result
+// to kf_run.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use crate::kir::{ExternArgMode, Func, Inst, MemAddr, Module, Op, Term};
+use crate::{llvm_word_sym, DivisionMode, RUNTIME_SYMBOLS};
+
+// Support code, emitted only when referenced: (name, C definition). Later
+// entries may use earlier ones.
+const HELPERS: &[(&str, &str)] = &[
+    ("kf_sp", "static int32_t kf_stack[1024];\nstatic int32_t kf_sp;\n"),
+    ("kf_push", "static void kf_push(int32_t v) { kf_stack[kf_sp++] = v; }\n"),
+    ("kf_pop", "static int32_t kf_pop(void) { return kf_stack[--kf_sp]; }\n"),
+    ("kf_flag", "static int32_t kf_flag(int c) { return c ? -1 : 0; }\n"),
+    (
+        "kf_push2",
+        "/* Doubles are ( lo hi ) with the high cell on top. */
+static void kf_push2(int64_t d) {
+  kf_push((int32_t)(uint32_t)(uint64_t)d);
+  kf_push((int32_t)(uint32_t)((uint64_t)d >> 32));
+}
+",
+    ),
+    (
+        "kf_pop2",
+        "static int64_t kf_pop2(void) {
+  uint64_t hi = (uint32_t)kf_pop();
+  uint64_t lo = (uint32_t)kf_pop();
+  return (int64_t)((hi << 32) | lo);
+}
+",
+    ),
+    (
+        "kf_sdivrem",
+        "/* Symmetric or floored; MIN / -1 wraps. */
+static void kf_sdivrem(int64_t d, int64_t n, int floored, const char* word, int64_t* r,
+                       int64_t* q) {
+  if (n == 0) rt_div_zero(word);
+  if (n == -1) {
+    *q = (int64_t)(0 - (uint64_t)d);
+    *r = 0;
+  } else {
+    *q = d / n;
+    *r = d % n;
+  }
+  if (floored && *r != 0 && (*r ^ n) < 0) {
+    *q -= 1;
+    *r += n;
+  }
+}
+",
+    ),
+    (
+        "kf_udivrem",
+        "static void kf_udivrem(uint64_t d, uint64_t n, const char* word, uint64_t* r, uint64_t* q) {
+  if (n == 0) rt_div_zero(word);
+  *q = d / n;
+  *r = d % n;
+}
+",
+    ),
+];
+
+// C prototype of a runtime service.
+fn prototype(sym: &str, mode: ExternArgMode) -> String {
+    let (ret, params) = match mode {
+        ExternArgMode::PopI32Void => ("void", "int32_t"),
+        ExternArgMode::Void => ("void", "void"),
+        ExternArgMode::RetI32Push => ("int32_t", "void"),
+        ExternArgMode::PopI32RetI32Push => ("int32_t", "int32_t"),
+        ExternArgMode::StrVoid => ("void", "const char*"),
+        ExternArgMode::Pop2I32Void => ("void", "int32_t, int32_t"),
+        ExternArgMode::Pop2I32RetI32Push => ("int32_t", "int32_t, int32_t"),
+        ExternArgMode::Pop3I32Void => ("void", "int32_t, int32_t, int32_t"),
+        ExternArgMode::PopI64Void => ("void", "int64_t"),
+        ExternArgMode::PopI64I32Void => ("void", "int64_t, int32_t"),
+    };
+    format!("{} {}({});", ret, sym, params)
+}
+
+// INT32_MIN has no literal form in C.
+fn c_int(v: i32) -> String {
+    if v == i32::MIN {
+        "(-2147483647 - 1)".into()
+    } else {
+        v.to_string()
+    }
+}
+
+// Printable ASCII as is, everything else as three-digit octal escapes.
+fn c_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for b in s.bytes() {
+        match b {
+            b'"' | b'\\' | b'?' => {
+                out.push('\\');
+                out.push(b as char);
+            }
+            0x20..=0x7e => out.push(b as char),
+            _ => {
+                let _ = write!(out, "\\{:03o}", b);
+            }
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Replaces the top `depth` cells by the cells in `order` (0 = deepest).
+fn permute(depth: usize, order: &[usize]) -> String {
+    let mut used: Vec<usize> = order.to_vec();
+    used.sort_unstable();
+    used.dedup();
+    if used.is_empty() {
+        return format!("kf_sp -= {};", depth);
+    }
+    let reads: Vec<String> = used
+        .iter()
+        .map(|k| format!("x{} = kf_stack[kf_sp - {}]", k, depth - k))
+        .collect();
+    let pushes: String = order.iter().map(|k| format!(" kf_push(x{});", k)).collect();
+    format!(
+        "{{ int32_t {}; kf_sp -= {};{} }}",
+        reads.join(", "),
+        depth,
+        pushes
+    )
+}
+
+fn unary(expr: &str) -> String {
+    format!("{{ int32_t a = kf_pop(); kf_push({}); }}", expr)
+}
+
+fn binary(expr: &str) -> String {
+    format!(
+        "{{ int32_t b = kf_pop(), a = kf_pop(); kf_push({}); }}",
+        expr
+    )
+}
+
+fn double_binary(expr: &str) -> String {
+    format!(
+        "{{ int64_t b = kf_pop2(), a = kf_pop2(); kf_push2({}); }}",
+        expr
+    )
+}
+
+fn double_cmp(expr: &str) -> String {
+    format!(
+        "{{ int64_t b = kf_pop2(), a = kf_pop2(); kf_push(kf_flag({})); }}",
+        expr
+    )
+}
+
+struct CEmitter<'m> {
+    m: &'m Module,
+    out: String,
+    // Runtime symbol -> prototype, for the functions used.
+    protos: BTreeMap<String, String>,
+    symbols: HashMap<&'static str, &'static str>,
+    // Definition -> kf_run index (from 1).
+    index: HashMap<&'m str, usize>,
+    floored: bool,
+    source: Option<&'m str>,
+}
+
+impl<'m> CEmitter<'m> {
+    fn line(&mut self, s: &str) {
+        self.out.push_str(s);
+        self.out.push('\n');
+    }
+
+    fn index_of(&self, w: &str) -> Result<usize, String> {
+        self.index
+            .get(w)
+            .copied()
+            .ok_or_else(|| format!("Call to unknown word: {}", w))
+    }
+
+    fn runtime(&mut self, key: &str, mode: ExternArgMode) -> Result<&'static str, String> {
+        let sym = *self
+            .symbols
+            .get(key)
+            .ok_or_else(|| format!("Unknown extern service word: {}", key))?;
+        self.protos
+            .entry(sym.to_string())
+            .or_insert_with(|| prototype(sym, mode));
+        Ok(sym)
+    }
+
+    fn div(&self, word: &str, push_rem: bool, push_quo: bool) -> String {
+        let mut s = format!(
+            "{{ int32_t b = kf_pop(), a = kf_pop(); int64_t r, q; kf_sdivrem(a, b, {}, {}, &r, &q);",
+            self.floored as i32, word
+        );
+        if push_rem {
+            s.push_str(" kf_push((int32_t)r);");
+        }
+        if push_quo {
+            s.push_str(" kf_push((int32_t)q);");
+        }
+        s + " }"
+    }
+
+    fn udiv(&self, word: &str, push_rem: bool, push_quo: bool) -> String {
+        let mut s = format!(
+            "{{ uint32_t b = (uint32_t)kf_pop(), a = (uint32_t)kf_pop(); uint64_t r, q; kf_udivrem(a, b, {}, &r, &q);",
+            word
+        );
+        if push_rem {
+            s.push_str(" kf_push((int32_t)(uint32_t)r);");
+        }
+        if push_quo {
+            s.push_str(" kf_push((int32_t)(uint32_t)q);");
+        }
+        s + " }"
+    }
+
+    fn op(&mut self, op: Op, word: &str) -> String {
+        let fl = self.floored as i32;
+        match op {
+            Op::Dup => permute(1, &[0, 0]),
+            Op::Drop => permute(1, &[]),
+            Op::Swap => permute(2, &[1, 0]),
+            Op::Over => permute(2, &[0, 1, 0]),
+            Op::Rot => permute(3, &[1, 2, 0]),
+            Op::MinusRot => permute(3, &[2, 0, 1]),
+            Op::Nip => permute(2, &[1]),
+            Op::Tuck => permute(2, &[1, 0, 1]),
+            Op::QDup => "{ int32_t a = kf_pop(); kf_push(a); if (a != 0) kf_push(a); }".into(),
+            Op::TwoDup => permute(2, &[0, 1, 0, 1]),
+            Op::TwoDrop => permute(2, &[]),
+            Op::TwoSwap => permute(4, &[2, 3, 0, 1]),
+            Op::TwoOver => permute(4, &[0, 1, 2, 3, 0, 1]),
+            Op::Pick => "{ int32_t n = kf_pop(); kf_push(kf_stack[kf_sp - 1 - n]); }".into(),
+            Op::Roll => "{ int32_t u = kf_pop(), top = kf_sp - 1, k = top - u, v = kf_stack[k]; \
+                         for (; k < top; k++) kf_stack[k] = kf_stack[k + 1]; kf_stack[top] = v; }"
+                .into(),
+            Op::Depth => "kf_push(kf_sp);".into(),
+            Op::ToR => "rs[rsp++] = kf_pop();".into(),
+            Op::RFrom => "kf_push(rs[--rsp]);".into(),
+            Op::RFetch => "kf_push(rs[rsp - 1]);".into(),
+            Op::TwoToR => {
+                "{ int32_t b = kf_pop(), a = kf_pop(); rs[rsp++] = a; rs[rsp++] = b; }".into()
+            }
+            Op::TwoRFrom => {
+                "{ int32_t b = rs[--rsp], a = rs[--rsp]; kf_push(a); kf_push(b); }".into()
+            }
+            Op::TwoRFetch => "kf_push(rs[rsp - 2]); kf_push(rs[rsp - 1]);".into(),
+            Op::Add => binary("(int32_t)((uint32_t)a + (uint32_t)b)"),
+            Op::Sub => binary("(int32_t)((uint32_t)a - (uint32_t)b)"),
+            Op::Mul => binary("(int32_t)((uint32_t)a * (uint32_t)b)"),
+            Op::Div => self.div(word, false, true),
+            Op::Mod => self.div(word, true, false),
+            Op::DivMod => self.div(word, true, true),
+            Op::StarSlash | Op::StarSlashMod => {
+                let rem = if op == Op::StarSlashMod {
+                    " kf_push((int32_t)r);"
+                } else {
+                    ""
+                };
+                format!(
+                    "{{ int32_t n3 = kf_pop(), n2 = kf_pop(), n1 = kf_pop(); int64_t r, q; \
+                     kf_sdivrem((int64_t)n1 * n2, n3, {}, {}, &r, &q);{} kf_push((int32_t)q); }}",
+                    fl, word, rem
+                )
+            }
+            Op::FmMod | Op::SmRem => format!(
+                "{{ int32_t n = kf_pop(); int64_t d = kf_pop2(), r, q; \
+                 kf_sdivrem(d, n, {}, {}, &r, &q); kf_push((int32_t)r); kf_push((int32_t)q); }}",
+                (op == Op::FmMod) as i32,
+                word
+            ),
+            Op::Negate => unary("(int32_t)(0u - (uint32_t)a)"),
+            Op::Abs => unary("a < 0 ? (int32_t)(0u - (uint32_t)a) : a"),
+            Op::Min => binary("a < b ? a : b"),
+            Op::Max => binary("a > b ? a : b"),
+            Op::OnePlus => unary("(int32_t)((uint32_t)a + 1u)"),
+            Op::OneMinus => unary("(int32_t)((uint32_t)a - 1u)"),
+            Op::TwoStar => unary("(int32_t)((uint32_t)a << 1)"),
+            Op::TwoSlash => unary("a >> 1"),
+            Op::And => binary("a & b"),
+            Op::Or => binary("a | b"),
+            Op::Xor => binary("a ^ b"),
+            Op::Invert => unary("~a"),
+            Op::Lshift => binary("(int32_t)((uint32_t)a << (b & 31))"),
+            Op::Rshift => binary("(int32_t)((uint32_t)a >> (b & 31))"),
+            Op::Arshift => binary("a >> (b & 31)"),
+            Op::Cells => unary("(int32_t)((uint32_t)a * 4u)"),
+            Op::CellPlus => unary("(int32_t)((uint32_t)a + 4u)"),
+            Op::Eq => binary("kf_flag(a == b)"),
+            Op::Ne => binary("kf_flag(a != b)"),
+            Op::Lt => binary("kf_flag(a < b)"),
+            Op::Le => binary("kf_flag(a <= b)"),
+            Op::Gt => binary("kf_flag(a > b)"),
+            Op::Ge => binary("kf_flag(a >= b)"),
+            Op::ULt => binary("kf_flag((uint32_t)a < (uint32_t)b)"),
+            Op::UGt => binary("kf_flag((uint32_t)a > (uint32_t)b)"),
+            Op::ZeroEq => unary("kf_flag(a == 0)"),
+            Op::ZeroLt => unary("kf_flag(a < 0)"),
+            Op::UDiv => self.udiv(word, false, true),
+            Op::UMod => self.udiv(word, true, false),
+            Op::UmStar => "{ uint32_t b = (uint32_t)kf_pop(), a = (uint32_t)kf_pop(); \
+                           kf_push2((int64_t)((uint64_t)a * b)); }"
+                .into(),
+            Op::UmSlashMod => format!(
+                "{{ uint32_t n = (uint32_t)kf_pop(); uint64_t d = (uint64_t)kf_pop2(), r, q; \
+                 kf_udivrem(d, n, {}, &r, &q); kf_push((int32_t)(uint32_t)r); kf_push((int32_t)(uint32_t)q); }}",
+                word
+            ),
+            Op::SToD => "kf_push2(kf_pop());".into(),
+            Op::DAdd => double_binary("(int64_t)((uint64_t)a + (uint64_t)b)"),
+            Op::DSub => double_binary("(int64_t)((uint64_t)a - (uint64_t)b)"),
+            Op::DNegate => "kf_push2((int64_t)(0 - (uint64_t)kf_pop2()));".into(),
+            Op::DAbs => "{ int64_t a = kf_pop2(); kf_push2(a < 0 ? (int64_t)(0 - (uint64_t)a) : a); }"
+                .into(),
+            Op::DEq => double_cmp("a == b"),
+            Op::DLt => double_cmp("a < b"),
+            Op::DZeroEq => "kf_push(kf_flag(kf_pop2() == 0));".into(),
+            Op::MStar => "{ int32_t b = kf_pop(), a = kf_pop(); kf_push2((int64_t)a * b); }".into(),
+            Op::MPlus => "{ int32_t n = kf_pop(); int64_t d = kf_pop2(); \
+                          kf_push2((int64_t)((uint64_t)d + (uint64_t)(int64_t)n)); }"
+                .into(),
+            Op::DTwoStar => "kf_push2((int64_t)((uint64_t)kf_pop2() << 1));".into(),
+            Op::DTwoSlash => "kf_push2(kf_pop2() >> 1);".into(),
+            Op::TwoFetch => "{ int32_t addr = kf_pop(); int32_t lo = pvar_get((int32_t)((uint32_t)addr + 4u)); \
+                             kf_push(lo); kf_push(pvar_get(addr)); }"
+                .into(),
+            Op::TwoStore => "{ int32_t addr = kf_pop(), hi = kf_pop(), lo = kf_pop(); \
+                             pvar_set(hi, addr); pvar_set(lo, (int32_t)((uint32_t)addr + 4u)); }"
+                .into(),
+        }
+    }
+
+    fn inst(&mut self, inst: &Inst, word: &str) -> Result<String, String> {
+        Ok(match inst {
+            Inst::Lit(v) => format!("kf_push({});", c_int(*v)),
+            Inst::Op(op) => self.op(*op, word),
+            Inst::PickConst(n) => format!("kf_push(kf_stack[kf_sp - 1 - {}]);", c_int(*n)),
+            Inst::RollConst(n) if *n <= 0 => String::new(),
+            Inst::RollConst(n) => {
+                let depth = *n as usize + 1;
+                let mut order: Vec<usize> = (1..depth).collect();
+                order.push(0);
+                permute(depth, &order)
+            }
+            // Called directly, so a recursion level costs one C frame;
+            // kf_run then follows any tail call it returns.
+            Inst::Call(w) => {
+                self.index_of(w)?;
+                format!("kf_run({}());", llvm_word_sym(w))
+            }
+            Inst::Service(svc) => {
+                let sym = self.runtime(svc.name, svc.mode)?;
+                match svc.mode {
+                    ExternArgMode::PopI32Void => format!("{}(kf_pop());", sym),
+                    ExternArgMode::Void => format!("{}();", sym),
+                    ExternArgMode::RetI32Push => format!("kf_push({}());", sym),
+                    ExternArgMode::PopI32RetI32Push => format!("kf_push({}(kf_pop()));", sym),
+                    ExternArgMode::Pop2I32Void => {
+                        format!("{{ int32_t b = kf_pop(), a = kf_pop(); {}(a, b); }}", sym)
+                    }
+                    ExternArgMode::Pop2I32RetI32Push => binary(&format!("{}(a, b)", sym)),
+                    ExternArgMode::Pop3I32Void => format!(
+                        "{{ int32_t c = kf_pop(), b = kf_pop(), a = kf_pop(); {}(a, b, c); }}",
+                        sym
+                    ),
+                    ExternArgMode::PopI64Void => format!("{}(kf_pop2());", sym),
+                    ExternArgMode::PopI64I32Void => format!(
+                        "{{ int32_t n = kf_pop(); int64_t d = kf_pop2(); {}(d, n); }}",
+                        sym
+                    ),
+                    ExternArgMode::StrVoid => {
+                        return Err(format!("Service {} needs a string operand", svc.name))
+                    }
+                }
+            }
+            Inst::Type(s) => {
+                let sym = self.runtime("TYPE", ExternArgMode::StrVoid)?;
+                format!("{}({});", sym, c_string(s))
+            }
+            Inst::Line(n) => match self.source {
+                Some(src) => format!("#line {} {}", n, c_string(src)),
+                None => String::new(),
+            },
+            Inst::MemCheck(c) => {
+                let addr = match c.addr {
+                    MemAddr::Top(k) => format!("(uint32_t)kf_stack[kf_sp - 1] + {}u", k),
+                    MemAddr::Field => {
+                        "(uint32_t)kf_stack[kf_sp - 2] + (uint32_t)kf_stack[kf_sp - 1]".into()
+                    }
+                };
+                format!(
+                    "rt_mem_check((int32_t)({}), {}, {});",
+                    addr, c.access as i32, word
+                )
+            }
+        })
+    }
+
+    fn func(&mut self, f: &Func) -> Result<(), String> {
+        let word = c_string(&f.name);
+        self.line(&format!(
+            "static int32_t {}(void) {{",
+            llvm_word_sym(&f.name)
+        ));
+        let rstack = f.uses_return_stack();
+        if rstack {
+            self.line("  int32_t rs[1024];");
+            self.line("  int32_t rsp;");
+        }
+        if f.has_self_tail_call() {
+            self.line("start:");
+        }
+        if rstack {
+            self.line("  rsp = 0;");
+        }
+        for (id, block) in f.blocks.iter().enumerate() {
+            if id > 0 {
+                self.line(&format!("{}:;", f.label(id)));
+            }
+            for inst in &block.insts {
+                let s = self.inst(inst, &word)?;
+                if s.starts_with('#') {
+                    self.line(&s);
+                } else if !s.is_empty() {
+                    self.line(&format!("  {}", s));
+                }
+            }
+            let term = match &block.term {
+                Term::Return => "return 0;".to_string(),
+                Term::Jump(t) => format!("goto {};", f.label(*t)),
+                Term::Branch { nonzero, zero } => format!(
+                    "if (kf_pop() != 0) goto {}; else goto {};",
+                    f.label(*nonzero),
+                    f.label(*zero)
+                ),
+                Term::TailCall(w) if *w == f.name => "goto start;".to_string(),
+                Term::TailCall(w) => format!("return {};", self.index_of(w)?),
+            };
+            self.line(&format!("  {}", term));
+        }
+        self.line("}");
+        self.line("");
+        Ok(())
+    }
+}
+
+// `source` (the .fth path) turns kIR line markers into `#line` directives.
+pub fn emit(m: &Module, source: Option<&str>) -> Result<String, String> {
+    let mut e = CEmitter {
+        m,
+        out: String::new(),
+        protos: BTreeMap::new(),
+        symbols: RUNTIME_SYMBOLS.iter().copied().collect(),
+        index: m
+            .funcs
+            .iter()
+            .enumerate()
+            .map(|(k, f)| (f.name.as_str(), k + 1))
+            .collect(),
+        floored: m.division == DivisionMode::Floored,
+        source,
+    };
+    for f in &m.funcs {
+        e.func(f)?;
+    }
+    let body = std::mem::take(&mut e.out);
+
+    e.line("/* Generated by kforthc; link with runtime/runtime.c. */");
+    e.line("#include <stdint.h>");
+    e.line("");
+    e.line("void rt_div_zero(const char* word);");
+    e.line("void rt_mem_check(int32_t addr, int32_t kind, const char* word);");
+    e.line("void rt_set_data_image(const int32_t* pairs, int32_t n);");
+    e.line("void rt_heap_reset(int32_t base);");
+    e.protos
+        .entry("pvar_get".into())
+        .or_insert_with(|| prototype("pvar_get", ExternArgMode::PopI32RetI32Push));
+    e.protos
+        .entry("pvar_set".into())
+        .or_insert_with(|| prototype("pvar_set", ExternArgMode::Pop2I32Void));
+    let protos: Vec<String> = e.protos.values().cloned().collect();
+    for p in protos {
+        e.line(&p);
+    }
+    e.line("");
+    let helpers_at = e.out.len();
+    for f in &e.m.funcs {
+        e.line(&format!("static int32_t {}(void);", llvm_word_sym(&f.name)));
+    }
+    let table: Vec<String> = e.m.funcs.iter().map(|f| llvm_word_sym(&f.name)).collect();
+    e.line(&format!(
+        "static int32_t (*const kf_words[])(void) = {{0, {}}};",
+        table.join(", ")
+    ));
+    e.line("");
+    e.line("/* Runs word `w` and the chain of tail calls it returns. */");
+    e.line("static void kf_run(int32_t w) {");
+    e.line("  while (w != 0) w = kf_words[w]();");
+    e.line("}");
+    e.line("");
+    e.out.push_str(&body);
+
+    let entry = e.index_of(&m.entry)?;
+    if !m.static_inits.is_empty() {
+        let pairs: Vec<String> = m
+            .static_inits
+            .iter()
+            .map(|(a, v)| format!("{}, {}", c_int(*a), c_int(*v)))
+            .collect();
+        e.line(&format!(
+            "static const int32_t kf_data_image[] = {{{}}};",
+            pairs.join(", ")
+        ));
+        e.line("");
+    }
+    e.line("int main(void) {");
+    if !m.static_inits.is_empty() {
+        e.line(&format!(
+            "  rt_set_data_image(kf_data_image, {});",
+            m.static_inits.len()
+        ));
+    }
+    e.line(&format!("  rt_heap_reset({});", c_int(m.here)));
+    e.line(&format!("  kf_run({});", entry));
+    e.line("  return 0;");
+    e.line("}");
+
+    // Helpers go between the prototypes and the definitions.
+    let mut text = e.out.clone();
+    let mut helpers = vec![false; HELPERS.len()];
+    for (k, (name, code)) in HELPERS.iter().enumerate().rev() {
+        if text.contains(name) {
+            helpers[k] = true;
+            text.push_str(code);
+        }
+    }
+    let support: String = HELPERS
+        .iter()
+        .zip(&helpers)
+        .filter(|(_, &used)| used)
+        .map(|((_, code), _)| format!("{}\n", code))
+        .collect();
+    e.out.insert_str(helpers_at, &support);
+    Ok(e.out)
+}
@@ -0,0 +1,111 @@
+// DWARF metadata for `-g`: one compile unit for the input file, a
+// DISubprogram per definition and a DILocation per source line used in it.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+pub struct DebugInfo {
+    nodes: Vec<String>,
+    cu: usize,
+    file: usize,
+    subroutine_type: usize,
+    scope: usize,
+    // (scope, line) -> DILocation node
+    locations: HashMap<(usize, u32), usize>,
+}
+
+// Bytes outside printable ASCII, `"` and `\` as LLVM `\XX` escapes.
+fn escape(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        if (0x20..0x7f).contains(&b) && b != b'"' && b != b'\\' {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("\\{:02X}", b));
+        }
+    }
+    out
+}
+
+impl DebugInfo {
+    pub fn new(input: &str) -> Self {
+        let path = Path::new(input);
+        let filename = path
+            .file_name()
+            .map_or(input.into(), |f| f.to_string_lossy().into_owned());
+        let dir = match path.parent() {
+            Some(p) if p.is_absolute() => p.to_path_buf(),
+            Some(p) => std::env::current_dir().unwrap_or_default().join(p),
+            None => std::env::current_dir().unwrap_or_default(),
+        };
+        let directory = dir.to_string_lossy().trim_end_matches('/').to_string();
+        let mut d = DebugInfo {
+            nodes: Vec::new(),
+            cu: 0,
+            file: 0,
+            subroutine_type: 0,
+            scope: 0,
+            locations: HashMap::new(),
+        };
+        d.file = d.node(format!(
+            "!DIFile(filename: \"{}\", directory: \"{}\")",
+            escape(&filename),
+            escape(&directory)
+        ));
+        d.cu = d.node(format!(
+            "distinct !DICompileUnit(language: DW_LANG_C, file: !{}, producer: \"kforthc\", isOptimized: false, runtimeVersion: 0, emissionKind: FullDebug)",
+            d.file
+        ));
+        let types = d.node("!{null}".into());
+        d.subroutine_type = d.node(format!("!DISubroutineType(types: !{})", types));
+        d
+    }
+
+    fn node(&mut self, text: String) -> usize {
+        self.nodes.push(text);
+        self.nodes.len() - 1
+    }
+
+    // Starts a definition; returns its DISubprogram for the `define` line.
+    pub fn subprogram(&mut self, name: &str, linkage_name: &str, line: u32) -> usize {
+        self.scope = self.node(format!(
+            "distinct !DISubprogram(name: \"{}\", linkageName: \"{}\", scope: !{}, file: !{}, line: {}, type: !{}, scopeLine: {}, spFlags: DISPFlagDefinition, unit: !{})",
+            escape(name),
+            linkage_name,
+            self.file,
+            self.file,
+            line,
+            self.subroutine_type,
+            line,
+            self.cu
+        ));
+        self.scope
+    }
+
+    pub fn location(&mut self, line: u32) -> usize {
+        let key = (self.scope, line);
+        if let Some(&id) = self.locations.get(&key) {
+            return id;
+        }
+        let id = self.node(format!(
+            "!DILocation(line: {}, column: 0, scope: !{})",
+            line, self.scope
+        ));
+        self.locations.insert(key, id);
+        id
+    }
+
+    // Named metadata and all nodes, for the end of the module.
+    pub fn finish(mut self) -> String {
+        let dwarf = self.node("!{i32 7, !\"Dwarf Version\", i32 4}".into());
+        let version = self.node("!{i32 2, !\"Debug Info Version\", i32 3}".into());
+        let mut out = format!(
+            "!llvm.dbg.cu = !{{!{}}}\n!llvm.module.flags = !{{!{}, !{}}}\n",
+            self.cu, dwarf, version
+        );
+        for (id, text) in self.nodes.iter().enumerate() {
+            out.push_str(&format!("!{} = {}\n", id, text));
+        }
+        out
+    }
+}
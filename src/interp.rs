@@ -0,0 +1,857 @@
+// `kforthc run`: executes kIR directly with the semantics of the compiled
+// program linked against runtime/runtime.c (cells, memory, I/O, floats).
+//
+// Calls use an explicit frame stack, so tail calls reuse their frame and
+// deep recursion does not grow the native stack.
+
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Read, Write};
+
+use crate::kir::{Access, ExternArgMode, Inst, MemAddr, MemCheck, Module, Op, Service, Term};
+use crate::CtMachine;
+
+// Same sizes as the generated code and runtime.c.
+const STACK_CELLS: usize = 1024;
+const RSTACK_CELLS: usize = 1024;
+const MEM_CELLS: i32 = 134217728;
+
+struct Frame {
+    func: usize,
+    block: usize,
+    pc: usize,
+    rstack: Vec<i32>,
+}
+
+// stdin as runtime.c reads it: bytes with one character of pushback.
+struct Input {
+    bytes: io::Bytes<io::StdinLock<'static>>,
+    pushback: Option<u8>,
+}
+
+impl Input {
+    fn getc(&mut self) -> Option<u8> {
+        self.pushback
+            .take()
+            .or_else(|| self.bytes.next().and_then(|b| b.ok()))
+    }
+
+    // Next whitespace-delimited token (at most 255 bytes kept).
+    fn token(&mut self) -> Option<Vec<u8>> {
+        let mut c = loop {
+            match self.getc() {
+                None => return None,
+                Some(c) if c.is_ascii_whitespace() => {}
+                Some(c) => break Some(c),
+            }
+        };
+        let mut tok = Vec::new();
+        while let Some(ch) = c {
+            if ch.is_ascii_whitespace() {
+                self.pushback = Some(ch);
+                break;
+            }
+            if tok.len() < 255 {
+                tok.push(ch);
+            }
+            c = self.getc();
+        }
+        Some(tok)
+    }
+}
+
+// strtoll(tok, &end, 10) requiring the whole token, truncated to a cell.
+fn parse_i32_token(tok: &[u8]) -> i32 {
+    let (neg, digits) = match tok.first() {
+        Some(b'-') => (true, &tok[1..]),
+        Some(b'+') => (false, &tok[1..]),
+        _ => (false, tok),
+    };
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return 0;
+    }
+    let mut v: i64 = 0;
+    for d in digits {
+        let d = (d - b'0') as i64;
+        v = if neg {
+            v.saturating_mul(10).saturating_sub(d)
+        } else {
+            v.saturating_mul(10).saturating_add(d)
+        };
+    }
+    v as i32
+}
+
+fn bits_to_f32(bits: i32) -> f32 {
+    f32::from_bits(bits as u32)
+}
+
+fn f32_to_bits(f: f32) -> i32 {
+    f.to_bits() as i32
+}
+
+// C float -> int32_t conversion as x86 performs it (cvttss2si): NaN and
+// out-of-range values give INT32_MIN.
+fn f32_to_i32(x: f32) -> i32 {
+    if x.is_nan() || !(-2147483648.0..2147483648.0).contains(&x) {
+        i32::MIN
+    } else {
+        x as i32
+    }
+}
+
+fn forth_bool(c: bool) -> i32 {
+    if c {
+        -1
+    } else {
+        0
+    }
+}
+
+const KP_FIX_SCALE_F: f32 = 10000.0;
+const KP_DEG2RAD_F: f32 = std::f32::consts::PI / 180.0;
+const KP_RAD2DEG_F: f32 = 180.0 / std::f32::consts::PI;
+
+fn kp_fix_from_float(x: f32) -> i32 {
+    if x >= 0.0 {
+        f32_to_i32(x * KP_FIX_SCALE_F + 0.5)
+    } else {
+        f32_to_i32(x * KP_FIX_SCALE_F - 0.5)
+    }
+}
+
+fn kp_round_deg(deg: f32) -> i32 {
+    f32_to_i32(if deg >= 0.0 { deg + 0.5 } else { deg - 0.5 })
+}
+
+fn kp_fix_unit(v: i32) -> f32 {
+    (v as f32 / KP_FIX_SCALE_F).clamp(-1.0, 1.0)
+}
+
+// Single-argument float and fixed-point services.
+fn unary_service(name: &str, a: i32) -> Option<i32> {
+    let f = bits_to_f32(a);
+    let u = a as u32;
+    let exp_all_ones = (u >> 23) & 0xFF == 0xFF;
+    Some(match name {
+        "PBOOL" => (a != 0) as i32,
+        "FNEGATE" => (u ^ 0x8000_0000) as i32,
+        "FABS" => (u & 0x7FFF_FFFF) as i32,
+        "FZERO?" => forth_bool(u & 0x7FFF_FFFF == 0),
+        "FINF?" => forth_bool(exp_all_ones && u & 0x7F_FFFF == 0),
+        "FNAN?" => forth_bool(exp_all_ones && u & 0x7F_FFFF != 0),
+        "FFINITE?" => forth_bool(!exp_all_ones),
+        "S>F" => f32_to_bits(a as f32),
+        "F>S" => f32_to_i32(f),
+        "Q16.16>F" => f32_to_bits(a as f32 / 65536.0),
+        "F>Q16.16" => f32_to_i32(f * 65536.0),
+        "FROUND-I32" => f32_to_i32(if f >= 0.0 { f + 0.5 } else { f - 0.5 }),
+        "__KP_FABS_F32" => f32_to_bits(f.abs()),
+        "__KP_FSQRT_F32" => f32_to_bits(f.sqrt()),
+        "__KP_FSIN_F32" => f32_to_bits(f.sin()),
+        "__KP_FCOS_F32" => f32_to_bits(f.cos()),
+        "__KP_FFLOOR_F32" => f32_to_bits(f.floor()),
+        "__KP_FCEIL_F32" => f32_to_bits(f.ceil()),
+        "__KP_FX_SQRT" if a <= 0 => 0,
+        "__KP_FX_SQRT" => f32_to_i32((a as f32).sqrt().floor()),
+        "__KP_FX_SIN" => kp_fix_from_float((a as f32 * KP_DEG2RAD_F).sin()),
+        "__KP_FX_COS" => kp_fix_from_float((a as f32 * KP_DEG2RAD_F).cos()),
+        "__KP_FX_TAN" => {
+            let r = a as f32 * KP_DEG2RAD_F;
+            if r.cos().abs() < 1.0e-6 {
+                0
+            } else {
+                kp_fix_from_float(r.tan())
+            }
+        }
+        "__KP_FX_ASIN" => kp_round_deg(kp_fix_unit(a).asin() * KP_RAD2DEG_F),
+        "__KP_FX_ACOS" => kp_round_deg(kp_fix_unit(a).acos() * KP_RAD2DEG_F),
+        "__KP_FX_ATAN" => kp_round_deg((a as f32 / KP_FIX_SCALE_F).atan() * KP_RAD2DEG_F),
+        "__KP_FX_LN" if a <= 0 => 0,
+        "__KP_FX_LN" => kp_fix_from_float((a as f32).ln()),
+        "__KP_FX_LOG" if a <= 0 => 0,
+        "__KP_FX_LOG" => kp_fix_from_float((a as f32).log10()),
+        _ => return None,
+    })
+}
+
+fn binary_service(name: &str, a: i32, b: i32) -> Option<i32> {
+    let (fa, fb) = (bits_to_f32(a), bits_to_f32(b));
+    Some(match name {
+        "FADD" => f32_to_bits(fa + fb),
+        "FSUB" => f32_to_bits(fa - fb),
+        "FMUL" => f32_to_bits(fa * fb),
+        "FDIV" => f32_to_bits(fa / fb),
+        "F=" => forth_bool(fa == fb),
+        "F<" => forth_bool(fa < fb),
+        "F<=" => forth_bool(fa <= fb),
+        "__KP_FPOW_F32_I32" => f32_to_bits(fa.powf(b as f32)),
+        _ => return None,
+    })
+}
+
+struct Machine<'m> {
+    m: &'m Module,
+    index: HashMap<&'m str, usize>,
+    data: CtMachine,
+    // Grown on demand up to MEM_CELLS.
+    mem: Vec<i32>,
+    here: i32,
+    heap: Heap,
+    input: Input,
+    out: BufWriter<io::Stdout>,
+}
+
+// ALLOCATE / FREE / RESIZE state, laid out in `mem` as in runtime.c.
+struct Heap {
+    last: i32,
+    end: i32,
+    freed: i32,
+    ior: i32,
+}
+
+impl Heap {
+    fn new() -> Self {
+        Heap {
+            last: -1,
+            end: 0,
+            freed: 0,
+            ior: 0,
+        }
+    }
+}
+
+const IOR_ALLOCATE: i32 = -59;
+const IOR_FREE: i32 = -60;
+const IOR_RESIZE: i32 = -61;
+
+fn clamp_idx(idx: i32) -> usize {
+    idx.clamp(0, MEM_CELLS - 1) as usize
+}
+
+impl<'m> Machine<'m> {
+    fn pop(&mut self, word: &str) -> Result<i32, String> {
+        self.data
+            .stack
+            .pop()
+            .ok_or_else(|| format!("data stack underflow in word {}", word))
+    }
+
+    fn push(&mut self, v: i32, word: &str) -> Result<(), String> {
+        if self.data.stack.len() >= STACK_CELLS {
+            return Err(format!("data stack overflow in word {}", word));
+        }
+        self.data.push(v);
+        Ok(())
+    }
+
+    fn pop_double(&mut self, word: &str) -> Result<i64, String> {
+        let hi = self.pop(word)?;
+        let lo = self.pop(word)?;
+        Ok(((hi as i64) << 32) | lo as u32 as i64)
+    }
+
+    fn load(&self, addr: i32) -> i32 {
+        self.mem.get(clamp_idx(addr / 4)).copied().unwrap_or(0)
+    }
+
+    fn store(&mut self, addr: i32, v: i32) {
+        let idx = clamp_idx(addr / 4);
+        if idx >= self.mem.len() {
+            self.mem.resize(idx + 1, 0);
+        }
+        self.mem[idx] = v;
+    }
+
+    // rt_mem_check (--check-memory).
+    fn mem_check(&self, c: &MemCheck, word: &str) -> Result<(), String> {
+        let stack = &self.data.stack;
+        let top = |k: usize| {
+            stack
+                .len()
+                .checked_sub(k)
+                .map(|at| stack[at])
+                .ok_or_else(|| format!("data stack underflow in word {}", word))
+        };
+        let addr = match c.addr {
+            MemAddr::Top(k) => top(1)?.wrapping_add(k),
+            MemAddr::Field => top(2)?.wrapping_add(top(1)?),
+        };
+        let why = if matches!(c.access, Access::Free | Access::Resize) {
+            match self.heap_block(addr) {
+                None => "invalid",
+                Some(h) if self.load(h) & 1 == 0 => return Ok(()),
+                Some(_) if c.access == Access::Free => "double",
+                Some(_) => "use-after-free",
+            }
+        } else if !(0..MEM_CELLS * 4).contains(&addr) {
+            "out-of-range"
+        } else if addr & 3 != 0 {
+            "misaligned"
+        } else if self.heap.freed > 0 && self.in_freed_block(addr) {
+            "use-after-free"
+        } else {
+            return Ok(());
+        };
+        Err(format!(
+            "{} {} at address {} in word {}",
+            why,
+            c.kind(),
+            addr,
+            word
+        ))
+    }
+
+    fn allot(&mut self, n: i32) {
+        let next = (self.here as i64 + n as i64).clamp(0, MEM_CELLS as i64 * 4);
+        self.here = next as i32;
+    }
+
+    fn heap_prev(&self, h: i32) -> i32 {
+        let prev = self.load(h + 4);
+        if prev >= 0 && prev < h && prev & 3 == 0 {
+            prev
+        } else {
+            -1
+        }
+    }
+
+    // Header of the heap block whose payload starts at `addr`, and the two
+    // headers chained before it (-1 past the newest).
+    fn heap_find(&self, addr: i32) -> Option<(i32, [i32; 2])> {
+        let (mut h, mut above) = (self.heap.last, [-1, -1]);
+        while h >= 0 && h as i64 + 8 >= addr as i64 {
+            if h + 8 == addr {
+                return Some((h, above));
+            }
+            above = [h, above[0]];
+            h = self.heap_prev(h);
+        }
+        None
+    }
+
+    fn heap_block(&self, addr: i32) -> Option<i32> {
+        self.heap_find(addr).map(|(h, _)| h)
+    }
+
+    fn heap_link(&mut self, newer: i32, h: i32) {
+        if newer >= 0 {
+            self.store(newer + 4, h);
+        } else {
+            self.heap.last = h;
+        }
+    }
+
+    // Only the block below `addr` can hold it; stop once every free block is passed.
+    fn in_freed_block(&self, addr: i32) -> bool {
+        let (mut h, mut left) = (self.heap.last, self.heap.freed);
+        while h >= 0 && left > 0 {
+            let info = self.load(h);
+            if h + 8 <= addr {
+                return info & 1 != 0 && addr - (h + 8) < info & !3;
+            }
+            if info & 1 != 0 {
+                left -= 1;
+            }
+            h = self.heap_prev(h);
+        }
+        false
+    }
+
+    // rt_allocate: first fit over freed blocks, else carve a new one at HERE.
+    fn allocate(&mut self, n: i32) -> i32 {
+        let size = (n as u32 as i64 + 3) & !3;
+        let (mut h, mut next) = (self.heap.last, -1);
+        while h >= 0 {
+            let info = self.load(h);
+            let mut have = info & !3;
+            if info & 1 != 0 && have as i64 >= size {
+                let size = size as i32;
+                if have - size >= 12 {
+                    let rest = h + 8 + size;
+                    self.store(rest, (have - size - 8) | 1);
+                    self.store(rest + 4, h);
+                    if next >= 0 {
+                        self.store(next + 4, rest);
+                    } else {
+                        self.heap.last = rest;
+                    }
+                    have = size;
+                    self.heap.freed += 1;
+                }
+                self.store(h, have);
+                self.heap.freed -= 1;
+                self.heap.ior = 0;
+                return h + 8;
+            }
+            next = h;
+            h = self.heap_prev(h);
+        }
+        let h = (self.here as i64 + 3) & !3;
+        if h < self.heap.end as i64 || h + 8 + size > MEM_CELLS as i64 * 4 {
+            self.heap.ior = IOR_ALLOCATE;
+            return 0;
+        }
+        let (h, size) = (h as i32, size as i32);
+        self.store(h, size);
+        self.store(h + 4, self.heap.last);
+        self.heap.last = h;
+        self.heap.end = h + 8 + size;
+        self.here = self.heap.end;
+        self.heap.ior = 0;
+        h + 8
+    }
+
+    // rt_free: merges with free blocks directly above and below.
+    fn free(&mut self, addr: i32) -> i32 {
+        let (h, mut above) = match self.heap_find(addr) {
+            Some((h, above)) if self.load(h) & 1 == 0 => (h, above),
+            _ => return IOR_FREE,
+        };
+        let mut size = self.load(h);
+        self.heap.freed += 1;
+        if above[0] >= 0 && self.load(above[0]) & 1 != 0 && h + 8 + size == above[0] {
+            size += 8 + (self.load(above[0]) & !3);
+            self.heap_link(above[1], h);
+            above[0] = above[1];
+            self.heap.freed -= 1;
+        }
+        let below = self.heap_prev(h);
+        if below >= 0 && self.load(below) & 1 != 0 && below + 8 + (self.load(below) & !3) == h {
+            self.store(below, self.load(below) + 8 + size);
+            self.heap_link(above[0], below);
+            self.heap.freed -= 1;
+            return 0;
+        }
+        self.store(h, size | 1);
+        0
+    }
+
+    fn resize(&mut self, addr: i32, n: i32) -> i32 {
+        let have = match self.heap_block(addr) {
+            Some(h) if self.load(h) & 1 == 0 => self.load(h),
+            _ => {
+                self.heap.ior = IOR_RESIZE;
+                return addr;
+            }
+        };
+        if n as u32 <= have as u32 {
+            self.heap.ior = 0;
+            return addr;
+        }
+        let to = self.allocate(n);
+        if self.heap.ior != 0 {
+            self.heap.ior = IOR_RESIZE;
+            return addr;
+        }
+        for i in 0..have / 4 {
+            let v = self.load(addr + 4 * i);
+            self.store(to + 4 * i, v);
+        }
+        self.free(addr);
+        to
+    }
+
+    // rt_heap_reset: HERE starts after the static data, then the data image.
+    fn heap_reset(&mut self) {
+        self.here = self.m.here.clamp(0, MEM_CELLS * 4);
+        self.heap = Heap::new();
+        for (&addr, &v) in &self.m.static_inits {
+            self.store(addr, v);
+        }
+    }
+
+    fn write(&mut self, s: &[u8]) -> Result<(), String> {
+        self.out
+            .write_all(s)
+            .map_err(|e| format!("write error: {}", e))
+    }
+
+    fn write_f32(&mut self, bits: i32) -> Result<(), String> {
+        let f = bits_to_f32(bits);
+        let s = if f.is_nan() {
+            "nan".to_string()
+        } else if f.is_infinite() {
+            if f < 0.0 { "-inf" } else { "inf" }.to_string()
+        } else {
+            format!("{:.4}", f as f64)
+        };
+        self.write(s.as_bytes())
+    }
+
+    fn service(&mut self, svc: Service, word: &str) -> Result<(), String> {
+        let name = svc.name;
+        if name.starts_with("PREAD") {
+            // Show any prompt before blocking on stdin.
+            self.out
+                .flush()
+                .map_err(|e| format!("write error: {}", e))?;
+        }
+        match svc.mode {
+            ExternArgMode::Void => match name {
+                "PWRITELN" => self.write(b"\n")?,
+                "PREADLN" => loop {
+                    match self.input.getc() {
+                        None | Some(b'\n') => break,
+                        Some(_) => {}
+                    }
+                },
+                _ => return Err(format!("unsupported service {}", name)),
+            },
+            ExternArgMode::PopI32Void => {
+                let v = self.pop(word)?;
+                match name {
+                    "PWRITE-I32" => self.write(v.to_string().as_bytes())?,
+                    "PWRITE-U32" => self.write((v as u32).to_string().as_bytes())?,
+                    "PWRITE-BOOL" => self.write(if v != 0 { b"TRUE" } else { b"FALSE" })?,
+                    "PWRITE-CHAR" => self.write(&[v as u8])?,
+                    "PWRITE-HEX" => self.write(format!("{:08X}", v as u32).as_bytes())?,
+                    "PWRITE-F32" => self.write_f32(v)?,
+                    "," => {
+                        self.store(self.here, v);
+                        self.allot(4);
+                    }
+                    "ALLOT" => self.allot(v),
+                    "PDISPOSE" => {
+                        if self.free(v) != 0 {
+                            return Err(format!("dispose of invalid pointer {}", v));
+                        }
+                    }
+                    _ => return Err(format!("unsupported service {}", name)),
+                }
+            }
+            ExternArgMode::RetI32Push => {
+                let v = match name {
+                    "HERE" => self.here,
+                    "__RT_IOR" => self.heap.ior,
+                    "PREAD-I32" | "PREAD-BOOL" => {
+                        let v = self.input.token().map_or(0, |t| parse_i32_token(&t));
+                        if name == "PREAD-BOOL" {
+                            (v != 0) as i32
+                        } else {
+                            v
+                        }
+                    }
+                    "PREAD-CHAR" => match self.input.token() {
+                        Some(t) if t.len() == 1 => t[0] as i32,
+                        Some(t) => parse_i32_token(&t),
+                        None => 0,
+                    },
+                    "PREAD-F32" => self
+                        .input
+                        .token()
+                        .and_then(|t| String::from_utf8(t).ok())
+                        .and_then(|t| t.parse::<f32>().ok())
+                        .map_or(0, f32_to_bits),
+                    _ => return Err(format!("unsupported service {}", name)),
+                };
+                self.push(v, word)?;
+            }
+            ExternArgMode::PopI32RetI32Push => {
+                let a = self.pop(word)?;
+                let v = match name {
+                    "PVAR@" => self.load(a),
+                    "ALLOCATE" => self.allocate(a),
+                    "FREE" => self.free(a),
+                    "PNEW" => {
+                        let p = self.allocate(a);
+                        if self.heap.ior != 0 {
+                            return Err(format!("out of memory in new ({} bytes)", a));
+                        }
+                        p
+                    }
+                    _ => unary_service(name, a)
+                        .ok_or_else(|| format!("unsupported service {}", name))?,
+                };
+                self.push(v, word)?;
+            }
+            ExternArgMode::Pop2I32Void => {
+                let addr = self.pop(word)?;
+                let v = self.pop(word)?;
+                match name {
+                    "PVAR!" => self.store(addr, v),
+                    _ => return Err(format!("unsupported service {}", name)),
+                }
+            }
+            ExternArgMode::Pop2I32RetI32Push => {
+                let b = self.pop(word)?;
+                let a = self.pop(word)?;
+                let v = match name {
+                    "PFIELD@" => self.load(a.wrapping_add(b)),
+                    "RESIZE" => self.resize(a, b),
+                    _ => binary_service(name, a, b)
+                        .ok_or_else(|| format!("unsupported service {}", name))?,
+                };
+                self.push(v, word)?;
+            }
+            ExternArgMode::Pop3I32Void => {
+                let off = self.pop(word)?;
+                let obj = self.pop(word)?;
+                let v = self.pop(word)?;
+                match name {
+                    "PFIELD!" => self.store(obj.wrapping_add(off), v),
+                    _ => return Err(format!("unsupported service {}", name)),
+                }
+            }
+            ExternArgMode::PopI64Void => {
+                let d = self.pop_double(word)?;
+                self.write(d.to_string().as_bytes())?;
+            }
+            ExternArgMode::PopI64I32Void => {
+                let width = self.pop(word)?;
+                let d = self.pop_double(word)?;
+                // printf("%*lld"): a negative width left-justifies.
+                let w = width.unsigned_abs() as usize;
+                let s = if width < 0 {
+                    format!("{:<w$}", d, w = w)
+                } else {
+                    format!("{:>w$}", d, w = w)
+                };
+                self.write(s.as_bytes())?;
+            }
+            ExternArgMode::StrVoid => return Err(format!("unsupported service {}", name)),
+        }
+        Ok(())
+    }
+
+    fn op(&mut self, op: Op, rstack: &mut Vec<i32>, word: &str) -> Result<(), String> {
+        let rpush = |rs: &mut Vec<i32>, v: i32| {
+            if rs.len() >= RSTACK_CELLS {
+                return Err(format!("return stack overflow in word {}", word));
+            }
+            rs.push(v);
+            Ok(())
+        };
+        let rpop = |rs: &mut Vec<i32>| {
+            rs.pop()
+                .ok_or_else(|| format!("return stack underflow in word {}", word))
+        };
+        match op {
+            Op::ToR => {
+                let v = self.pop(word)?;
+                rpush(rstack, v)?;
+            }
+            Op::RFrom => {
+                let v = rpop(rstack)?;
+                self.push(v, word)?;
+            }
+            Op::RFetch => {
+                let v = rpop(rstack)?;
+                rstack.push(v);
+                self.push(v, word)?;
+            }
+            Op::TwoToR => {
+                let b = self.pop(word)?;
+                let a = self.pop(word)?;
+                rpush(rstack, a)?;
+                rpush(rstack, b)?;
+            }
+            Op::TwoRFrom | Op::TwoRFetch => {
+                let b = rpop(rstack)?;
+                let a = rpop(rstack)?;
+                if op == Op::TwoRFetch {
+                    rstack.push(a);
+                    rstack.push(b);
+                }
+                self.push(a, word)?;
+                self.push(b, word)?;
+            }
+            Op::TwoFetch => {
+                let addr = self.pop(word)?;
+                let lo = self.load(addr.wrapping_add(4));
+                let hi = self.load(addr);
+                self.push(lo, word)?;
+                self.push(hi, word)?;
+            }
+            Op::TwoStore => {
+                let addr = self.pop(word)?;
+                let hi = self.pop(word)?;
+                let lo = self.pop(word)?;
+                self.store(addr, hi);
+                self.store(addr.wrapping_add(4), lo);
+            }
+            // The most common words skip exec_pure's lookup by name.
+            Op::Dup => {
+                let a = self.pop(word)?;
+                self.push(a, word)?;
+                self.push(a, word)?;
+            }
+            Op::Drop => {
+                self.pop(word)?;
+            }
+            Op::Swap | Op::Over | Op::Add | Op::Sub | Op::Eq | Op::Lt => {
+                let b = self.pop(word)?;
+                let a = self.pop(word)?;
+                let r = match op {
+                    Op::Swap => {
+                        self.push(b, word)?;
+                        a
+                    }
+                    Op::Over => {
+                        self.push(a, word)?;
+                        self.push(b, word)?;
+                        a
+                    }
+                    Op::Add => a.wrapping_add(b),
+                    Op::Sub => a.wrapping_sub(b),
+                    Op::Eq => forth_bool(a == b),
+                    _ => forth_bool(a < b),
+                };
+                self.push(r, word)?;
+            }
+            Op::OnePlus | Op::OneMinus | Op::ZeroEq => {
+                let a = self.pop(word)?;
+                let r = match op {
+                    Op::OnePlus => a.wrapping_add(1),
+                    Op::OneMinus => a.wrapping_sub(1),
+                    _ => forth_bool(a == 0),
+                };
+                self.push(r, word)?;
+            }
+            _ => {
+                let checked = matches!(
+                    op,
+                    Op::Div
+                        | Op::Mod
+                        | Op::DivMod
+                        | Op::StarSlash
+                        | Op::StarSlashMod
+                        | Op::FmMod
+                        | Op::SmRem
+                        | Op::UDiv
+                        | Op::UMod
+                        | Op::UmSlashMod
+                );
+                if checked && self.data.stack.last() == Some(&0) {
+                    return Err(format!("division by zero in word {}", word));
+                }
+                match self.data.exec_pure(op.word()) {
+                    Ok(true) => {}
+                    Ok(false) => return Err(format!("unsupported word {}", op.word())),
+                    Err(_) => return Err(format!("data stack underflow in word {}", word)),
+                }
+                if self.data.stack.len() > STACK_CELLS {
+                    return Err(format!("data stack overflow in word {}", word));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn func(&self, name: &str) -> Result<usize, String> {
+        self.index
+            .get(name)
+            .copied()
+            .ok_or_else(|| format!("call to unknown word {}", name))
+    }
+
+    fn exec(&mut self) -> Result<(), String> {
+        let mut frames = vec![Frame {
+            func: self.func(&self.m.entry)?,
+            block: 0,
+            pc: 0,
+            rstack: Vec::new(),
+        }];
+        while let Some(frame) = frames.last_mut() {
+            let f = &self.m.funcs[frame.func];
+            let block = &f.blocks[frame.block];
+            if let Some(inst) = block.insts.get(frame.pc) {
+                frame.pc += 1;
+                let word = f.name.as_str();
+                match inst {
+                    Inst::Lit(v) => self.push(*v, word)?,
+                    Inst::Op(op) => {
+                        let mut rstack = std::mem::take(&mut frame.rstack);
+                        let r = self.op(*op, &mut rstack, word);
+                        if let Some(frame) = frames.last_mut() {
+                            frame.rstack = rstack;
+                        }
+                        r?;
+                    }
+                    Inst::PickConst(n) => {
+                        self.push(*n, word)?;
+                        self.op(Op::Pick, &mut Vec::new(), word)?;
+                    }
+                    Inst::RollConst(n) => {
+                        self.push(*n, word)?;
+                        self.op(Op::Roll, &mut Vec::new(), word)?;
+                    }
+                    Inst::Call(w) => {
+                        let func = self.func(w)?;
+                        frames.push(Frame {
+                            func,
+                            block: 0,
+                            pc: 0,
+                            rstack: Vec::new(),
+                        });
+                    }
+                    Inst::Service(svc) => self.service(*svc, word)?,
+                    Inst::Type(s) => {
+                        let bytes = s.as_bytes();
+                        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+                        self.write(&bytes[..end])?;
+                    }
+                    Inst::Line(_) => {}
+                    Inst::MemCheck(c) => self.mem_check(c, word)?,
+                }
+                continue;
+            }
+            match &block.term {
+                Term::Return => {
+                    frames.pop();
+                }
+                Term::Jump(t) => {
+                    frame.block = *t;
+                    frame.pc = 0;
+                }
+                Term::Branch { nonzero, zero } => {
+                    let flag = self.pop(&f.name)?;
+                    let frame = frames.last_mut().unwrap();
+                    frame.block = if flag != 0 { *nonzero } else { *zero };
+                    frame.pc = 0;
+                }
+                Term::TailCall(w) => {
+                    let func = self.func(w)?;
+                    *frames.last_mut().unwrap() = Frame {
+                        func,
+                        block: 0,
+                        pc: 0,
+                        rstack: Vec::new(),
+                    };
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Runs the program and returns its exit status.
+pub fn run(m: &Module) -> i32 {
+    let mut vm = Machine {
+        m,
+        index: m
+            .funcs
+            .iter()
+            .enumerate()
+            .map(|(k, f)| (f.name.as_str(), k))
+            .collect(),
+        data: CtMachine::new(m.division),
+        mem: Vec::new(),
+        here: 0,
+        heap: Heap::new(),
+        input: Input {
+            bytes: io::stdin().lock().bytes(),
+            pushback: None,
+        },
+        out: BufWriter::new(io::stdout()),
+    };
+    vm.heap_reset();
+    let result = vm.exec();
+    let _ = vm.out.flush();
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("runtime error: {}", e);
+            1
+        }
+    }
+}
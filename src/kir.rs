@@ -0,0 +1,1056 @@
+// kIR: typed intermediate representation between tokens and backend text.
+//
+// Every definition becomes a `Func` made of basic blocks. A block holds
+// straight-line instructions over the data stack and ends in an explicit
+// terminator, so analyses and backends never have to re-parse tokens.
+// Symbol resolution (constants, VALUEs, DOES> children, `[ ]`, `LITERAL`)
+// happens here; backends only see literals, primitive ops, calls and
+// runtime service calls.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+
+use crate::{
+    parse_f32_token_bits, resolve_prev_compile_time_value, Body, CtMachine, DivisionMode,
+    ParsedProgram, Tok,
+};
+
+// Primitive stack/arithmetic words with a fixed stack effect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Dup,
+    Drop,
+    Swap,
+    Over,
+    Rot,
+    MinusRot,
+    Nip,
+    Tuck,
+    QDup,
+    TwoDup,
+    TwoDrop,
+    TwoSwap,
+    TwoOver,
+    Pick,
+    Roll,
+    Depth,
+    ToR,
+    RFrom,
+    RFetch,
+    TwoToR,
+    TwoRFrom,
+    TwoRFetch,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    DivMod,
+    StarSlash,
+    StarSlashMod,
+    FmMod,
+    SmRem,
+    Negate,
+    Abs,
+    Min,
+    Max,
+    OnePlus,
+    OneMinus,
+    TwoStar,
+    TwoSlash,
+    And,
+    Or,
+    Xor,
+    Invert,
+    Lshift,
+    Rshift,
+    Arshift,
+    Cells,
+    CellPlus,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    ULt,
+    UGt,
+    ZeroEq,
+    ZeroLt,
+    UDiv,
+    UMod,
+    UmStar,
+    UmSlashMod,
+    SToD,
+    DAdd,
+    DSub,
+    DNegate,
+    DAbs,
+    DEq,
+    DLt,
+    DZeroEq,
+    MStar,
+    MPlus,
+    DTwoStar,
+    DTwoSlash,
+    TwoFetch,
+    TwoStore,
+}
+
+const OP_WORDS: &[(&str, Op)] = &[
+    ("DUP", Op::Dup),
+    ("DROP", Op::Drop),
+    ("SWAP", Op::Swap),
+    ("OVER", Op::Over),
+    ("ROT", Op::Rot),
+    ("-ROT", Op::MinusRot),
+    ("NIP", Op::Nip),
+    ("TUCK", Op::Tuck),
+    ("?DUP", Op::QDup),
+    ("2DUP", Op::TwoDup),
+    ("2DROP", Op::TwoDrop),
+    ("2SWAP", Op::TwoSwap),
+    ("2OVER", Op::TwoOver),
+    ("PICK", Op::Pick),
+    ("ROLL", Op::Roll),
+    ("DEPTH", Op::Depth),
+    (">R", Op::ToR),
+    ("R>", Op::RFrom),
+    ("R@", Op::RFetch),
+    ("2>R", Op::TwoToR),
+    ("2R>", Op::TwoRFrom),
+    ("2R@", Op::TwoRFetch),
+    ("+", Op::Add),
+    ("-", Op::Sub),
+    ("*", Op::Mul),
+    ("/", Op::Div),
+    ("MOD", Op::Mod),
+    ("/MOD", Op::DivMod),
+    ("*/", Op::StarSlash),
+    ("*/MOD", Op::StarSlashMod),
+    ("FM/MOD", Op::FmMod),
+    ("SM/REM", Op::SmRem),
+    ("NEGATE", Op::Negate),
+    ("ABS", Op::Abs),
+    ("MIN", Op::Min),
+    ("MAX", Op::Max),
+    ("1+", Op::OnePlus),
+    ("1-", Op::OneMinus),
+    ("2*", Op::TwoStar),
+    ("2/", Op::TwoSlash),
+    ("AND", Op::And),
+    ("OR", Op::Or),
+    ("XOR", Op::Xor),
+    ("INVERT", Op::Invert),
+    ("LSHIFT", Op::Lshift),
+    ("RSHIFT", Op::Rshift),
+    ("ARSHIFT", Op::Arshift),
+    ("CELLS", Op::Cells),
+    ("CELL+", Op::CellPlus),
+    ("=", Op::Eq),
+    ("<>", Op::Ne),
+    ("<", Op::Lt),
+    ("<=", Op::Le),
+    (">", Op::Gt),
+    (">=", Op::Ge),
+    ("U<", Op::ULt),
+    ("U>", Op::UGt),
+    ("0=", Op::ZeroEq),
+    ("0<", Op::ZeroLt),
+    ("U/", Op::UDiv),
+    ("UMOD", Op::UMod),
+    ("UM*", Op::UmStar),
+    ("UM/MOD", Op::UmSlashMod),
+    ("S>D", Op::SToD),
+    ("D+", Op::DAdd),
+    ("D-", Op::DSub),
+    ("DNEGATE", Op::DNegate),
+    ("DABS", Op::DAbs),
+    ("D=", Op::DEq),
+    ("D<", Op::DLt),
+    ("D0=", Op::DZeroEq),
+    ("M*", Op::MStar),
+    ("M+", Op::MPlus),
+    ("D2*", Op::DTwoStar),
+    ("D2/", Op::DTwoSlash),
+    ("2@", Op::TwoFetch),
+    ("2!", Op::TwoStore),
+];
+
+impl Op {
+    pub fn from_word(w: &str) -> Option<Op> {
+        OP_WORDS.iter().find(|(n, _)| *n == w).map(|&(_, op)| op)
+    }
+
+    pub fn word(self) -> &'static str {
+        OP_WORDS
+            .iter()
+            .find(|(_, op)| *op == self)
+            .map(|&(n, _)| n)
+            .unwrap_or("?")
+    }
+}
+
+// C-level signature of a runtime service call.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExternArgMode {
+    PopI32Void,
+    Void,
+    RetI32Push,
+    PopI32RetI32Push,
+    StrVoid,
+    Pop2I32Void,
+    Pop2I32RetI32Push,
+    Pop3I32Void,
+    PopI64Void,
+    PopI64I32Void,
+}
+
+// A runtime service: `name` is the service key (see Codegen externs).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Service {
+    pub name: &'static str,
+    pub mode: ExternArgMode,
+}
+
+// Source word -> (service key, signature). Aliases map onto one key.
+const SERVICE_WORDS: &[(&str, &str, ExternArgMode)] = &[
+    ("PWRITE-I32", "PWRITE-I32", ExternArgMode::PopI32Void),
+    (".", "PWRITE-I32", ExternArgMode::PopI32Void),
+    ("D.", "D.", ExternArgMode::PopI64Void),
+    ("D.R", "D.R", ExternArgMode::PopI64I32Void),
+    ("PWRITE-U32", "PWRITE-U32", ExternArgMode::PopI32Void),
+    ("U.", "PWRITE-U32", ExternArgMode::PopI32Void),
+    ("PWRITE-BOOL", "PWRITE-BOOL", ExternArgMode::PopI32Void),
+    ("PWRITE-CHAR", "PWRITE-CHAR", ExternArgMode::PopI32Void),
+    ("EMIT", "PWRITE-CHAR", ExternArgMode::PopI32Void),
+    ("PWRITELN", "PWRITELN", ExternArgMode::Void),
+    ("PWRITE-HEX", "PWRITE-HEX", ExternArgMode::PopI32Void),
+    ("PREAD-I32", "PREAD-I32", ExternArgMode::RetI32Push),
+    ("PREAD-BOOL", "PREAD-BOOL", ExternArgMode::RetI32Push),
+    ("PREAD-CHAR", "PREAD-CHAR", ExternArgMode::RetI32Push),
+    ("PREADLN", "PREADLN", ExternArgMode::Void),
+    ("PBOOL", "PBOOL", ExternArgMode::PopI32RetI32Push),
+    ("PVAR!", "PVAR!", ExternArgMode::Pop2I32Void),
+    ("PVAR@", "PVAR@", ExternArgMode::PopI32RetI32Push),
+    ("PFIELD!", "PFIELD!", ExternArgMode::Pop3I32Void),
+    ("PFIELD@", "PFIELD@", ExternArgMode::Pop2I32RetI32Push),
+    // Float32-on-cell words from bootstrap treated as primitives.
+    ("PREAD-F32", "PREAD-F32", ExternArgMode::RetI32Push),
+    ("FADD", "FADD", ExternArgMode::Pop2I32RetI32Push),
+    ("FSUB", "FSUB", ExternArgMode::Pop2I32RetI32Push),
+    ("FMUL", "FMUL", ExternArgMode::Pop2I32RetI32Push),
+    ("FDIV", "FDIV", ExternArgMode::Pop2I32RetI32Push),
+    ("FNEGATE", "FNEGATE", ExternArgMode::PopI32RetI32Push),
+    ("FABS", "FABS", ExternArgMode::PopI32RetI32Push),
+    ("F=", "F=", ExternArgMode::Pop2I32RetI32Push),
+    ("F<", "F<", ExternArgMode::Pop2I32RetI32Push),
+    ("F<=", "F<=", ExternArgMode::Pop2I32RetI32Push),
+    ("FZERO?", "FZERO?", ExternArgMode::PopI32RetI32Push),
+    ("F0=", "FZERO?", ExternArgMode::PopI32RetI32Push),
+    ("FINF?", "FINF?", ExternArgMode::PopI32RetI32Push),
+    ("FNAN?", "FNAN?", ExternArgMode::PopI32RetI32Push),
+    ("FFINITE?", "FFINITE?", ExternArgMode::PopI32RetI32Push),
+    ("S>F", "S>F", ExternArgMode::PopI32RetI32Push),
+    ("F>S", "F>S", ExternArgMode::PopI32RetI32Push),
+    ("Q16.16>F", "Q16.16>F", ExternArgMode::PopI32RetI32Push),
+    ("F>Q16.16", "F>Q16.16", ExternArgMode::PopI32RetI32Push),
+    ("FROUND-I32", "FROUND-I32", ExternArgMode::PopI32RetI32Push),
+    ("F.", "PWRITE-F32", ExternArgMode::PopI32Void),
+    ("WRITE-F32", "PWRITE-F32", ExternArgMode::PopI32Void),
+    ("PWRITE-F32", "PWRITE-F32", ExternArgMode::PopI32Void),
+    ("HERE", "HERE", ExternArgMode::RetI32Push),
+    // Inside a body, comma stores at the runtime HERE and advances it.
+    (",", ",", ExternArgMode::PopI32Void),
+    ("ALLOT", "ALLOT", ExternArgMode::PopI32Void),
+    // ALLOCATE and RESIZE are followed by IOR_SERVICE.
+    ("ALLOCATE", "ALLOCATE", ExternArgMode::PopI32RetI32Push),
+    ("FREE", "FREE", ExternArgMode::PopI32RetI32Push),
+    ("RESIZE", "RESIZE", ExternArgMode::Pop2I32RetI32Push),
+    // Pascal new / dispose: the same heap, trapping instead of an ior.
+    ("PNEW", "PNEW", ExternArgMode::PopI32RetI32Push),
+    ("PDISPOSE", "PDISPOSE", ExternArgMode::PopI32Void),
+];
+
+// Pushes the ior of the last ALLOCATE or RESIZE.
+const IOR_SERVICE: Service = Service {
+    name: "__RT_IOR",
+    mode: ExternArgMode::RetI32Push,
+};
+
+// kPascal routines (by ROUTINE alias) compiled as a single native call.
+const NATIVE_ROUTINES: &[(&str, &str, ExternArgMode)] = &[
+    (
+        "program::abs",
+        "__KP_FABS_F32",
+        ExternArgMode::PopI32RetI32Push,
+    ),
+    (
+        "program::sqrt",
+        "__KP_FSQRT_F32",
+        ExternArgMode::PopI32RetI32Push,
+    ),
+    (
+        "program::sin",
+        "__KP_FSIN_F32",
+        ExternArgMode::PopI32RetI32Push,
+    ),
+    (
+        "program::cos",
+        "__KP_FCOS_F32",
+        ExternArgMode::PopI32RetI32Push,
+    ),
+    (
+        "program::pow",
+        "__KP_FPOW_F32_I32",
+        ExternArgMode::Pop2I32RetI32Push,
+    ),
+    (
+        "program::floor",
+        "__KP_FFLOOR_F32",
+        ExternArgMode::PopI32RetI32Push,
+    ),
+    (
+        "program::ceil",
+        "__KP_FCEIL_F32",
+        ExternArgMode::PopI32RetI32Push,
+    ),
+    (
+        "program::fx_sqrt",
+        "__KP_FX_SQRT",
+        ExternArgMode::PopI32RetI32Push,
+    ),
+    (
+        "program::fx_sin",
+        "__KP_FX_SIN",
+        ExternArgMode::PopI32RetI32Push,
+    ),
+    (
+        "program::fx_cos",
+        "__KP_FX_COS",
+        ExternArgMode::PopI32RetI32Push,
+    ),
+    (
+        "program::fx_tan",
+        "__KP_FX_TAN",
+        ExternArgMode::PopI32RetI32Push,
+    ),
+    (
+        "program::fx_asin",
+        "__KP_FX_ASIN",
+        ExternArgMode::PopI32RetI32Push,
+    ),
+    (
+        "program::fx_acos",
+        "__KP_FX_ACOS",
+        ExternArgMode::PopI32RetI32Push,
+    ),
+    (
+        "program::fx_atan",
+        "__KP_FX_ATAN",
+        ExternArgMode::PopI32RetI32Push,
+    ),
+    (
+        "program::fx_ln",
+        "__KP_FX_LN",
+        ExternArgMode::PopI32RetI32Push,
+    ),
+    (
+        "program::fx_log",
+        "__KP_FX_LOG",
+        ExternArgMode::PopI32RetI32Push,
+    ),
+];
+
+fn lookup_service(table: &[(&str, &'static str, ExternArgMode)], w: &str) -> Option<Service> {
+    table
+        .iter()
+        .find(|(k, _, _)| *k == w)
+        .map(|&(_, name, mode)| Service { name, mode })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inst {
+    Lit(i32),
+    Op(Op),
+    // PICK / ROLL with a literal index.
+    PickConst(i32),
+    RollConst(i32),
+    Call(String),
+    Service(Service),
+    // S" ..." TYPE
+    Type(String),
+    // Source line of the instructions that follow (only with -g).
+    Line(u32),
+    // Only with --check-memory: precedes each memory access.
+    MemCheck(MemCheck),
+}
+
+// The cell address an access is about to use, read without popping. It
+// traps unless the address is aligned and inside runtime memory and not in
+// a freed heap block. Before FREE / RESIZE the address is the pointer, which
+// must be a live heap block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemCheck {
+    pub access: Access,
+    pub addr: MemAddr,
+}
+
+// Discriminants are the kind codes passed to rt_mem_check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Fetch = 0,
+    Store = 1,
+    Free = 2,
+    Resize = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemAddr {
+    // Top of stack plus an offset (PVAR@, PVAR!, 2@, 2!, and HERE for `,`).
+    Top(i32),
+    // Second plus top of stack (PFIELD@, PFIELD!).
+    Field,
+}
+
+impl MemCheck {
+    pub fn kind(&self) -> &'static str {
+        match self.access {
+            Access::Fetch => "fetch",
+            Access::Store => "store",
+            Access::Free => "free",
+            Access::Resize => "resize",
+        }
+    }
+}
+
+// Checks to insert before `inst` in checked memory mode.
+fn mem_checks(inst: &Inst) -> Vec<MemCheck> {
+    use Access::{Fetch, Free, Store};
+    let at = |access, addr| MemCheck { access, addr };
+    match inst {
+        Inst::Service(s) => match s.name {
+            "PVAR@" => vec![at(Fetch, MemAddr::Top(0))],
+            "PVAR!" => vec![at(Store, MemAddr::Top(0))],
+            "PFIELD@" => vec![at(Fetch, MemAddr::Field)],
+            "PFIELD!" => vec![at(Store, MemAddr::Field)],
+            "FREE" | "PDISPOSE" => vec![at(Free, MemAddr::Top(0))],
+            _ => Vec::new(),
+        },
+        Inst::Op(Op::TwoFetch) => vec![at(Fetch, MemAddr::Top(0)), at(Fetch, MemAddr::Top(4))],
+        Inst::Op(Op::TwoStore) => vec![at(Store, MemAddr::Top(0)), at(Store, MemAddr::Top(4))],
+        _ => Vec::new(),
+    }
+}
+
+pub type BlockId = usize;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Term {
+    Return,
+    Jump(BlockId),
+    // Pops a flag: nonzero continues at `nonzero`, zero at `zero`.
+    Branch { nonzero: BlockId, zero: BlockId },
+    // Call as the last action: the callee returns on our behalf.
+    TailCall(String),
+}
+
+impl Term {
+    pub fn targets(&self) -> Vec<BlockId> {
+        match self {
+            Term::Jump(t) => vec![*t],
+            Term::Branch { nonzero, zero } => vec![*nonzero, *zero],
+            Term::Return | Term::TailCall(_) => Vec::new(),
+        }
+    }
+
+    pub fn map_targets(&self, f: impl Fn(BlockId) -> BlockId) -> Term {
+        match self {
+            Term::Jump(t) => Term::Jump(f(*t)),
+            Term::Branch { nonzero, zero } => Term::Branch {
+                nonzero: f(*nonzero),
+                zero: f(*zero),
+            },
+            t => t.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    // Label stem for dumps and backends ("then", "begin", ...).
+    pub hint: &'static str,
+    pub insts: Vec<Inst>,
+    pub term: Term,
+}
+
+// Blocks are in layout order; blocks[0] is the entry and is never a target.
+#[derive(Debug, Clone)]
+pub struct Func {
+    pub name: String,
+    pub blocks: Vec<Block>,
+    // Source line of the definition.
+    pub line: u32,
+}
+
+impl Func {
+    // Keeps only the blocks in `order` (entry first), in that order.
+    pub fn reorder(&mut self, order: &[BlockId]) {
+        let mut remap = vec![usize::MAX; self.blocks.len()];
+        for (new, &old) in order.iter().enumerate() {
+            remap[old] = new;
+        }
+        self.blocks = order
+            .iter()
+            .map(|&old| {
+                let b = &self.blocks[old];
+                Block {
+                    hint: b.hint,
+                    insts: b.insts.clone(),
+                    term: b.term.map_targets(|t| remap[t]),
+                }
+            })
+            .collect();
+    }
+
+    pub fn uses_return_stack(&self) -> bool {
+        self.blocks.iter().flat_map(|b| &b.insts).any(|i| {
+            matches!(
+                i,
+                Inst::Op(
+                    Op::ToR | Op::RFrom | Op::RFetch | Op::TwoToR | Op::TwoRFrom | Op::TwoRFetch
+                )
+            )
+        })
+    }
+
+    pub fn has_self_tail_call(&self) -> bool {
+        self.blocks
+            .iter()
+            .any(|b| matches!(&b.term, Term::TailCall(w) if *w == self.name))
+    }
+
+    pub fn label(&self, id: BlockId) -> String {
+        if id == 0 {
+            "entry".to_string()
+        } else {
+            format!("{}{}", self.blocks[id].hint, id)
+        }
+    }
+}
+
+pub struct Module {
+    pub funcs: Vec<Func>,
+    pub entry: String,
+    pub here: i32,
+    // byte address -> initial cell value
+    pub static_inits: BTreeMap<i32, i32>,
+    pub division: DivisionMode,
+}
+
+#[derive(Debug, Clone)]
+enum Control {
+    If {
+        else_blk: BlockId,
+        // Created by ELSE; without ELSE the else block is the join point.
+        end_blk: Option<BlockId>,
+    },
+    Begin {
+        begin_blk: BlockId,
+        // Created by WHILE.
+        exit_blk: Option<BlockId>,
+    },
+}
+
+struct Lowering<'p> {
+    p: &'p ParsedProgram,
+    // Body-level CONSTANT / CREATE extend these for later definitions.
+    constant_words: HashMap<String, i32>,
+    created_words: HashMap<String, i32>,
+    known_defs: HashSet<String>,
+    ct: CtMachine,
+    blocks: Vec<Block>,
+    cur: BlockId,
+    order: Vec<BlockId>,
+    ctrl: Vec<Control>,
+    // Emit `Line` markers: source line of the current token, and the last
+    // line marked in the current block.
+    debug: bool,
+    line: u32,
+    marked: Option<u32>,
+    check_memory: bool,
+}
+
+impl<'p> Lowering<'p> {
+    fn emit(&mut self, inst: Inst) {
+        if self.debug && self.marked != Some(self.line) {
+            self.marked = Some(self.line);
+            self.blocks[self.cur].insts.push(Inst::Line(self.line));
+        }
+        if self.check_memory {
+            let insts = &mut self.blocks[self.cur].insts;
+            if matches!(&inst, Inst::Service(s) if s.name == ",") {
+                // `,` stores at HERE.
+                insts.push(Inst::Service(
+                    lookup_service(SERVICE_WORDS, "HERE").unwrap(),
+                ));
+                insts.push(Inst::MemCheck(MemCheck {
+                    access: Access::Store,
+                    addr: MemAddr::Top(0),
+                }));
+                insts.push(Inst::Op(Op::Drop));
+            } else if matches!(&inst, Inst::Service(s) if s.name == "RESIZE") {
+                // RESIZE ( a-addr u ): the pointer is second.
+                insts.push(Inst::Op(Op::Over));
+                insts.push(Inst::MemCheck(MemCheck {
+                    access: Access::Resize,
+                    addr: MemAddr::Top(0),
+                }));
+                insts.push(Inst::Op(Op::Drop));
+            }
+            insts.extend(mem_checks(&inst).into_iter().map(Inst::MemCheck));
+        }
+        self.blocks[self.cur].insts.push(inst);
+    }
+
+    fn new_block(&mut self, hint: &'static str) -> BlockId {
+        self.blocks.push(Block {
+            hint,
+            insts: Vec::new(),
+            term: Term::Return,
+        });
+        self.blocks.len() - 1
+    }
+
+    // Ends the current block with `term` and continues in `next`.
+    fn switch_to(&mut self, term: Term, next: BlockId) {
+        self.blocks[self.cur].term = term;
+        self.cur = next;
+        self.marked = None;
+        self.order.push(next);
+    }
+
+    fn begin_if(&mut self) {
+        let then_blk = self.new_block("then");
+        let else_blk = self.new_block("else");
+        self.switch_to(
+            Term::Branch {
+                nonzero: then_blk,
+                zero: else_blk,
+            },
+            then_blk,
+        );
+        self.ctrl.push(Control::If {
+            else_blk,
+            end_blk: None,
+        });
+    }
+
+    fn do_else(&mut self) -> Result<(), String> {
+        let end = self.new_block("endif");
+        match self.ctrl.last_mut() {
+            Some(Control::If { else_blk, end_blk }) if end_blk.is_none() => {
+                *end_blk = Some(end);
+                let else_blk = *else_blk;
+                self.switch_to(Term::Jump(end), else_blk);
+                Ok(())
+            }
+            _ => Err("ELSE without IF".into()),
+        }
+    }
+
+    fn end_then(&mut self) -> Result<(), String> {
+        match self.ctrl.pop() {
+            Some(Control::If { else_blk, end_blk }) => {
+                let join = end_blk.unwrap_or(else_blk);
+                self.switch_to(Term::Jump(join), join);
+                Ok(())
+            }
+            _ => Err("THEN without IF".into()),
+        }
+    }
+
+    fn begin_begin(&mut self) {
+        let begin_blk = self.new_block("begin");
+        self.switch_to(Term::Jump(begin_blk), begin_blk);
+        self.ctrl.push(Control::Begin {
+            begin_blk,
+            exit_blk: None,
+        });
+    }
+
+    fn begin_while(&mut self) -> Result<(), String> {
+        // WHILE must be inside BEGIN ... REPEAT
+        if !matches!(
+            self.ctrl.last(),
+            Some(Control::Begin { exit_blk: None, .. })
+        ) {
+            return Err("WHILE without matching BEGIN".into());
+        }
+        let body = self.new_block("while");
+        let exit = self.new_block("wend");
+        if let Some(Control::Begin { exit_blk, .. }) = self.ctrl.last_mut() {
+            *exit_blk = Some(exit);
+        }
+        self.switch_to(
+            Term::Branch {
+                nonzero: body,
+                zero: exit,
+            },
+            body,
+        );
+        Ok(())
+    }
+
+    fn end_repeat(&mut self) -> Result<(), String> {
+        match self.ctrl.pop() {
+            Some(Control::Begin {
+                begin_blk,
+                exit_blk,
+            }) => {
+                // plain BEGIN ... REPEAT is an infinite loop; code after it is unreachable
+                let next = exit_blk.unwrap_or_else(|| self.new_block("after"));
+                self.switch_to(Term::Jump(begin_blk), next);
+                Ok(())
+            }
+            _ => Err("REPEAT without BEGIN".into()),
+        }
+    }
+
+    fn end_until(&mut self) -> Result<(), String> {
+        match self.ctrl.pop() {
+            Some(Control::Begin {
+                begin_blk,
+                exit_blk,
+            }) => {
+                // UNTIL: loop until cond is nonzero.
+                let done = exit_blk.unwrap_or_else(|| self.new_block("until"));
+                self.switch_to(
+                    Term::Branch {
+                        nonzero: done,
+                        zero: begin_blk,
+                    },
+                    done,
+                );
+                Ok(())
+            }
+            _ => Err("UNTIL without BEGIN".into()),
+        }
+    }
+
+    // Interprets `[ ... ]` starting after the `[` at `i`; returns the `]` index.
+    fn interpret_bracket(&mut self, toks: &[Tok], mut i: usize) -> Result<usize, String> {
+        i += 1;
+        loop {
+            match toks.get(i) {
+                None => return Err("'[' without matching ']'".into()),
+                Some(Tok::Word(x)) if x == "]" => return Ok(i),
+                Some(Tok::Num(v)) => self.ct.push(*v),
+                Some(Tok::Word(x)) => {
+                    if !self.ct.exec_pure(x)? {
+                        let v = if x == "HERE" {
+                            Some(self.p.here)
+                        } else {
+                            self.constant_words
+                                .get(x)
+                                .or_else(|| self.created_words.get(x))
+                                .copied()
+                        };
+                        match v {
+                            Some(v) => self.ct.push(v),
+                            None => return Err(format!("Word {} is not supported inside [ ]", x)),
+                        }
+                    }
+                }
+                Some(_) => return Err("Only words and numbers are allowed inside [ ]".into()),
+            }
+            i += 1;
+        }
+    }
+
+    fn lower_body(&mut self, body: &Body) -> Result<(), String> {
+        let toks = &body.toks;
+        let pvar_get = lookup_service(SERVICE_WORDS, "PVAR@").unwrap();
+        let pvar_set = lookup_service(SERVICE_WORDS, "PVAR!").unwrap();
+        let mut i = 0usize;
+        while i < toks.len() {
+            self.line = body.lines[i];
+            match &toks[i] {
+                Tok::Num(v) => match toks.get(i + 1) {
+                    // Constant-index PICK/ROLL address the slot directly.
+                    Some(Tok::Word(w)) if w == "PICK" && *v >= 0 => {
+                        self.emit(Inst::PickConst(*v));
+                        i += 1;
+                    }
+                    Some(Tok::Word(w)) if w == "ROLL" && *v >= 0 => {
+                        self.emit(Inst::RollConst(*v));
+                        i += 1;
+                    }
+                    _ => self.emit(Inst::Lit(*v)),
+                },
+                Tok::Str(s) => {
+                    // Compile-time handling for a few bootstrap-style immediate string consumers.
+                    match toks.get(i + 1) {
+                        Some(Tok::Word(w)) if w == "TYPE" => self.emit(Inst::Type(s.clone())),
+                        Some(Tok::Word(w)) if w == "READ-F32" || w == "FNUMBER?" => {
+                            if let Some(bits) = parse_f32_token_bits(s) {
+                                self.emit(Inst::Lit(bits));
+                                self.emit(Inst::Lit(-1));
+                            } else {
+                                self.emit(Inst::Lit(0));
+                            }
+                        }
+                        None => return Err("S\" must be followed by a word (e.g., TYPE)".into()),
+                        _ => return Err(
+                            "S\" currently only supported as: S\" ...\" TYPE / READ-F32 / FNUMBER?"
+                                .into(),
+                        ),
+                    }
+                    i += 1; // consume following word
+                }
+                Tok::Word(w) => {
+                    if let Some(&v) = self.constant_words.get(w) {
+                        self.emit(Inst::Lit(v));
+                    } else if let Some(&addr) = self.created_words.get(w) {
+                        self.emit(Inst::Lit(addr));
+                    } else if let Some(&(lo, hi)) = self.p.double_constant_words.get(w) {
+                        self.emit(Inst::Lit(lo));
+                        self.emit(Inst::Lit(hi));
+                    } else if let Some((addr, does_def)) = self.p.does_words.get(w) {
+                        // Child of a CREATE ... DOES> word: data address, then DOES> body.
+                        self.emit(Inst::Lit(*addr));
+                        self.emit(Inst::Call(does_def.clone()));
+                    } else if self.p.defining_words.contains_key(w) {
+                        return Err(format!("Defining word {} can only be used at top-level", w));
+                    } else if let Some(&addr) = self.p.value_words.get(w) {
+                        self.emit(Inst::Lit(addr));
+                        self.emit(Inst::Service(pvar_get));
+                    } else if let Some(op) = Op::from_word(w) {
+                        self.emit(Inst::Op(op));
+                    } else if let Some(svc) = lookup_service(SERVICE_WORDS, w) {
+                        self.emit(Inst::Service(svc));
+                        if matches!(svc.name, "ALLOCATE" | "RESIZE") {
+                            self.emit(Inst::Service(IOR_SERVICE));
+                        }
+                    } else {
+                        match w.as_str() {
+                            "IF" => self.begin_if(),
+                            "ELSE" => self.do_else()?,
+                            "THEN" => self.end_then()?,
+                            "BEGIN" => self.begin_begin(),
+                            "UNTIL" => self.end_until()?,
+                            "WHILE" => self.begin_while()?,
+                            "REPEAT" => self.end_repeat()?,
+
+                            "F+INF" => self.emit(Inst::Lit(f32::INFINITY.to_bits() as i32)),
+                            "F-INF" => self.emit(Inst::Lit(f32::NEG_INFINITY.to_bits() as i32)),
+                            "FNAN" => self.emit(Inst::Lit(f32::NAN.to_bits() as i32)),
+
+                            // Minimal compile-time dictionary words used by generated IL.
+                            "CONSTANT" => {
+                                let after_bracket = matches!(toks.get(i.wrapping_sub(1)), Some(Tok::Word(p)) if p == "]");
+                                let val = if after_bracket {
+                                    // `[ ... ] CONSTANT X`: value is on the compile-time stack.
+                                    self.ct.pop("CONSTANT")?
+                                } else {
+                                    let val = resolve_prev_compile_time_value(
+                                        toks,
+                                        i,
+                                        self.p.here,
+                                        &self.constant_words,
+                                        &self.created_words,
+                                        self.p.division,
+                                    )
+                                    .ok_or_else(|| {
+                                        "CONSTANT currently requires a compile-time value before it"
+                                            .to_string()
+                                    })?;
+                                    self.emit(Inst::Op(Op::Drop));
+                                    val
+                                };
+                                let name = match toks.get(i + 1) {
+                                    Some(Tok::Word(name)) => name.clone(),
+                                    _ => return Err("CONSTANT requires a following name".into()),
+                                };
+                                self.constant_words.insert(name, val);
+                                i += 1; // consume name
+                            }
+                            "CREATE" => {
+                                let name = match toks.get(i + 1) {
+                                    Some(Tok::Word(name)) => name.clone(),
+                                    _ => return Err("CREATE requires a following name".into()),
+                                };
+                                self.created_words.insert(name, self.p.here);
+                                i += 1; // consume name
+                            }
+                            "[" => i = self.interpret_bracket(toks, i)?,
+                            "]" => return Err("']' without matching '['".into()),
+                            "LITERAL" => {
+                                let v = self.ct.pop("LITERAL")?;
+                                self.emit(Inst::Lit(v));
+                            }
+                            "TO" | "+TO" => {
+                                let addr = match toks.get(i + 1) {
+                                    Some(Tok::Word(name)) => {
+                                        self.p.value_words.get(name).copied().ok_or_else(|| {
+                                            format!("{} target is not a VALUE: {}", w, name)
+                                        })?
+                                    }
+                                    _ => {
+                                        return Err(format!(
+                                            "{} requires a following VALUE name",
+                                            w
+                                        ))
+                                    }
+                                };
+                                if w == "+TO" {
+                                    self.emit(Inst::Lit(addr));
+                                    self.emit(Inst::Service(pvar_get));
+                                    self.emit(Inst::Op(Op::Add));
+                                }
+                                self.emit(Inst::Lit(addr));
+                                self.emit(Inst::Service(pvar_set));
+                                i += 1; // consume name
+                            }
+
+                            _ if self.known_defs.contains(w) => self.emit(Inst::Call(w.clone())),
+                            _ => return Err(format!("Unknown word: {}", w)),
+                        }
+                    }
+                }
+                Tok::InlineHint => {}
+                Tok::Colon | Tok::Semi => {
+                    return Err(
+                        "Unexpected ':' or ';' inside body (top-level parser should split defs)"
+                            .into(),
+                    )
+                }
+            }
+            i += 1;
+        }
+
+        if !self.ctrl.is_empty() {
+            return Err(format!("Unclosed control structure(s): {:?}", self.ctrl));
+        }
+        Ok(())
+    }
+
+    fn lower_def(&mut self, name: &str, body: &Body, alias: Option<&str>) -> Result<Func, String> {
+        self.blocks.clear();
+        self.ctrl.clear();
+        self.line = body.line;
+        self.marked = None;
+        self.order = vec![0];
+        self.cur = self.new_block("entry");
+        match alias.and_then(|a| lookup_service(NATIVE_ROUTINES, a)) {
+            Some(svc) => self.emit(Inst::Service(svc)),
+            None => self.lower_body(body)?,
+        }
+        // Renumber blocks into the order they were opened.
+        let mut f = Func {
+            name: name.to_string(),
+            blocks: std::mem::take(&mut self.blocks),
+            line: body.line,
+        };
+        f.reorder(&self.order);
+        Ok(f)
+    }
+}
+
+pub fn lower_program(
+    p: &ParsedProgram,
+    defs: &[(String, Body)],
+    routine_aliases: &HashMap<String, String>,
+    debug: bool,
+    check_memory: bool,
+) -> Result<Module, String> {
+    let mut lw = Lowering {
+        p,
+        constant_words: p.constant_words.clone(),
+        created_words: p.created_words.clone(),
+        known_defs: defs.iter().map(|(n, _)| n.clone()).collect(),
+        ct: CtMachine::new(p.division),
+        blocks: Vec::new(),
+        cur: 0,
+        order: Vec::new(),
+        ctrl: Vec::new(),
+        debug,
+        line: 0,
+        marked: None,
+        check_memory,
+    };
+    let mut funcs = Vec::with_capacity(defs.len());
+    for (name, body) in defs {
+        let alias = routine_aliases.get(name).map(|s| s.as_str());
+        funcs.push(lw.lower_def(name, body, alias)?);
+    }
+
+    // If there is a word named MAIN, create @main wrapper calling it.
+    // Otherwise, if exactly one def exists, call it.
+    let entry = if let Some(entry) = &p.entry_call {
+        entry.clone()
+    } else if defs.iter().any(|(n, _)| n == "MAIN") {
+        "MAIN".to_string()
+    } else if defs.len() == 1 {
+        defs[0].0.clone()
+    } else {
+        return Err(
+            "No entry point. Define : MAIN ... ; or provide exactly one definition.".into(),
+        );
+    };
+
+    Ok(Module {
+        funcs,
+        entry,
+        here: p.here,
+        static_inits: p.static_inits.clone(),
+        division: p.division,
+    })
+}
+
+// Text form used by `--emit=kir`.
+pub fn dump(m: &Module) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "entry {}", m.entry);
+    let _ = writeln!(out, "here {}", m.here);
+    let division = match m.division {
+        DivisionMode::Symmetric => "symmetric",
+        DivisionMode::Floored => "floored",
+    };
+    let _ = writeln!(out, "division {}", division);
+    for (addr, v) in &m.static_inits {
+        let _ = writeln!(out, "data {} {}", addr, v);
+    }
+    for f in &m.funcs {
+        let _ = writeln!(out, "\n: {}", f.name);
+        for (id, b) in f.blocks.iter().enumerate() {
+            let _ = writeln!(out, "{}:", f.label(id));
+            for inst in &b.insts {
+                let _ = match inst {
+                    Inst::Lit(v) => writeln!(out, "  lit {}", v),
+                    Inst::Op(op) => writeln!(out, "  op {}", op.word()),
+                    Inst::PickConst(n) => writeln!(out, "  pick {}", n),
+                    Inst::RollConst(n) => writeln!(out, "  roll {}", n),
+                    Inst::Call(w) => writeln!(out, "  call {}", w),
+                    Inst::Service(s) => writeln!(out, "  service {} {:?}", s.name, s.mode),
+                    Inst::Type(s) => writeln!(out, "  type {:?}", s),
+                    Inst::Line(n) => writeln!(out, "  line {}", n),
+                    Inst::MemCheck(c) => match c.addr {
+                        MemAddr::Top(k) => writeln!(out, "  memcheck {} top+{}", c.kind(), k),
+                        MemAddr::Field => writeln!(out, "  memcheck {} field", c.kind()),
+                    },
+                };
+            }
+            let _ = match &b.term {
+                Term::Return => writeln!(out, "  return"),
+                Term::TailCall(w) => writeln!(out, "  tailcall {}", w),
+                Term::Jump(t) => writeln!(out, "  jump {}", f.label(*t)),
+                Term::Branch { nonzero, zero } => {
+                    writeln!(out, "  branch {} {}", f.label(*nonzero), f.label(*zero))
+                }
+            };
+        }
+        let _ = writeln!(out, ";");
+    }
+    out
+}
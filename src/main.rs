@@ -1,7 +1,20 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::fs;
 
+mod asmbackend;
+mod bytecode;
+mod cbackend;
+mod debuginfo;
+mod interp;
+mod kir;
+mod passes;
+mod target;
+mod testrunner;
+mod watbackend;
+
+use kir::{ExternArgMode, Inst, MemAddr, MemCheck, Op, Term};
+
 #[derive(Debug, Clone)]
 enum Tok {
     Word(String),
@@ -9,16 +22,22 @@ enum Tok {
     Str(String), // S" ... "
     Colon,
     Semi,
+    // `( INLINE )` comment inside a colon definition.
+    InlineHint,
 }
 
 fn is_space(c: char) -> bool {
     c.is_whitespace()
 }
 
-fn tokenize(src: &str) -> Result<Vec<Tok>, String> {
+// Returns the tokens and the 1-based source line of each.
+fn tokenize(src: &str) -> Result<(Vec<Tok>, Vec<u32>), String> {
     let mut t = Vec::new();
+    let mut lines = Vec::new();
     let chars: Vec<char> = src.chars().collect();
     let mut i = 0usize;
+    let mut line = 1u32;
+    let mut scanned = 0usize;
 
     while i < chars.len() {
         let c = chars[i];
@@ -28,8 +47,16 @@ fn tokenize(src: &str) -> Result<Vec<Tok>, String> {
             continue;
         }
 
+        while scanned < i {
+            if chars[scanned] == '\n' {
+                line += 1;
+            }
+            scanned += 1;
+        }
+
         // comment: ( ... )
         if c == '(' {
+            let start = i + 1;
             i += 1;
             while i < chars.len() && chars[i] != ')' {
                 i += 1;
@@ -37,6 +64,10 @@ fn tokenize(src: &str) -> Result<Vec<Tok>, String> {
             if i >= chars.len() {
                 return Err("Unterminated comment '('".into());
             }
+            if chars[start..i].iter().collect::<String>().trim() == "INLINE" {
+                t.push(Tok::InlineHint);
+                lines.push(line);
+            }
             i += 1; // skip ')'
             continue;
         }
@@ -44,11 +75,13 @@ fn tokenize(src: &str) -> Result<Vec<Tok>, String> {
         // colon / semicolon
         if c == ':' {
             t.push(Tok::Colon);
+            lines.push(line);
             i += 1;
             continue;
         }
         if c == ';' {
             t.push(Tok::Semi);
+            lines.push(line);
             i += 1;
             continue;
         }
@@ -69,6 +102,7 @@ fn tokenize(src: &str) -> Result<Vec<Tok>, String> {
             }
             i += 1; // skip closing "
             t.push(Tok::Str(s));
+            lines.push(line);
             continue;
         }
 
@@ -89,9 +123,10 @@ fn tokenize(src: &str) -> Result<Vec<Tok>, String> {
         } else {
             t.push(Tok::Word(buf));
         }
+        lines.push(line);
     }
 
-    Ok(t)
+    Ok((t, lines))
 }
 
 fn parse_f32_token_bits(s: &str) -> Option<i32> {
@@ -140,21 +175,385 @@ fn extract_routine_aliases(src: &str) -> HashMap<String, String> {
     m
 }
 
+// Semantics of the standalone signed division words (`/`, `MOD`, `/MOD`,
+// `*/`, `*/MOD`). kPascal IL always uses Symmetric (Pascal div/mod).
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DivisionMode {
+    Symmetric,
+    Floored,
+}
+
+fn parse_division_mode(s: &str) -> Option<DivisionMode> {
+    match s.to_ascii_lowercase().as_str() {
+        "symmetric" => Some(DivisionMode::Symmetric),
+        "floored" => Some(DivisionMode::Floored),
+        _ => None,
+    }
+}
+
+// `( DIVISION FLOORED )` / `( DIVISION SYMMETRIC )` on a line of its own.
+fn extract_division_pragma(src: &str) -> Result<Option<DivisionMode>, String> {
+    let mut mode = None;
+    for line in src.lines() {
+        let line = line.trim();
+        if !line.starts_with("( DIVISION ") || !line.ends_with(')') {
+            continue;
+        }
+        let arg = line[11..line.len() - 1].trim();
+        mode = Some(
+            parse_division_mode(arg)
+                .ok_or_else(|| format!("Unknown DIVISION pragma value: {}", arg))?,
+        );
+    }
+    Ok(mode)
+}
+
+// Value on top after the pure expression ending just before `i` (e.g. the
+// `4 4 *` in `4 4 * CONSTANT X`), evaluated with CtMachine. Starts are tried
+// from the earliest; one that underflows into runtime values is skipped.
 fn resolve_prev_compile_time_value(
     toks: &[Tok],
     i: usize,
     here: i32,
     constant_words: &HashMap<String, i32>,
     created_words: &HashMap<String, i32>,
+    division: DivisionMode,
 ) -> Option<i32> {
-    match toks.get(i.wrapping_sub(1))? {
-        Tok::Num(v) => Some(*v),
-        Tok::Word(w) if w == "HERE" => Some(here),
-        Tok::Word(w) => constant_words
-            .get(w)
-            .copied()
-            .or_else(|| created_words.get(w).copied()),
-        _ => None,
+    let mut start = i;
+    while start > 0 {
+        // DEPTH sees runtime cells; a name after CONSTANT etc. is not executed.
+        let named = start >= 2 && matches!(&toks[start - 2], Tok::Word(p) if takes_name(p));
+        match &toks[start - 1] {
+            Tok::Num(_) if !named => start -= 1,
+            Tok::Word(w) if !named && w != "DEPTH" => start -= 1,
+            _ => break,
+        }
+    }
+    let mut s = start;
+    while s < i {
+        let mut ct = CtMachine::new(division);
+        let mut failed = None;
+        for (k, tok) in toks.iter().enumerate().take(i).skip(s) {
+            match tok {
+                Tok::Num(v) => ct.push(*v),
+                Tok::Word(w) => match ct.exec_pure(w) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        let v = if w == "HERE" {
+                            Some(here)
+                        } else {
+                            constant_words
+                                .get(w)
+                                .or_else(|| created_words.get(w))
+                                .copied()
+                        };
+                        match v {
+                            Some(v) => ct.push(v),
+                            // Not compile-time: no start at or before `k` works.
+                            None => {
+                                failed = Some(k + 1);
+                                break;
+                            }
+                        }
+                    }
+                    Err(_) => {
+                        failed = Some(s + 1);
+                        break;
+                    }
+                },
+                _ => unreachable!(),
+            }
+        }
+        match failed {
+            Some(next) => s = next,
+            None => return ct.stack.last().copied(),
+        }
+    }
+    None
+}
+
+// Compile-time stack machine for pure words: used at top-level, by defining
+// words before DOES>, and between `[` and `]` inside bodies.
+struct CtMachine {
+    stack: Vec<i32>,
+    division: DivisionMode,
+}
+
+impl CtMachine {
+    fn new(division: DivisionMode) -> Self {
+        Self {
+            stack: Vec::new(),
+            division,
+        }
+    }
+
+    fn push(&mut self, v: i32) {
+        self.stack.push(v);
+    }
+
+    fn pop(&mut self, word: &str) -> Result<i32, String> {
+        self.stack
+            .pop()
+            .ok_or_else(|| format!("{} requires a compile-time value before it", word))
+    }
+
+    fn pop2(&mut self, word: &str) -> Result<(i32, i32), String> {
+        let b = self.pop(word)?;
+        let a = self.pop(word)?;
+        Ok((a, b))
+    }
+
+    fn pop_double(&mut self, word: &str) -> Result<i64, String> {
+        let (lo, hi) = self.pop2(word)?;
+        Ok(((hi as i64) << 32) | lo as u32 as i64)
+    }
+
+    fn push_double(&mut self, d: i64) {
+        self.push(d as i32);
+        self.push((d >> 32) as i32);
+    }
+
+    // (remainder, quotient) of a 64-bit signed division; wraps on MIN / -1.
+    fn divrem(d: i64, n: i64, floored: bool) -> (i64, i64) {
+        let (mut q, mut r) = (d.wrapping_div(n), d.wrapping_rem(n));
+        if floored && r != 0 && (r ^ n) < 0 {
+            q -= 1;
+            r += n;
+        }
+        (r, q)
+    }
+
+    fn nonzero_divisor(b: i32, word: &str) -> Result<i32, String> {
+        if b == 0 {
+            return Err(format!("Division by zero in compile-time {}", word));
+        }
+        Ok(b)
+    }
+
+    // Executes `w` if it is a pure word; returns Ok(false) if it is not one.
+    fn exec_pure(&mut self, w: &str) -> Result<bool, String> {
+        let flag = |c: bool| if c { -1 } else { 0 };
+        match w {
+            "DUP" => {
+                let a = self.pop(w)?;
+                self.push(a);
+                self.push(a);
+            }
+            "DROP" => {
+                let _ = self.pop(w)?;
+            }
+            "SWAP" => {
+                let (a, b) = self.pop2(w)?;
+                self.push(b);
+                self.push(a);
+            }
+            "OVER" => {
+                let (a, b) = self.pop2(w)?;
+                self.push(a);
+                self.push(b);
+                self.push(a);
+            }
+            "ROT" | "-ROT" | "NIP" | "TUCK" | "2DUP" | "2DROP" | "2SWAP" | "2OVER" => {
+                let (depth, order): (usize, &[usize]) = match w {
+                    "ROT" => (3, &[1, 2, 0]),
+                    "-ROT" => (3, &[2, 0, 1]),
+                    "NIP" => (2, &[1]),
+                    "TUCK" => (2, &[1, 0, 1]),
+                    "2DUP" => (2, &[0, 1, 0, 1]),
+                    "2DROP" => (2, &[]),
+                    "2SWAP" => (4, &[2, 3, 0, 1]),
+                    _ => (4, &[0, 1, 2, 3, 0, 1]),
+                };
+                if self.stack.len() < depth {
+                    return Err(format!("{} requires a compile-time value before it", w));
+                }
+                let cells = self.stack.split_off(self.stack.len() - depth);
+                self.stack.extend(order.iter().map(|&k| cells[k]));
+            }
+            "?DUP" => {
+                let a = self.pop(w)?;
+                self.push(a);
+                if a != 0 {
+                    self.push(a);
+                }
+            }
+            "PICK" | "ROLL" => {
+                let n = self.pop(w)?;
+                if n < 0 || n as usize >= self.stack.len() {
+                    return Err(format!("{} index out of range at compile time: {}", w, n));
+                }
+                let idx = self.stack.len() - 1 - n as usize;
+                if w == "PICK" {
+                    self.push(self.stack[idx]);
+                } else {
+                    let v = self.stack.remove(idx);
+                    self.push(v);
+                }
+            }
+            "DEPTH" => self.push(self.stack.len() as i32),
+            "+" | "-" | "*" | "AND" | "OR" | "XOR" | "LSHIFT" | "RSHIFT" => {
+                let (a, b) = self.pop2(w)?;
+                self.push(match w {
+                    "+" => a.wrapping_add(b),
+                    "-" => a.wrapping_sub(b),
+                    "*" => a.wrapping_mul(b),
+                    "AND" => a & b,
+                    "OR" => a | b,
+                    "XOR" => a ^ b,
+                    "LSHIFT" => a.wrapping_shl(b as u32 & 31),
+                    _ => ((a as u32) >> (b as u32 & 31)) as i32,
+                });
+            }
+            "/" | "MOD" | "/MOD" => {
+                let (a, b) = self.pop2(w)?;
+                let b = Self::nonzero_divisor(b, w)?;
+                let floored = self.division == DivisionMode::Floored;
+                let (r, q) = Self::divrem(a as i64, b as i64, floored);
+                if w != "/" {
+                    self.push(r as i32);
+                }
+                if w != "MOD" {
+                    self.push(q as i32);
+                }
+            }
+            "NEGATE" | "1+" | "1-" | "2*" | "2/" | "ABS" | "INVERT" => {
+                let a = self.pop(w)?;
+                self.push(match w {
+                    "NEGATE" => a.wrapping_neg(),
+                    "1+" => a.wrapping_add(1),
+                    "1-" => a.wrapping_sub(1),
+                    "2*" => a.wrapping_shl(1),
+                    "2/" => a >> 1,
+                    "ABS" => a.wrapping_abs(),
+                    _ => !a,
+                });
+            }
+            "MIN" | "MAX" | "ARSHIFT" => {
+                let (a, b) = self.pop2(w)?;
+                self.push(match w {
+                    "MIN" => a.min(b),
+                    "MAX" => a.max(b),
+                    _ => a >> (b as u32 & 31),
+                });
+            }
+            "*/" | "*/MOD" | "FM/MOD" | "SM/REM" => {
+                let n = self.pop(w)? as i64;
+                let d = match w {
+                    "*/" | "*/MOD" => {
+                        let (a, b) = self.pop2(w)?;
+                        a as i64 * b as i64
+                    }
+                    _ => {
+                        let (lo, hi) = self.pop2(w)?;
+                        ((hi as i64) << 32) | (lo as u32 as i64)
+                    }
+                };
+                let n = Self::nonzero_divisor(n as i32, w)? as i64;
+                let floored = match w {
+                    "FM/MOD" => true,
+                    "SM/REM" => false,
+                    _ => self.division == DivisionMode::Floored,
+                };
+                let (r, q) = Self::divrem(d, n, floored);
+                if w != "*/" {
+                    self.push(r as i32);
+                }
+                self.push(q as i32);
+            }
+            "CELLS" => {
+                let a = self.pop(w)?;
+                self.push(a.wrapping_mul(4));
+            }
+            "CELL+" => {
+                let a = self.pop(w)?;
+                self.push(a.wrapping_add(4));
+            }
+            "=" | "<>" | "<" | "<=" | ">" | ">=" => {
+                let (a, b) = self.pop2(w)?;
+                self.push(flag(match w {
+                    "=" => a == b,
+                    "<>" => a != b,
+                    "<" => a < b,
+                    "<=" => a <= b,
+                    ">" => a > b,
+                    _ => a >= b,
+                }));
+            }
+            "U<" | "U>" => {
+                let (a, b) = self.pop2(w)?;
+                let (a, b) = (a as u32, b as u32);
+                self.push(flag(if w == "U<" { a < b } else { a > b }));
+            }
+            "U/" | "UMOD" => {
+                let (a, b) = self.pop2(w)?;
+                let b = Self::nonzero_divisor(b, w)? as u32;
+                let a = a as u32;
+                self.push(if w == "U/" { a / b } else { a % b } as i32);
+            }
+            "UM*" => {
+                let (a, b) = self.pop2(w)?;
+                let d = a as u32 as u64 * b as u32 as u64;
+                self.push(d as i32);
+                self.push((d >> 32) as i32);
+            }
+            "UM/MOD" => {
+                let n = Self::nonzero_divisor(self.pop(w)?, w)? as u32 as u64;
+                let (lo, hi) = self.pop2(w)?;
+                let d = ((hi as u32 as u64) << 32) | lo as u32 as u64;
+                self.push((d % n) as i32);
+                self.push((d / n) as i32);
+            }
+            "S>D" => {
+                let a = self.pop(w)?;
+                self.push_double(a as i64);
+            }
+            "D+" | "D-" => {
+                let b = self.pop_double(w)?;
+                let a = self.pop_double(w)?;
+                self.push_double(if w == "D+" {
+                    a.wrapping_add(b)
+                } else {
+                    a.wrapping_sub(b)
+                });
+            }
+            "DNEGATE" | "DABS" | "D2*" | "D2/" => {
+                let a = self.pop_double(w)?;
+                self.push_double(match w {
+                    "DNEGATE" => a.wrapping_neg(),
+                    "DABS" => a.wrapping_abs(),
+                    "D2*" => a.wrapping_shl(1),
+                    _ => a >> 1,
+                });
+            }
+            "D=" | "D<" => {
+                let b = self.pop_double(w)?;
+                let a = self.pop_double(w)?;
+                self.push(flag(if w == "D=" { a == b } else { a < b }));
+            }
+            "D0=" => {
+                let a = self.pop_double(w)?;
+                self.push(flag(a == 0));
+            }
+            "M*" => {
+                let (a, b) = self.pop2(w)?;
+                self.push_double(a as i64 * b as i64);
+            }
+            "M+" => {
+                let n = self.pop(w)?;
+                let d = self.pop_double(w)?;
+                self.push_double(d.wrapping_add(n as i64));
+            }
+            "0=" => {
+                let a = self.pop(w)?;
+                self.push(flag(a == 0));
+            }
+            "0<" => {
+                let a = self.pop(w)?;
+                self.push(flag(a < 0));
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
     }
 }
 
@@ -163,6 +562,10 @@ struct LlvmBuilder {
     globals: String,
     tmp: u32,
     lbl: u32,
+    // DILocation attached to every instruction line (with -g).
+    dbg: Option<usize>,
+    // `ptr` instead of typed pointers (LLVM 15 and later).
+    opaque_ptrs: bool,
 }
 
 impl LlvmBuilder {
@@ -172,6 +575,8 @@ impl LlvmBuilder {
             globals: String::new(),
             tmp: 0,
             lbl: 0,
+            dbg: None,
+            opaque_ptrs: false,
         }
     }
     fn fresh_tmp(&mut self) -> String {
@@ -184,6 +589,9 @@ impl LlvmBuilder {
     }
     fn emit_line(&mut self, s: &str) {
         self.out.push_str(s);
+        if let (Some(loc), true) = (self.dbg, s.starts_with("  ")) {
+            self.out.push_str(&format!(", !dbg !{}", loc));
+        }
         self.out.push('\n');
     }
 
@@ -191,23 +599,119 @@ impl LlvmBuilder {
         self.globals.push_str(s);
         self.globals.push('\n');
     }
-}
 
-#[derive(Debug, Clone)]
-enum Control {
-    If {
-        else_lbl: String,
-        end_lbl: String,
-        has_else: bool,
-    },
-    Begin {
-        begin_lbl: String,
-        // For WHILE/REPEAT
-        while_false_lbl: Option<String>,
-        while_true_lbl: Option<String>,
-    },
+    // Pointer type to `pointee` in the selected dialect.
+    fn ptr(&self, pointee: &str) -> String {
+        if self.opaque_ptrs {
+            "ptr".to_string()
+        } else {
+            format!("{}*", pointee)
+        }
+    }
+
+    fn load_i32(&mut self, ptr: &str) -> String {
+        let v = self.fresh_tmp();
+        let ty = self.ptr("i32");
+        self.emit_line(&format!("  {} = load i32, {} {}, align 4", v, ty, ptr));
+        v
+    }
+
+    fn store_i32(&mut self, v: &str, ptr: &str) {
+        let ty = self.ptr("i32");
+        self.emit_line(&format!("  store i32 {}, {} {}, align 4", v, ty, ptr));
+    }
+
+    // Address of cell `idx` of the i32 array at `base`.
+    fn gep_i32(&mut self, base: &str, idx: &str) -> String {
+        let p = self.fresh_tmp();
+        let ty = self.ptr("i32");
+        self.emit_line(&format!(
+            "  {} = getelementptr inbounds i32, {} {}, i32 {}",
+            p, ty, base, idx
+        ));
+        p
+    }
+
+    // Address of the first element of the `[n x elem]` array at `array`.
+    fn gep_array_start(&mut self, dst: &str, n: usize, elem: &str, array: &str) {
+        let arr = format!("[{} x {}]", n, elem);
+        let ty = self.ptr(&arr);
+        self.emit_line(&format!(
+            "  {} = getelementptr inbounds {}, {} {}, i32 0, i32 0",
+            dst, arr, ty, array
+        ));
+    }
 }
 
+// Service key -> C runtime symbol (runtime/runtime.c).
+const RUNTIME_SYMBOLS: &[(&str, &str)] = &[
+    ("PWRITE-I32", "pwrite_i32"),
+    ("PWRITE-U32", "pwrite_u32"),
+    ("PWRITE-BOOL", "pwrite_bool"),
+    ("D.", "pwrite_i64"),
+    ("D.R", "pwrite_i64_r"),
+    ("PWRITE-CHAR", "pwrite_char"),
+    ("TYPE", "pwrite_str"),
+    ("PWRITELN", "pwriteln"),
+    ("PWRITE-HEX", "pwrite_hex"),
+    ("PREAD-I32", "pread_i32"),
+    ("PREAD-BOOL", "pread_bool"),
+    ("PREAD-CHAR", "pread_char"),
+    ("PREAD-F32", "pread_f32_bits"),
+    ("PREADLN", "preadln"),
+    ("HERE", "rt_here"),
+    ("ALLOT", "rt_allot"),
+    (",", "rt_comma"),
+    ("ALLOCATE", "rt_allocate"),
+    ("FREE", "rt_free"),
+    ("RESIZE", "rt_resize"),
+    ("__RT_IOR", "rt_ior"),
+    ("PNEW", "rt_pnew"),
+    ("PDISPOSE", "rt_pdispose"),
+    ("__RT_HEAP_RESET", "rt_heap_reset"),
+    // Variable/field accessors as services (you can later lower them)
+    ("PVAR@", "pvar_get"),
+    ("PVAR!", "pvar_set"),
+    ("PFIELD@", "pfield_get"),
+    ("PFIELD!", "pfield_set"),
+    ("PBOOL", "pbool"),
+    ("PWRITE-F32", "pwrite_f32_bits"),
+    ("FADD", "fadd_bits"),
+    ("FSUB", "fsub_bits"),
+    ("FMUL", "fmul_bits"),
+    ("FDIV", "fdiv_bits"),
+    ("FNEGATE", "fnegate_bits"),
+    ("FABS", "fabs_bits"),
+    ("F=", "feq_bits"),
+    ("F<", "flt_bits"),
+    ("F<=", "fle_bits"),
+    ("FZERO?", "fzero_bits"),
+    ("FINF?", "finf_bits"),
+    ("FNAN?", "fnan_bits"),
+    ("FFINITE?", "ffinite_bits"),
+    ("S>F", "s_to_f_bits"),
+    ("F>S", "f_bits_to_s"),
+    ("Q16.16>F", "q16_16_to_f_bits"),
+    ("F>Q16.16", "f_bits_to_q16_16"),
+    ("FROUND-I32", "fround_i32_bits"),
+    ("__KP_FABS_F32", "kp_fabs_f32_bits"),
+    ("__KP_FSQRT_F32", "kp_fsqrt_f32_bits"),
+    ("__KP_FSIN_F32", "kp_fsin_f32_bits"),
+    ("__KP_FCOS_F32", "kp_fcos_f32_bits"),
+    ("__KP_FPOW_F32_I32", "kp_fpow_f32_i32_bits"),
+    ("__KP_FFLOOR_F32", "kp_ffloor_f32_bits"),
+    ("__KP_FCEIL_F32", "kp_fceil_f32_bits"),
+    ("__KP_FX_SQRT", "kp_fx_sqrt_i32"),
+    ("__KP_FX_SIN", "kp_fx_sin_deg_i32"),
+    ("__KP_FX_COS", "kp_fx_cos_deg_i32"),
+    ("__KP_FX_TAN", "kp_fx_tan_deg_i32"),
+    ("__KP_FX_ASIN", "kp_fx_asin_fixed_i32"),
+    ("__KP_FX_ACOS", "kp_fx_acos_fixed_i32"),
+    ("__KP_FX_ATAN", "kp_fx_atan_fixed_i32"),
+    ("__KP_FX_LN", "kp_fx_ln_i32"),
+    ("__KP_FX_LOG", "kp_fx_log_i32"),
+];
+
 struct Codegen<'a> {
     b: LlvmBuilder,
     // ABI: stack_base: i32*, sp_ptr: i32*
@@ -215,172 +719,155 @@ struct Codegen<'a> {
     sp_ptr: &'a str,
     rstack_base: &'a str,
     rsp_ptr: &'a str,
-    ctrl: Vec<Control>,
     externs: HashMap<String, String>, // word -> llvm callee
-    created_words: HashMap<String, i32>,
-    constant_words: HashMap<String, i32>,
-    known_defs: HashSet<String>,
+    static_inits: BTreeMap<i32, i32>,
     here: i32,
+    division: DivisionMode,
+    // Definition currently being compiled (for runtime diagnostics).
+    cur_word: String,
+    word_name_globals: HashMap<String, (String, usize)>,
+    // Literal operands: when enabled, constants pushed by the body are kept
+    // here (top last) and only stored to the data stack when something needs
+    // the stack in memory.
+    opt: bool,
+    pending: Vec<String>,
+    // None leaves the triple and data layout to llc (the host).
+    target: Option<&'static target::Target>,
+    debug: Option<debuginfo::DebugInfo>,
 }
 
 impl<'a> Codegen<'a> {
     fn new() -> Self {
-        let mut externs = HashMap::new();
-        // Map your high-level service words to C runtime symbols
-        externs.insert("PWRITE-I32".into(), "pwrite_i32".into());
-        externs.insert("PWRITE-BOOL".into(), "pwrite_bool".into());
-        externs.insert("PWRITE-CHAR".into(), "pwrite_char".into());
-        externs.insert("TYPE".into(), "pwrite_str".into());
-        externs.insert("PWRITELN".into(), "pwriteln".into());
-        externs.insert("PWRITE-HEX".into(), "pwrite_hex".into());
-
-        externs.insert("PREAD-I32".into(), "pread_i32".into());
-        externs.insert("PREAD-BOOL".into(), "pread_bool".into());
-        externs.insert("PREAD-CHAR".into(), "pread_char".into());
-        externs.insert("PREAD-F32".into(), "pread_f32_bits".into());
-        externs.insert("PREADLN".into(), "preadln".into());
-        externs.insert("HERE".into(), "rt_here".into());
-        externs.insert("ALLOT".into(), "rt_allot".into());
-        externs.insert("__RT_HEAP_RESET".into(), "rt_heap_reset".into());
-
-        // Variable/field accessors as services (you can later lower them)
-        externs.insert("PVAR@".into(), "pvar_get".into());
-        externs.insert("PVAR!".into(), "pvar_set".into());
-        externs.insert("PFIELD@".into(), "pfield_get".into());
-        externs.insert("PFIELD!".into(), "pfield_set".into());
-
-        externs.insert("PBOOL".into(), "pbool".into());
-        externs.insert("PWRITE-F32".into(), "pwrite_f32_bits".into());
-        externs.insert("FADD".into(), "fadd_bits".into());
-        externs.insert("FSUB".into(), "fsub_bits".into());
-        externs.insert("FMUL".into(), "fmul_bits".into());
-        externs.insert("FDIV".into(), "fdiv_bits".into());
-        externs.insert("FNEGATE".into(), "fnegate_bits".into());
-        externs.insert("FABS".into(), "fabs_bits".into());
-        externs.insert("F=".into(), "feq_bits".into());
-        externs.insert("F<".into(), "flt_bits".into());
-        externs.insert("F<=".into(), "fle_bits".into());
-        externs.insert("FZERO?".into(), "fzero_bits".into());
-        externs.insert("FINF?".into(), "finf_bits".into());
-        externs.insert("FNAN?".into(), "fnan_bits".into());
-        externs.insert("FFINITE?".into(), "ffinite_bits".into());
-        externs.insert("S>F".into(), "s_to_f_bits".into());
-        externs.insert("F>S".into(), "f_bits_to_s".into());
-        externs.insert("Q16.16>F".into(), "q16_16_to_f_bits".into());
-        externs.insert("F>Q16.16".into(), "f_bits_to_q16_16".into());
-        externs.insert("FROUND-I32".into(), "fround_i32_bits".into());
-
-        externs.insert("__KP_FABS_F32".into(), "kp_fabs_f32_bits".into());
-        externs.insert("__KP_FSQRT_F32".into(), "kp_fsqrt_f32_bits".into());
-        externs.insert("__KP_FSIN_F32".into(), "kp_fsin_f32_bits".into());
-        externs.insert("__KP_FCOS_F32".into(), "kp_fcos_f32_bits".into());
-        externs.insert("__KP_FPOW_F32_I32".into(), "kp_fpow_f32_i32_bits".into());
-        externs.insert("__KP_FFLOOR_F32".into(), "kp_ffloor_f32_bits".into());
-        externs.insert("__KP_FCEIL_F32".into(), "kp_fceil_f32_bits".into());
-
-        externs.insert("__KP_FX_SQRT".into(), "kp_fx_sqrt_i32".into());
-        externs.insert("__KP_FX_SIN".into(), "kp_fx_sin_deg_i32".into());
-        externs.insert("__KP_FX_COS".into(), "kp_fx_cos_deg_i32".into());
-        externs.insert("__KP_FX_TAN".into(), "kp_fx_tan_deg_i32".into());
-        externs.insert("__KP_FX_ASIN".into(), "kp_fx_asin_fixed_i32".into());
-        externs.insert("__KP_FX_ACOS".into(), "kp_fx_acos_fixed_i32".into());
-        externs.insert("__KP_FX_ATAN".into(), "kp_fx_atan_fixed_i32".into());
-        externs.insert("__KP_FX_LN".into(), "kp_fx_ln_i32".into());
-        externs.insert("__KP_FX_LOG".into(), "kp_fx_log_i32".into());
-
+        let externs = RUNTIME_SYMBOLS
+            .iter()
+            .map(|&(k, v)| (k.to_string(), v.to_string()))
+            .collect();
         Self {
             b: LlvmBuilder::new(),
             stack_base: "%stack_base",
             sp_ptr: "%sp_ptr",
             rstack_base: "%rstack_base",
             rsp_ptr: "%rsp_ptr",
-            ctrl: Vec::new(),
             externs,
-            created_words: HashMap::new(),
-            constant_words: HashMap::new(),
-            known_defs: HashSet::new(),
+            static_inits: BTreeMap::new(),
             here: 0,
+            division: DivisionMode::Symmetric,
+            cur_word: String::new(),
+            word_name_globals: HashMap::new(),
+            opt: false,
+            pending: Vec::new(),
+            target: None,
+            debug: None,
         }
     }
 
-    fn set_program_symbols(
-        &mut self,
-        created_words: HashMap<String, i32>,
-        constant_words: HashMap<String, i32>,
-        known_defs: HashSet<String>,
-        here: i32,
-    ) {
-        self.created_words = created_words;
-        self.constant_words = constant_words;
-        self.known_defs = known_defs;
-        self.here = here;
+    fn set_module_info(&mut self, m: &kir::Module) {
+        self.static_inits = m.static_inits.clone();
+        self.here = m.here;
+        self.division = m.division;
     }
 
     fn emit_prelude(&mut self) {
         self.b.emit_line("; ModuleID = 'forthc'");
+        if let Some(t) = self.target {
+            self.b
+                .emit_line(&format!("target datalayout = \"{}\"", t.datalayout));
+            self.b
+                .emit_line(&format!("target triple = \"{}\"", t.triple));
+        }
         self.b.emit_line("");
 
         // extern declarations (edit to match your runtime.c)
-        self.b.emit_line("declare void @pwrite_i32(i32)");
-        self.b.emit_line("declare void @pwrite_bool(i32)");
-        self.b.emit_line("declare void @pwrite_char(i32)");
-        self.b.emit_line("declare void @pwrite_hex(i32)");
-        self.b.emit_line("declare void @pwriteln()");
-        self.b.emit_line("declare void @pwrite_str(i8*)");
-
-        self.b.emit_line("declare i32 @pread_i32()");
-        self.b.emit_line("declare i32 @pread_bool()");
-        self.b.emit_line("declare i32 @pread_char()");
-        self.b.emit_line("declare i32 @pread_f32_bits()");
-        self.b.emit_line("declare void @preadln()");
-        self.b.emit_line("declare i32 @rt_here()");
-        self.b.emit_line("declare void @rt_allot(i32)");
-        self.b.emit_line("declare void @rt_heap_reset(i32)");
-
-        self.b.emit_line("declare i32 @pvar_get(i32)");
-        self.b.emit_line("declare void @pvar_set(i32, i32)");
-        self.b.emit_line("declare i32 @pfield_get(i32, i32)");
-        self.b.emit_line("declare void @pfield_set(i32, i32, i32)");
-        self.b.emit_line("declare i32 @pbool(i32)");
-        self.b.emit_line("declare void @pwrite_f32_bits(i32)");
-        self.b.emit_line("declare i32 @fadd_bits(i32, i32)");
-        self.b.emit_line("declare i32 @fsub_bits(i32, i32)");
-        self.b.emit_line("declare i32 @fmul_bits(i32, i32)");
-        self.b.emit_line("declare i32 @fdiv_bits(i32, i32)");
-        self.b.emit_line("declare i32 @fnegate_bits(i32)");
-        self.b.emit_line("declare i32 @fabs_bits(i32)");
-        self.b.emit_line("declare i32 @feq_bits(i32, i32)");
-        self.b.emit_line("declare i32 @flt_bits(i32, i32)");
-        self.b.emit_line("declare i32 @fle_bits(i32, i32)");
-        self.b.emit_line("declare i32 @fzero_bits(i32)");
-        self.b.emit_line("declare i32 @finf_bits(i32)");
-        self.b.emit_line("declare i32 @fnan_bits(i32)");
-        self.b.emit_line("declare i32 @ffinite_bits(i32)");
-        self.b.emit_line("declare i32 @s_to_f_bits(i32)");
-        self.b.emit_line("declare i32 @f_bits_to_s(i32)");
-        self.b.emit_line("declare i32 @q16_16_to_f_bits(i32)");
-        self.b.emit_line("declare i32 @f_bits_to_q16_16(i32)");
-        self.b.emit_line("declare i32 @fround_i32_bits(i32)");
-        self.b.emit_line("declare i32 @kp_fabs_f32_bits(i32)");
-        self.b.emit_line("declare i32 @kp_fsqrt_f32_bits(i32)");
-        self.b.emit_line("declare i32 @kp_fsin_f32_bits(i32)");
-        self.b.emit_line("declare i32 @kp_fcos_f32_bits(i32)");
-        self.b
-            .emit_line("declare i32 @kp_fpow_f32_i32_bits(i32, i32)");
-        self.b.emit_line("declare i32 @kp_ffloor_f32_bits(i32)");
-        self.b.emit_line("declare i32 @kp_fceil_f32_bits(i32)");
-        self.b.emit_line("declare i32 @kp_fx_sqrt_i32(i32)");
-        self.b.emit_line("declare i32 @kp_fx_sin_deg_i32(i32)");
-        self.b.emit_line("declare i32 @kp_fx_cos_deg_i32(i32)");
-        self.b.emit_line("declare i32 @kp_fx_tan_deg_i32(i32)");
-        self.b.emit_line("declare i32 @kp_fx_asin_fixed_i32(i32)");
-        self.b.emit_line("declare i32 @kp_fx_acos_fixed_i32(i32)");
-        self.b.emit_line("declare i32 @kp_fx_atan_fixed_i32(i32)");
-        self.b.emit_line("declare i32 @kp_fx_ln_i32(i32)");
-        self.b.emit_line("declare i32 @kp_fx_log_i32(i32)");
+        self.declare("void @pwrite_i32(i32)");
+        self.declare("void @pwrite_u32(i32)");
+        self.declare("void @pwrite_bool(i32)");
+        self.declare("void @pwrite_i64(i64)");
+        self.declare("void @pwrite_i64_r(i64, i32)");
+        self.declare("void @pwrite_char(i32)");
+        self.declare("void @pwrite_hex(i32)");
+        self.declare("void @pwriteln()");
+        let i8p = self.b.ptr("i8");
+        let i32p = self.b.ptr("i32");
+        self.declare(&format!("void @pwrite_str({})", i8p));
+
+        self.declare("i32 @pread_i32()");
+        self.declare("i32 @pread_bool()");
+        self.declare("i32 @pread_char()");
+        self.declare("i32 @pread_f32_bits()");
+        self.declare("void @preadln()");
+        self.declare("i32 @rt_here()");
+        self.declare("void @rt_allot(i32)");
+        self.declare("void @rt_heap_reset(i32)");
+        self.declare(&format!("void @rt_set_data_image({}, i32)", i32p));
+        self.declare("void @rt_comma(i32)");
+        self.declare("i32 @rt_allocate(i32)");
+        self.declare("i32 @rt_free(i32)");
+        self.declare("i32 @rt_resize(i32, i32)");
+        self.declare("i32 @rt_ior()");
+        self.declare("i32 @rt_pnew(i32)");
+        self.declare("void @rt_pdispose(i32)");
+        self.declare(&format!("void @rt_div_zero({}) noreturn", i8p));
+        self.declare(&format!("void @rt_mem_check(i32, i32, {})", i8p));
+
+        self.declare("i32 @pvar_get(i32)");
+        self.declare("void @pvar_set(i32, i32)");
+        self.declare("i32 @pfield_get(i32, i32)");
+        self.declare("void @pfield_set(i32, i32, i32)");
+        self.declare("i32 @pbool(i32)");
+        self.declare("void @pwrite_f32_bits(i32)");
+        self.declare("i32 @fadd_bits(i32, i32)");
+        self.declare("i32 @fsub_bits(i32, i32)");
+        self.declare("i32 @fmul_bits(i32, i32)");
+        self.declare("i32 @fdiv_bits(i32, i32)");
+        self.declare("i32 @fnegate_bits(i32)");
+        self.declare("i32 @fabs_bits(i32)");
+        self.declare("i32 @feq_bits(i32, i32)");
+        self.declare("i32 @flt_bits(i32, i32)");
+        self.declare("i32 @fle_bits(i32, i32)");
+        self.declare("i32 @fzero_bits(i32)");
+        self.declare("i32 @finf_bits(i32)");
+        self.declare("i32 @fnan_bits(i32)");
+        self.declare("i32 @ffinite_bits(i32)");
+        self.declare("i32 @s_to_f_bits(i32)");
+        self.declare("i32 @f_bits_to_s(i32)");
+        self.declare("i32 @q16_16_to_f_bits(i32)");
+        self.declare("i32 @f_bits_to_q16_16(i32)");
+        self.declare("i32 @fround_i32_bits(i32)");
+        self.declare("i32 @kp_fabs_f32_bits(i32)");
+        self.declare("i32 @kp_fsqrt_f32_bits(i32)");
+        self.declare("i32 @kp_fsin_f32_bits(i32)");
+        self.declare("i32 @kp_fcos_f32_bits(i32)");
+        self.declare("i32 @kp_fpow_f32_i32_bits(i32, i32)");
+        self.declare("i32 @kp_ffloor_f32_bits(i32)");
+        self.declare("i32 @kp_fceil_f32_bits(i32)");
+        self.declare("i32 @kp_fx_sqrt_i32(i32)");
+        self.declare("i32 @kp_fx_sin_deg_i32(i32)");
+        self.declare("i32 @kp_fx_cos_deg_i32(i32)");
+        self.declare("i32 @kp_fx_tan_deg_i32(i32)");
+        self.declare("i32 @kp_fx_asin_fixed_i32(i32)");
+        self.declare("i32 @kp_fx_acos_fixed_i32(i32)");
+        self.declare("i32 @kp_fx_atan_fixed_i32(i32)");
+        self.declare("i32 @kp_fx_ln_i32(i32)");
+        self.declare("i32 @kp_fx_log_i32(i32)");
         self.b.emit_line("");
     }
 
+    // Runtime functions take and return C int32_t, which some ABIs want
+    // sign-extended to register width.
+    fn declare(&mut self, sig: &str) {
+        let sig = if self.target.is_some_and(|t| t.sign_extend_i32) {
+            let (ret, rest) = sig.split_once(' ').unwrap_or((sig, ""));
+            let ret = if ret == "i32" { "signext i32" } else { ret };
+            let rest = rest
+                .replace("i32,", "i32 signext,")
+                .replace("i32)", "i32 signext)");
+            format!("{} {}", ret, rest)
+        } else {
+            sig.to_string()
+        };
+        self.b.emit_line(&format!("declare {}", sig));
+    }
+
     fn emit_main_wrapper(&mut self, entry: &str) {
         let entry = llvm_word_sym(entry);
         // A tiny C-like main in LLVM, allocating stack+sp on entry.
@@ -389,112 +876,148 @@ impl<'a> Codegen<'a> {
         self.b.emit_line("entry:");
         self.b.emit_line("  %stack = alloca [1024 x i32], align 16");
         self.b.emit_line("  %sp = alloca i32, align 4");
-        self.b.emit_line("  store i32 0, i32* %sp, align 4");
-        self.b.emit_line(
-            "  %base = getelementptr inbounds [1024 x i32], [1024 x i32]* %stack, i32 0, i32 0",
-        );
+        self.b.store_i32("0", "%sp");
+        self.b.gep_array_start("%base", 1024, "i32", "%stack");
+        if !self.static_inits.is_empty() {
+            // Initializer image: (byte address, value) pairs applied by rt_heap_reset.
+            let n = self.static_inits.len();
+            let body: Vec<String> = self
+                .static_inits
+                .iter()
+                .map(|(a, v)| format!("i32 {}, i32 {}", a, v))
+                .collect();
+            self.b.emit_global_line(&format!(
+                "@data_image = private constant [{} x i32] [{}]",
+                n * 2,
+                body.join(", ")
+            ));
+            self.b
+                .gep_array_start("%image", n * 2, "i32", "@data_image");
+            self.b.emit_line(&format!(
+                "  call void @rt_set_data_image({} %image, i32 {})",
+                self.b.ptr("i32"),
+                n
+            ));
+        }
         self.b
             .emit_line(&format!("  call void @rt_heap_reset(i32 {})", self.here));
-        self.b
-            .emit_line(&format!("  call void @{}(i32* %base, i32* %sp)", entry));
+        self.b.emit_line(&format!(
+            "  call void @{}({})",
+            entry,
+            self.word_args("%base", "%sp")
+        ));
         self.b.emit_line("  ret i32 0");
         self.b.emit_line("}");
         self.b.emit_line("");
     }
 
-    fn begin_func(&mut self, name: &str) {
-        let name = llvm_word_sym(name);
+    // Parameter/argument list of the word ABI.
+    fn word_args(&self, stack_base: &str, sp_ptr: &str) -> String {
+        let p = self.b.ptr("i32");
+        format!("{} {}, {} {}", p, stack_base, p, sp_ptr)
+    }
+
+    // The return stack is only allocated when the word uses it. A word
+    // with self tail calls loops back to `start`, which resets it.
+    fn begin_func(&mut self, f: &kir::Func) {
+        self.cur_word = f.name.clone();
+        let name = llvm_word_sym(&f.name);
+        let dbg = match &mut self.debug {
+            Some(d) => {
+                let sp = d.subprogram(&f.name, &name, f.line);
+                self.b.dbg = Some(d.location(f.line));
+                format!(" !dbg !{}", sp)
+            }
+            None => String::new(),
+        };
         self.b.emit_line(&format!(
-            "define void @{}(i32* %stack_base, i32* %sp_ptr) {{",
-            name
+            "define void @{}({}){} {{",
+            name,
+            self.word_args("%stack_base", "%sp_ptr"),
+            dbg
         ));
         self.b.emit_line("entry:");
-        self.b
-            .emit_line("  %rstack = alloca [1024 x i32], align 16");
-        self.b.emit_line("  %rsp_ptr = alloca i32, align 4");
-        self.b.emit_line("  store i32 0, i32* %rsp_ptr, align 4");
-        self.b.emit_line(
-            "  %rstack_base = getelementptr inbounds [1024 x i32], [1024 x i32]* %rstack, i32 0, i32 0",
-        );
+        let uses_rstack = f.uses_return_stack();
+        if uses_rstack {
+            self.b
+                .emit_line("  %rstack = alloca [1024 x i32], align 16");
+            self.b.emit_line("  %rsp_ptr = alloca i32, align 4");
+            self.b
+                .gep_array_start("%rstack_base", 1024, "i32", "%rstack");
+        }
+        if f.has_self_tail_call() {
+            self.b.emit_line("  br label %start");
+            self.b.emit_line("start:");
+        }
+        if uses_rstack {
+            self.b.store_i32("0", "%rsp_ptr");
+        }
     }
 
     fn end_func(&mut self) {
-        self.b.emit_line("  ret void");
+        self.b.dbg = None;
         self.b.emit_line("}");
         self.b.emit_line("");
     }
 
     // stack ops: push/pop using memory stack + sp_ptr
     fn load_sp(&mut self) -> String {
-        let t = self.b.fresh_tmp();
-        self.b.emit_line(&format!(
-            "  {} = load i32, i32* {}, align 4",
-            t, self.sp_ptr
-        ));
-        t
+        self.b.load_i32(self.sp_ptr)
     }
     fn store_sp(&mut self, sp: &str) {
-        self.b.emit_line(&format!(
-            "  store i32 {}, i32* {}, align 4",
-            sp, self.sp_ptr
-        ));
+        self.b.store_i32(sp, self.sp_ptr);
     }
 
     fn push_i32(&mut self, v: &str) {
+        if self.opt && v.parse::<i32>().is_ok() {
+            self.pending.push(v.to_string());
+            return;
+        }
+        self.flush_pending();
+        self.store_push(v);
+    }
+
+    fn store_push(&mut self, v: &str) {
         let sp = self.load_sp();
-        let ptr = self.b.fresh_tmp();
-        self.b.emit_line(&format!(
-            "  {} = getelementptr inbounds i32, i32* {}, i32 {}",
-            ptr, self.stack_base, sp
-        ));
-        self.b
-            .emit_line(&format!("  store i32 {}, i32* {}, align 4", v, ptr));
+        let ptr = self.b.gep_i32(self.stack_base, &sp);
+        self.b.store_i32(v, &ptr);
         let sp2 = self.b.fresh_tmp();
         self.b.emit_line(&format!("  {} = add i32 {}, 1", sp2, sp)); // wrap
         self.store_sp(&sp2);
     }
 
+    // Stores deferred literals so the memory stack is exact. Needed before
+    // calls, control flow and anything that reads sp directly.
+    fn flush_pending(&mut self) {
+        for v in std::mem::take(&mut self.pending) {
+            self.store_push(&v);
+        }
+    }
+
     fn pop_i32(&mut self) -> String {
+        if let Some(v) = self.pending.pop() {
+            return v;
+        }
         let sp = self.load_sp();
         let sp2 = self.b.fresh_tmp();
         self.b.emit_line(&format!("  {} = sub i32 {}, 1", sp2, sp)); // wrap
         self.store_sp(&sp2);
-        let ptr = self.b.fresh_tmp();
-        self.b.emit_line(&format!(
-            "  {} = getelementptr inbounds i32, i32* {}, i32 {}",
-            ptr, self.stack_base, sp2
-        ));
-        let v = self.b.fresh_tmp();
-        self.b
-            .emit_line(&format!("  {} = load i32, i32* {}, align 4", v, ptr));
-        v
+        let ptr = self.b.gep_i32(self.stack_base, &sp2);
+        self.b.load_i32(&ptr)
     }
 
     fn load_rsp(&mut self) -> String {
-        let t = self.b.fresh_tmp();
-        self.b.emit_line(&format!(
-            "  {} = load i32, i32* {}, align 4",
-            t, self.rsp_ptr
-        ));
-        t
+        self.b.load_i32(self.rsp_ptr)
     }
 
     fn store_rsp(&mut self, rsp: &str) {
-        self.b.emit_line(&format!(
-            "  store i32 {}, i32* {}, align 4",
-            rsp, self.rsp_ptr
-        ));
+        self.b.store_i32(rsp, self.rsp_ptr);
     }
 
     fn rpush_i32(&mut self, v: &str) {
         let rsp = self.load_rsp();
-        let ptr = self.b.fresh_tmp();
-        self.b.emit_line(&format!(
-            "  {} = getelementptr inbounds i32, i32* {}, i32 {}",
-            ptr, self.rstack_base, rsp
-        ));
-        self.b
-            .emit_line(&format!("  store i32 {}, i32* {}, align 4", v, ptr));
+        let ptr = self.b.gep_i32(self.rstack_base, &rsp);
+        self.b.store_i32(v, &ptr);
         let rsp2 = self.b.fresh_tmp();
         self.b
             .emit_line(&format!("  {} = add i32 {}, 1", rsp2, rsp));
@@ -507,15 +1030,8 @@ impl<'a> Codegen<'a> {
         self.b
             .emit_line(&format!("  {} = sub i32 {}, 1", rsp2, rsp));
         self.store_rsp(&rsp2);
-        let ptr = self.b.fresh_tmp();
-        self.b.emit_line(&format!(
-            "  {} = getelementptr inbounds i32, i32* {}, i32 {}",
-            ptr, self.rstack_base, rsp2
-        ));
-        let v = self.b.fresh_tmp();
-        self.b
-            .emit_line(&format!("  {} = load i32, i32* {}, align 4", v, ptr));
-        v
+        let ptr = self.b.gep_i32(self.rstack_base, &rsp2);
+        self.b.load_i32(&ptr)
     }
 
     fn rpeek_i32(&mut self) -> String {
@@ -523,15 +1039,8 @@ impl<'a> Codegen<'a> {
         let rsp2 = self.b.fresh_tmp();
         self.b
             .emit_line(&format!("  {} = sub i32 {}, 1", rsp2, rsp));
-        let ptr = self.b.fresh_tmp();
-        self.b.emit_line(&format!(
-            "  {} = getelementptr inbounds i32, i32* {}, i32 {}",
-            ptr, self.rstack_base, rsp2
-        ));
-        let v = self.b.fresh_tmp();
-        self.b
-            .emit_line(&format!("  {} = load i32, i32* {}, align 4", v, ptr));
-        v
+        let ptr = self.b.gep_i32(self.rstack_base, &rsp2);
+        self.b.load_i32(&ptr)
     }
 
     fn dup(&mut self) {
@@ -543,6 +1052,122 @@ impl<'a> Codegen<'a> {
         let _ = self.pop_i32();
     }
 
+    // Pops the listed number of cells and pushes them back in the order given
+    // by `order` (indices into the popped cells, deepest first).
+    fn permute(&mut self, depth: usize, order: &[usize]) {
+        let mut cells = Vec::with_capacity(depth);
+        for _ in 0..depth {
+            cells.push(self.pop_i32());
+        }
+        cells.reverse();
+        for &k in order {
+            let v = cells[k].clone();
+            self.push_i32(&v);
+        }
+    }
+
+    fn stack_slot(&mut self, idx: &str) -> String {
+        self.b.gep_i32(self.stack_base, idx)
+    }
+
+    fn qdup(&mut self) {
+        // Branch-free ?DUP: always write two slots, advance sp by 1 or 2.
+        let v = self.pop_i32();
+        self.flush_pending();
+        let sp = self.load_sp();
+        let p0 = self.stack_slot(&sp);
+        self.b.store_i32(&v, &p0);
+        let sp1 = self.b.fresh_tmp();
+        self.b.emit_line(&format!("  {} = add i32 {}, 1", sp1, sp));
+        let p1 = self.stack_slot(&sp1);
+        self.b.store_i32(&v, &p1);
+        let nz = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = icmp ne i32 {}, 0", nz, v));
+        let inc = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = zext i1 {} to i32", inc, nz));
+        let sp2 = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = add i32 {}, {}", sp2, sp1, inc));
+        self.store_sp(&sp2);
+    }
+
+    // Address index of the cell `n` below the top of stack (0 = top).
+    fn index_below_top(&mut self, n: &str) -> String {
+        let sp = self.load_sp();
+        let top = self.b.fresh_tmp();
+        self.b.emit_line(&format!("  {} = sub i32 {}, 1", top, sp));
+        let idx = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = sub i32 {}, {}", idx, top, n));
+        idx
+    }
+
+    fn pick(&mut self, n: &str) {
+        self.flush_pending();
+        let idx = self.index_below_top(n);
+        let ptr = self.stack_slot(&idx);
+        let v = self.b.load_i32(&ptr);
+        self.push_i32(&v);
+    }
+
+    fn roll_const(&mut self, n: i32) {
+        if n <= 0 {
+            return;
+        }
+        let depth = n as usize + 1;
+        let mut order: Vec<usize> = (1..depth).collect();
+        order.push(0);
+        self.permute(depth, &order);
+    }
+
+    fn roll_dynamic(&mut self) {
+        // ( xu ... x0 u -- xu-1 ... x0 xu ): shift cells down in a loop.
+        let u = self.pop_i32();
+        self.flush_pending();
+        let sp = self.load_sp();
+        let top = self.b.fresh_tmp();
+        self.b.emit_line(&format!("  {} = sub i32 {}, 1", top, sp));
+        let start = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = sub i32 {}, {}", start, top, u));
+        let start_ptr = self.stack_slot(&start);
+        let v = self.b.load_i32(&start_ptr);
+
+        let pre = self.b.fresh_lbl("roll_pre");
+        let head = self.b.fresh_lbl("roll_head");
+        let body = self.b.fresh_lbl("roll_body");
+        let done = self.b.fresh_lbl("roll_done");
+        let k = self.b.fresh_tmp();
+        let k1 = self.b.fresh_tmp();
+        self.b.emit_line(&format!("  br label %{}", pre));
+        self.b.emit_line(&format!("{}:", pre));
+        self.b.emit_line(&format!("  br label %{}", head));
+        self.b.emit_line(&format!("{}:", head));
+        self.b.emit_line(&format!(
+            "  {} = phi i32 [ {}, %{} ], [ {}, %{} ]",
+            k, start, pre, k1, body
+        ));
+        let more = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = icmp slt i32 {}, {}", more, k, top));
+        self.b.emit_line(&format!(
+            "  br i1 {}, label %{}, label %{}",
+            more, body, done
+        ));
+        self.b.emit_line(&format!("{}:", body));
+        self.b.emit_line(&format!("  {} = add i32 {}, 1", k1, k));
+        let src = self.stack_slot(&k1);
+        let x = self.b.load_i32(&src);
+        let dst = self.stack_slot(&k);
+        self.b.store_i32(&x, &dst);
+        self.b.emit_line(&format!("  br label %{}", head));
+        self.b.emit_line(&format!("{}:", done));
+        let top_ptr = self.stack_slot(&top);
+        self.b.store_i32(&v, &top_ptr);
+    }
+
     fn binop(&mut self, op: &str) {
         let b = self.pop_i32();
         let a = self.pop_i32();
@@ -577,193 +1202,442 @@ impl<'a> Codegen<'a> {
         self.binop("and");
     }
 
-    fn div_mod(&mut self, is_mod: bool) {
-        let b = self.pop_i32();
+    fn binop_imm(&mut self, op: &str, imm: i32) {
         let a = self.pop_i32();
         let r = self.b.fresh_tmp();
-        if is_mod {
-            self.b
-                .emit_line(&format!("  {} = srem i32 {}, {}", r, a, b)); // 0方向
-        } else {
-            self.b
-                .emit_line(&format!("  {} = sdiv i32 {}, {}", r, a, b)); // 0方向
-        }
+        self.b
+            .emit_line(&format!("  {} = {} i32 {}, {}", r, op, a, imm));
         self.push_i32(&r);
     }
 
-    fn zero_eq(&mut self) {
+    fn shift(&mut self, op: &str) {
+        let b = self.pop_i32();
         let a = self.pop_i32();
-        let c = self.b.fresh_tmp();
-        self.b.emit_line(&format!("  {} = icmp eq i32 {}, 0", c, a));
-        let z = self.b.fresh_tmp();
-        self.b.emit_line(&format!("  {} = zext i1 {} to i32", z, c));
-        let neg = self.b.fresh_tmp();
-        self.b.emit_line(&format!("  {} = sub i32 0, {}", neg, z));
-        self.push_i32(&neg);
+        let sh = self.b.fresh_tmp();
+        self.b.emit_line(&format!("  {} = and i32 {}, 31", sh, b));
+        let r = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = {} i32 {}, {}", r, op, a, sh));
+        self.push_i32(&r);
     }
 
-    fn zero_lt(&mut self) {
+    fn select_cmp(&mut self, pred: &str) {
+        // MIN/MAX: keep `a` when `a pred b`, else `b`.
+        let b = self.pop_i32();
         let a = self.pop_i32();
         let c = self.b.fresh_tmp();
         self.b
-            .emit_line(&format!("  {} = icmp slt i32 {}, 0", c, a));
-        let z = self.b.fresh_tmp();
-        self.b.emit_line(&format!("  {} = zext i1 {} to i32", z, c));
-        let neg = self.b.fresh_tmp();
-        self.b.emit_line(&format!("  {} = sub i32 0, {}", neg, z));
-        self.push_i32(&neg);
+            .emit_line(&format!("  {} = icmp {} i32 {}, {}", c, pred, a, b));
+        let r = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = select i1 {}, i32 {}, i32 {}", r, c, a, b));
+        self.push_i32(&r);
     }
 
-    // Control flow sugar (IF/ELSE/THEN, BEGIN/UNTIL, BEGIN/WHILE/REPEAT)
-    fn emit_br_cond_zero_to(&mut self, cond_i32: &str, if_zero_lbl: &str, if_nz_lbl: &str) {
+    fn abs(&mut self) {
+        let a = self.pop_i32();
+        let neg = self.b.fresh_tmp();
+        self.b.emit_line(&format!("  {} = sub i32 0, {}", neg, a));
         let c = self.b.fresh_tmp();
         self.b
-            .emit_line(&format!("  {} = icmp eq i32 {}, 0", c, cond_i32));
+            .emit_line(&format!("  {} = icmp slt i32 {}, 0", c, a));
+        let r = self.b.fresh_tmp();
         self.b.emit_line(&format!(
-            "  br i1 {}, label %{}, label %{}",
-            c, if_zero_lbl, if_nz_lbl
+            "  {} = select i1 {}, i32 {}, i32 {}",
+            r, c, neg, a
         ));
+        self.push_i32(&r);
     }
 
-    fn begin_if(&mut self) -> Result<(), String> {
-        let cond = self.pop_i32();
-        let then_lbl = self.b.fresh_lbl("then");
-        let else_lbl = self.b.fresh_lbl("else");
-        let end_lbl = self.b.fresh_lbl("endif");
+    fn sext64(&mut self, v: &str) -> String {
+        let r = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = sext i32 {} to i64", r, v));
+        r
+    }
 
-        self.emit_br_cond_zero_to(&cond, &else_lbl, &then_lbl);
-        self.b.emit_line(&format!("{}:", then_lbl));
+    fn trunc32(&mut self, v: &str) -> String {
+        let r = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = trunc i64 {} to i32", r, v));
+        r
+    }
 
-        self.ctrl.push(Control::If {
-            else_lbl,
-            end_lbl,
-            has_else: false,
-        });
-        Ok(())
+    // Division by zero is undefined in LLVM, so every division is guarded by
+    // an explicit branch to the runtime trap.
+    fn guard_div_zero(&mut self, ty: &str, n: &str) {
+        let z = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = icmp eq {} {}, 0", z, ty, n));
+        let trap_lbl = self.b.fresh_lbl("divzero");
+        let ok_lbl = self.b.fresh_lbl("divok");
+        self.b.emit_line(&format!(
+            "  br i1 {}, label %{}, label %{}",
+            z, trap_lbl, ok_lbl
+        ));
+        self.b.emit_line(&format!("{}:", trap_lbl));
+        let p = self.cur_word_name_ptr();
+        self.b.emit_line(&format!(
+            "  call void @rt_div_zero({} {})",
+            self.b.ptr("i8"),
+            p
+        ));
+        self.b.emit_line("  unreachable");
+        self.b.emit_line(&format!("{}:", ok_lbl));
     }
 
-    fn do_else(&mut self) -> Result<(), String> {
-        match self.ctrl.last_mut() {
-            Some(Control::If {
-                else_lbl,
-                end_lbl,
-                has_else,
-                ..
-            }) => {
-                // jump to end from then-branch
-                self.b.emit_line(&format!("  br label %{}", end_lbl));
-                // start else label
-                self.b.emit_line(&format!("{}:", else_lbl));
-                *has_else = true;
-                Ok(())
-            }
-            _ => Err("ELSE without IF".into()),
+    // Signed division of `d` by `n` in `ty` returning (remainder, quotient).
+    // A divisor of -1 is special-cased so that MIN / -1 wraps instead of
+    // hitting LLVM's undefined overflow case.
+    fn sdivrem(&mut self, ty: &str, d: &str, n: &str, floored: bool) -> (String, String) {
+        self.guard_div_zero(ty, n);
+        let is_m1 = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = icmp eq {} {}, -1", is_m1, ty, n));
+        let safe_n = self.b.fresh_tmp();
+        self.b.emit_line(&format!(
+            "  {} = select i1 {}, {} 1, {} {}",
+            safe_n, is_m1, ty, ty, n
+        ));
+        let q0 = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = sdiv {} {}, {}", q0, ty, d, safe_n));
+        let r0 = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = srem {} {}, {}", r0, ty, d, safe_n));
+        let neg_d = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = sub {} 0, {}", neg_d, ty, d));
+        let mut q = self.b.fresh_tmp();
+        self.b.emit_line(&format!(
+            "  {} = select i1 {}, {} {}, {} {}",
+            q, is_m1, ty, neg_d, ty, q0
+        ));
+        let mut r = self.b.fresh_tmp();
+        self.b.emit_line(&format!(
+            "  {} = select i1 {}, {} 0, {} {}",
+            r, is_m1, ty, ty, r0
+        ));
+        if floored {
+            // Adjust toward negative infinity when the remainder's sign
+            // differs from the divisor's.
+            let r_nz = self.b.fresh_tmp();
+            self.b
+                .emit_line(&format!("  {} = icmp ne {} {}, 0", r_nz, ty, r));
+            let x = self.b.fresh_tmp();
+            self.b
+                .emit_line(&format!("  {} = xor {} {}, {}", x, ty, r, n));
+            let diff = self.b.fresh_tmp();
+            self.b
+                .emit_line(&format!("  {} = icmp slt {} {}, 0", diff, ty, x));
+            let adj = self.b.fresh_tmp();
+            self.b
+                .emit_line(&format!("  {} = and i1 {}, {}", adj, r_nz, diff));
+            let q1 = self.b.fresh_tmp();
+            self.b.emit_line(&format!("  {} = sub {} {}, 1", q1, ty, q));
+            let r1 = self.b.fresh_tmp();
+            self.b
+                .emit_line(&format!("  {} = add {} {}, {}", r1, ty, r, n));
+            let qf = self.b.fresh_tmp();
+            self.b.emit_line(&format!(
+                "  {} = select i1 {}, {} {}, {} {}",
+                qf, adj, ty, q1, ty, q
+            ));
+            let rf = self.b.fresh_tmp();
+            self.b.emit_line(&format!(
+                "  {} = select i1 {}, {} {}, {} {}",
+                rf, adj, ty, r1, ty, r
+            ));
+            q = qf;
+            r = rf;
         }
+        (r, q)
     }
 
-    fn end_then(&mut self) -> Result<(), String> {
-        match self.ctrl.pop() {
-            Some(Control::If {
-                else_lbl,
-                end_lbl,
-                has_else,
-                ..
-            }) => {
-                // if there was no ELSE, else_lbl is the end target
-                if !has_else {
-                    self.b.emit_line(&format!("  br label %{}", end_lbl));
-                    self.b.emit_line(&format!("{}:", else_lbl));
-                    self.b.emit_line(&format!("  br label %{}", end_lbl));
-                } else {
-                    self.b.emit_line(&format!("  br label %{}", end_lbl));
-                }
-                self.b.emit_line(&format!("{}:", end_lbl));
-                Ok(())
-            }
-            _ => Err("THEN without IF".into()),
-        }
+    // i64 division returning (remainder, quotient) truncated to cells.
+    fn div64(&mut self, d: &str, n: &str, floored: bool) -> (String, String) {
+        let (r, q) = self.sdivrem("i64", d, n, floored);
+        let r32 = self.trunc32(&r);
+        let q32 = self.trunc32(&q);
+        (r32, q32)
     }
 
-    fn begin_begin(&mut self) {
-        let begin_lbl = self.b.fresh_lbl("begin");
-        self.b.emit_line(&format!("  br label %{}", begin_lbl));
-        self.b.emit_line(&format!("{}:", begin_lbl));
-        self.ctrl.push(Control::Begin {
-            begin_lbl,
-            while_false_lbl: None,
-            while_true_lbl: None,
-        });
+    fn udivrem(&mut self, ty: &str, d: &str, n: &str) -> (String, String) {
+        self.guard_div_zero(ty, n);
+        let r = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = urem {} {}, {}", r, ty, d, n));
+        let q = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = udiv {} {}, {}", q, ty, d, n));
+        (r, q)
     }
 
-    fn begin_while(&mut self) -> Result<(), String> {
-        // WHILE must be inside BEGIN ... REPEAT
-        let cond = self.pop_i32();
-        if !matches!(self.ctrl.last(), Some(Control::Begin { .. })) {
-            return Err("WHILE without matching BEGIN".into());
+    fn star_slash(&mut self, push_rem: bool) {
+        // */ and */MOD: ( n1 n2 n3 -- [rem] quot ) with an i64 product.
+        let n3 = self.pop_i32();
+        let n2 = self.pop_i32();
+        let n1 = self.pop_i32();
+        let a = self.sext64(&n1);
+        let b = self.sext64(&n2);
+        let d = self.b.fresh_tmp();
+        self.b.emit_line(&format!("  {} = mul i64 {}, {}", d, a, b));
+        let n = self.sext64(&n3);
+        let floored = self.division == DivisionMode::Floored;
+        let (rem, quo) = self.div64(&d, &n, floored);
+        if push_rem {
+            self.push_i32(&rem);
         }
+        self.push_i32(&quo);
+    }
 
-        let true_lbl = self.b.fresh_lbl("while_true");
-        let false_lbl = self.b.fresh_lbl("while_false");
-        self.emit_br_cond_zero_to(&cond, &false_lbl, &true_lbl);
-        self.b.emit_line(&format!("{}:", true_lbl));
-
-        match self.ctrl.last_mut() {
-            Some(Control::Begin {
-                begin_lbl: _,
-                while_false_lbl,
-                while_true_lbl,
-            }) => {
-                *while_true_lbl = Some(true_lbl);
-                *while_false_lbl = Some(false_lbl);
-                Ok(())
-            }
-            _ => unreachable!(),
-        }
+    fn pop_double(&mut self) -> String {
+        // ( lo hi -- ) as one i64
+        let hi = self.pop_i32();
+        let lo = self.pop_i32();
+        let hi64 = self.sext64(&hi);
+        let hi_sh = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = shl i64 {}, 32", hi_sh, hi64));
+        let lo64 = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = zext i32 {} to i64", lo64, lo));
+        let d = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = or i64 {}, {}", d, hi_sh, lo64));
+        d
     }
 
-    fn end_repeat(&mut self) -> Result<(), String> {
-        match self.ctrl.pop() {
-            Some(Control::Begin {
-                begin_lbl,
-                while_false_lbl,
-                ..
-            }) => {
-                // if WHILE was used, we must close the true-branch back to begin,
-                // and continue at while_false label.
-                if let Some(false_lbl) = while_false_lbl {
-                    self.b.emit_line(&format!("  br label %{}", begin_lbl));
-                    self.b.emit_line(&format!("{}:", false_lbl));
-                } else {
-                    // plain BEGIN ... REPEAT is infinite loop
-                    self.b.emit_line(&format!("  br label %{}", begin_lbl));
-                }
-                Ok(())
-            }
-            _ => Err("REPEAT without BEGIN".into()),
-        }
+    fn mixed_div(&mut self, floored: bool) {
+        // FM/MOD and SM/REM: ( d n -- rem quot )
+        let n32 = self.pop_i32();
+        let d = self.pop_double();
+        let n = self.sext64(&n32);
+        let (rem, quo) = self.div64(&d, &n, floored);
+        self.push_i32(&rem);
+        self.push_i32(&quo);
+    }
+
+    fn push_double(&mut self, d: &str) {
+        // one i64 as ( lo hi )
+        let lo = self.trunc32(d);
+        let sh = self.b.fresh_tmp();
+        self.b.emit_line(&format!("  {} = lshr i64 {}, 32", sh, d));
+        let hi = self.trunc32(&sh);
+        self.push_i32(&lo);
+        self.push_i32(&hi);
+    }
+
+    fn zext64(&mut self, v: &str) -> String {
+        let r = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = zext i32 {} to i64", r, v));
+        r
+    }
+
+    fn um_star(&mut self) {
+        let b = self.pop_i32();
+        let a = self.pop_i32();
+        let a64 = self.zext64(&a);
+        let b64 = self.zext64(&b);
+        let d = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = mul i64 {}, {}", d, a64, b64));
+        self.push_double(&d);
+    }
+
+    fn um_slash_mod(&mut self) {
+        // ( ud u -- urem uquot )
+        let n32 = self.pop_i32();
+        let d = self.pop_double();
+        let n = self.zext64(&n32);
+        let (r, q) = self.udivrem("i64", &d, &n);
+        let r32 = self.trunc32(&r);
+        let q32 = self.trunc32(&q);
+        self.push_i32(&r32);
+        self.push_i32(&q32);
+    }
+
+    fn dbinop(&mut self, op: &str) {
+        let b = self.pop_double();
+        let a = self.pop_double();
+        let r = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = {} i64 {}, {}", r, op, a, b));
+        self.push_double(&r);
+    }
+
+    fn dunop_imm(&mut self, op: &str, imm: i64) {
+        let a = self.pop_double();
+        let r = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = {} i64 {}, {}", r, op, a, imm));
+        self.push_double(&r);
+    }
+
+    fn dcmp(&mut self, pred: &str, against_zero: bool) {
+        let b = if against_zero {
+            "0".to_string()
+        } else {
+            self.pop_double()
+        };
+        let a = self.pop_double();
+        let c = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = icmp {} i64 {}, {}", c, pred, a, b));
+        let z = self.b.fresh_tmp();
+        self.b.emit_line(&format!("  {} = sext i1 {} to i32", z, c));
+        self.push_i32(&z);
+    }
+
+    fn dabs(&mut self) {
+        let a = self.pop_double();
+        let neg = self.b.fresh_tmp();
+        self.b.emit_line(&format!("  {} = sub i64 0, {}", neg, a));
+        let c = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = icmp slt i64 {}, 0", c, a));
+        let r = self.b.fresh_tmp();
+        self.b.emit_line(&format!(
+            "  {} = select i1 {}, i64 {}, i64 {}",
+            r, c, neg, a
+        ));
+        self.push_double(&r);
     }
 
-    fn end_until(&mut self) -> Result<(), String> {
-        let cond = self.pop_i32();
-        match self.ctrl.pop() {
-            Some(Control::Begin { begin_lbl, .. }) => {
-                // UNTIL: loop until cond is true (-1). We'll treat nonzero as true.
-                // If cond == 0 => continue loop.
-                let done_lbl = self.b.fresh_lbl("until_done");
-                let cont_lbl = begin_lbl;
-                // cond==0 -> cont, else -> done
-                let is_zero = self.b.fresh_tmp();
+    fn m_star(&mut self) {
+        let b = self.pop_i32();
+        let a = self.pop_i32();
+        let a64 = self.sext64(&a);
+        let b64 = self.sext64(&b);
+        let d = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = mul i64 {}, {}", d, a64, b64));
+        self.push_double(&d);
+    }
+
+    fn m_plus(&mut self) {
+        let n = self.pop_i32();
+        let d = self.pop_double();
+        let n64 = self.sext64(&n);
+        let r = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = add i64 {}, {}", r, d, n64));
+        self.push_double(&r);
+    }
+
+    // 2@ / 2!: the cell at addr holds the high (top-of-stack) half, addr+4
+    // holds the low half.
+    fn two_fetch(&mut self) {
+        let addr = self.pop_i32();
+        let addr4 = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = add i32 {}, 4", addr4, addr));
+        let lo = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = call i32 @pvar_get(i32 {})", lo, addr4));
+        let hi = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = call i32 @pvar_get(i32 {})", hi, addr));
+        self.push_i32(&lo);
+        self.push_i32(&hi);
+    }
+
+    fn two_store(&mut self) {
+        let addr = self.pop_i32();
+        let hi = self.pop_i32();
+        let lo = self.pop_i32();
+        self.b
+            .emit_line(&format!("  call void @pvar_set(i32 {}, i32 {})", hi, addr));
+        let addr4 = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = add i32 {}, 4", addr4, addr));
+        self.b
+            .emit_line(&format!("  call void @pvar_set(i32 {}, i32 {})", lo, addr4));
+    }
+
+    // --check-memory: rt_mem_check traps on a bad address, naming the word.
+    fn mem_check(&mut self, c: &MemCheck) {
+        let top = self.pop_i32();
+        let addr = match c.addr {
+            MemAddr::Top(0) => top.clone(),
+            MemAddr::Top(k) => {
+                let a = self.b.fresh_tmp();
                 self.b
-                    .emit_line(&format!("  {} = icmp eq i32 {}, 0", is_zero, cond));
-                self.b.emit_line(&format!(
-                    "  br i1 {}, label %{}, label %{}",
-                    is_zero, cont_lbl, done_lbl
-                ));
-                self.b.emit_line(&format!("{}:", done_lbl));
-                Ok(())
+                    .emit_line(&format!("  {} = add i32 {}, {}", a, top, k));
+                a
             }
-            _ => Err("UNTIL without BEGIN".into()),
+            MemAddr::Field => {
+                let obj = self.pop_i32();
+                let a = self.b.fresh_tmp();
+                self.b
+                    .emit_line(&format!("  {} = add i32 {}, {}", a, obj, top));
+                self.push_i32(&obj);
+                a
+            }
+        };
+        self.push_i32(&top);
+        let p = self.cur_word_name_ptr();
+        self.b.emit_line(&format!(
+            "  call void @rt_mem_check(i32 {}, i32 {}, {} {})",
+            addr,
+            c.access as i32,
+            self.b.ptr("i8"),
+            p
+        ));
+    }
+
+    // `/`, `MOD`, `/MOD` (and their unsigned forms) on cells.
+    fn div_mod(&mut self, push_rem: bool, push_quo: bool, unsigned: bool) {
+        let b = self.pop_i32();
+        let a = self.pop_i32();
+        let (rem, quo) = if unsigned {
+            self.udivrem("i32", &a, &b)
+        } else {
+            let floored = self.division == DivisionMode::Floored;
+            self.sdivrem("i32", &a, &b, floored)
+        };
+        // Forth: remainder quotient
+        if push_rem {
+            self.push_i32(&rem);
         }
+        if push_quo {
+            self.push_i32(&quo);
+        }
+    }
+
+    fn zero_eq(&mut self) {
+        let a = self.pop_i32();
+        let c = self.b.fresh_tmp();
+        self.b.emit_line(&format!("  {} = icmp eq i32 {}, 0", c, a));
+        let z = self.b.fresh_tmp();
+        self.b.emit_line(&format!("  {} = zext i1 {} to i32", z, c));
+        let neg = self.b.fresh_tmp();
+        self.b.emit_line(&format!("  {} = sub i32 0, {}", neg, z));
+        self.push_i32(&neg);
+    }
+
+    fn zero_lt(&mut self) {
+        let a = self.pop_i32();
+        let c = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = icmp slt i32 {}, 0", c, a));
+        let z = self.b.fresh_tmp();
+        self.b.emit_line(&format!("  {} = zext i1 {} to i32", z, c));
+        let neg = self.b.fresh_tmp();
+        self.b.emit_line(&format!("  {} = sub i32 0, {}", neg, z));
+        self.push_i32(&neg);
+    }
+
+    // Control flow sugar (IF/ELSE/THEN, BEGIN/UNTIL, BEGIN/WHILE/REPEAT)
+    fn emit_br_cond_zero_to(&mut self, cond_i32: &str, if_zero_lbl: &str, if_nz_lbl: &str) {
+        let c = self.b.fresh_tmp();
+        self.b
+            .emit_line(&format!("  {} = icmp eq i32 {}, 0", c, cond_i32));
+        self.b.emit_line(&format!(
+            "  br i1 {}, label %{}, label %{}",
+            c, if_zero_lbl, if_nz_lbl
+        ));
     }
 
     fn emit_string_global(&mut self, s: &str) -> String {
@@ -783,10 +1657,34 @@ impl<'a> Codegen<'a> {
 
         // Return pointer to first element
         let ptr = self.b.fresh_tmp();
-        self.b.emit_line(&format!(
-            "  {} = getelementptr inbounds [{} x i8], [{} x i8]* @{}, i32 0, i32 0",
-            ptr, n, n, name
-        ));
+        self.b.gep_array_start(&ptr, n, "i8", &format!("@{}", name));
+        ptr
+    }
+
+    // Pointer to the current definition's name, emitted once per definition.
+    fn cur_word_name_ptr(&mut self) -> String {
+        let (name, n) = match self.word_name_globals.get(&self.cur_word) {
+            Some(g) => g.clone(),
+            None => {
+                let n = self.cur_word.len() + 1;
+                let name = format!("name.{}", llvm_word_sym(&self.cur_word));
+                let body: String = self
+                    .cur_word
+                    .bytes()
+                    .chain(std::iter::once(0))
+                    .map(|b| format!("\\{:02X}", b))
+                    .collect();
+                self.b.emit_global_line(&format!(
+                    "@{} = private constant [{} x i8] c\"{}\"",
+                    name, n, body
+                ));
+                self.word_name_globals
+                    .insert(self.cur_word.clone(), (name.clone(), n));
+                (name, n)
+            }
+        };
+        let ptr = self.b.fresh_tmp();
+        self.b.gep_array_start(&ptr, n, "i8", &format!("@{}", name));
         ptr
     }
 
@@ -827,8 +1725,12 @@ impl<'a> Codegen<'a> {
             ExternArgMode::StrVoid => {
                 let s = str_arg.ok_or("Missing string argument for TYPE")?;
                 let p = self.emit_string_global(&s);
-                self.b
-                    .emit_line(&format!("  call void @{}(i8* {})", callee, p));
+                self.b.emit_line(&format!(
+                    "  call void @{}({} {})",
+                    callee,
+                    self.b.ptr("i8"),
+                    p
+                ));
             }
             ExternArgMode::Pop2I32Void => {
                 let b = self.pop_i32();
@@ -855,372 +1757,431 @@ impl<'a> Codegen<'a> {
                     callee, a, b, c
                 ));
             }
+            ExternArgMode::PopI64Void => {
+                let d = self.pop_double();
+                self.b
+                    .emit_line(&format!("  call void @{}(i64 {})", callee, d));
+            }
+            ExternArgMode::PopI64I32Void => {
+                let n = self.pop_i32();
+                let d = self.pop_double();
+                self.b
+                    .emit_line(&format!("  call void @{}(i64 {}, i32 {})", callee, d, n));
+            }
         }
         Ok(())
     }
 
     fn call_word(&mut self, word: &str) {
+        self.flush_pending();
         let word = llvm_word_sym(word);
         self.b.emit_line(&format!(
-            "  call void @{}(i32* {}, i32* {})",
-            word, self.stack_base, self.sp_ptr
+            "  call void @{}({})",
+            word,
+            self.word_args(self.stack_base, self.sp_ptr)
         ));
     }
 
-    fn try_emit_native_pascal_routine(&mut self, alias: Option<&str>) -> Result<bool, String> {
-        let Some(alias) = alias else {
-            return Ok(false);
-        };
-        let key = match alias {
-            "program::abs" => Some(("__KP_FABS_F32", ExternArgMode::PopI32RetI32Push)),
-            "program::sqrt" => Some(("__KP_FSQRT_F32", ExternArgMode::PopI32RetI32Push)),
-            "program::sin" => Some(("__KP_FSIN_F32", ExternArgMode::PopI32RetI32Push)),
-            "program::cos" => Some(("__KP_FCOS_F32", ExternArgMode::PopI32RetI32Push)),
-            "program::pow" => Some(("__KP_FPOW_F32_I32", ExternArgMode::Pop2I32RetI32Push)),
-            "program::floor" => Some(("__KP_FFLOOR_F32", ExternArgMode::PopI32RetI32Push)),
-            "program::ceil" => Some(("__KP_FCEIL_F32", ExternArgMode::PopI32RetI32Push)),
-
-            "program::fx_sqrt" => Some(("__KP_FX_SQRT", ExternArgMode::PopI32RetI32Push)),
-            "program::fx_sin" => Some(("__KP_FX_SIN", ExternArgMode::PopI32RetI32Push)),
-            "program::fx_cos" => Some(("__KP_FX_COS", ExternArgMode::PopI32RetI32Push)),
-            "program::fx_tan" => Some(("__KP_FX_TAN", ExternArgMode::PopI32RetI32Push)),
-            "program::fx_asin" => Some(("__KP_FX_ASIN", ExternArgMode::PopI32RetI32Push)),
-            "program::fx_acos" => Some(("__KP_FX_ACOS", ExternArgMode::PopI32RetI32Push)),
-            "program::fx_atan" => Some(("__KP_FX_ATAN", ExternArgMode::PopI32RetI32Push)),
-            "program::fx_ln" => Some(("__KP_FX_LN", ExternArgMode::PopI32RetI32Push)),
-            "program::fx_log" => Some(("__KP_FX_LOG", ExternArgMode::PopI32RetI32Push)),
-            _ => None,
-        };
-        if let Some((word, mode)) = key {
-            self.call_extern(word, mode, None)?;
-            return Ok(true);
-        }
-        Ok(false)
-    }
-
-    fn compile_body(&mut self, toks: &[Tok]) -> Result<(), String> {
-        let mut i = 0usize;
-        while i < toks.len() {
-            match &toks[i] {
-                Tok::Num(v) => self.push_i32(&format!("{}", v)),
-                Tok::Str(s) => {
-                    // Compile-time handling for a few bootstrap-style immediate string consumers.
-                    if i + 1 >= toks.len() {
-                        return Err("S\" must be followed by a word (e.g., TYPE)".into());
-                    }
-                    match &toks[i + 1] {
-                        Tok::Word(w) if w == "TYPE" => {
-                            self.call_extern("TYPE", ExternArgMode::StrVoid, Some(s.clone()))?;
-                            i += 1; // consume following word
-                        }
-                        Tok::Word(w) if w == "READ-F32" || w == "FNUMBER?" => {
-                            if let Some(bits) = parse_f32_token_bits(s) {
-                                self.push_i32(&bits.to_string());
-                                self.push_i32("-1");
-                            } else {
-                                self.push_i32("0");
-                            }
-                            i += 1; // consume following word
-                        }
-                        _ => return Err(
-                            "S\" currently only supported as: S\" ...\" TYPE / READ-F32 / FNUMBER?"
-                                .into(),
-                        ),
-                    }
+    fn emit_func(&mut self, f: &kir::Func) -> Result<(), String> {
+        self.begin_func(f);
+        for (id, block) in f.blocks.iter().enumerate() {
+            if id > 0 {
+                self.b.emit_line(&format!("{}:", f.label(id)));
+            }
+            for inst in &block.insts {
+                self.emit_inst(inst)?;
+            }
+            match &block.term {
+                Term::Return => {
+                    self.flush_pending();
+                    self.b.emit_line("  ret void");
                 }
-                Tok::Word(w) => {
-                    if let Some(v) = self.constant_words.get(w) {
-                        self.push_i32(&v.to_string());
-                        i += 1;
-                        continue;
-                    }
-                    if let Some(addr) = self.created_words.get(w) {
-                        self.push_i32(&addr.to_string());
-                        i += 1;
-                        continue;
-                    }
-                    match w.as_str() {
-                        // stack ops
-                        "DUP" => self.dup(),
-                        "DROP" => self.drop(),
-                        "SWAP" => {
-                            let b = self.pop_i32();
-                            let a = self.pop_i32();
-                            self.push_i32(&b);
-                            self.push_i32(&a);
-                        }
-                        "OVER" => {
-                            let b = self.pop_i32();
-                            let a = self.pop_i32();
-                            self.push_i32(&a);
-                            self.push_i32(&b);
-                            self.push_i32(&a);
-                        }
-                        ">R" => {
-                            let v = self.pop_i32();
-                            self.rpush_i32(&v);
-                        }
-                        "R>" => {
-                            let v = self.rpop_i32();
-                            self.push_i32(&v);
-                        }
-                        "R@" => {
-                            let v = self.rpeek_i32();
-                            self.push_i32(&v);
-                        }
-
-                        // arithmetic / logic (wrap semantics by default)
-                        "+" => self.binop("add"),
-                        "-" => self.binop("sub"),
-                        "*" => self.binop("mul"),
-                        "/" => self.div_mod(false),
-                        "MOD" => self.div_mod(true),
-
-                        "NEGATE" => self.unary_negate(),
-                        "AND" => self.and(),
-                        "OR" => self.binop("or"),
-                        "XOR" => self.binop("xor"),
-                        "LSHIFT" => {
-                            let b = self.pop_i32();
-                            let a = self.pop_i32();
-                            let sh = self.b.fresh_tmp();
-                            self.b.emit_line(&format!("  {} = and i32 {}, 31", sh, b));
-                            let r = self.b.fresh_tmp();
-                            self.b
-                                .emit_line(&format!("  {} = shl i32 {}, {}", r, a, sh));
-                            self.push_i32(&r);
-                        }
-                        "RSHIFT" => {
-                            let b = self.pop_i32();
-                            let a = self.pop_i32();
-                            let sh = self.b.fresh_tmp();
-                            self.b.emit_line(&format!("  {} = and i32 {}, 31", sh, b));
-                            let r = self.b.fresh_tmp();
-                            self.b
-                                .emit_line(&format!("  {} = lshr i32 {}, {}", r, a, sh));
-                            self.push_i32(&r);
-                        }
-                        "/MOD" => {
-                            let b = self.pop_i32();
-                            let a = self.pop_i32();
-                            let rem = self.b.fresh_tmp();
-                            let quo = self.b.fresh_tmp();
-                            self.b
-                                .emit_line(&format!("  {} = srem i32 {}, {}", rem, a, b));
-                            self.b
-                                .emit_line(&format!("  {} = sdiv i32 {}, {}", quo, a, b));
-                            // Forth: remainder quotient
-                            self.push_i32(&rem);
-                            self.push_i32(&quo);
-                        }
-
-                        // comparisons: return -1/0
-                        "=" => self.cmp_to_bool_minus1("eq"),
-                        "<>" => self.cmp_to_bool_minus1("ne"),
-                        "<" => self.cmp_to_bool_minus1("slt"),
-                        "<=" => self.cmp_to_bool_minus1("sle"),
-                        ">" => self.cmp_to_bool_minus1("sgt"),
-                        ">=" => self.cmp_to_bool_minus1("sge"),
-                        "0=" => self.zero_eq(),
-                        "0<" => self.zero_lt(),
-
-                        // control sugar
-                        "IF" => self.begin_if()?,
-                        "ELSE" => self.do_else()?,
-                        "THEN" => self.end_then()?,
-                        "BEGIN" => self.begin_begin(),
-                        "UNTIL" => self.end_until()?,
-                        "WHILE" => self.begin_while()?,
-                        "REPEAT" => self.end_repeat()?,
-
-                        // service calls (extern)
-                        "PWRITE-I32" => {
-                            self.call_extern("PWRITE-I32", ExternArgMode::PopI32Void, None)?
-                        }
-                        "." => self.call_extern("PWRITE-I32", ExternArgMode::PopI32Void, None)?,
-                        "PWRITE-BOOL" => {
-                            self.call_extern("PWRITE-BOOL", ExternArgMode::PopI32Void, None)?
-                        }
-                        "PWRITE-CHAR" => {
-                            self.call_extern("PWRITE-CHAR", ExternArgMode::PopI32Void, None)?
-                        }
-                        "EMIT" => {
-                            self.call_extern("PWRITE-CHAR", ExternArgMode::PopI32Void, None)?
-                        }
-                        "PWRITELN" => self.call_extern("PWRITELN", ExternArgMode::Void, None)?,
-                        "PWRITE-HEX" => {
-                            self.call_extern("PWRITE-HEX", ExternArgMode::PopI32Void, None)?
-                        }
-
-                        "PREAD-I32" => {
-                            self.call_extern("PREAD-I32", ExternArgMode::RetI32Push, None)?
-                        }
-                        "PREAD-BOOL" => {
-                            self.call_extern("PREAD-BOOL", ExternArgMode::RetI32Push, None)?
-                        }
-                        "PREAD-CHAR" => {
-                            self.call_extern("PREAD-CHAR", ExternArgMode::RetI32Push, None)?
-                        }
-                        "PREADLN" => self.call_extern("PREADLN", ExternArgMode::Void, None)?,
-
-                        "PBOOL" => {
-                            self.call_extern("PBOOL", ExternArgMode::PopI32RetI32Push, None)?
-                        }
-                        "PVAR!" => self.call_extern("PVAR!", ExternArgMode::Pop2I32Void, None)?,
-                        "PVAR@" => {
-                            self.call_extern("PVAR@", ExternArgMode::PopI32RetI32Push, None)?
-                        }
-                        "PFIELD!" => {
-                            self.call_extern("PFIELD!", ExternArgMode::Pop3I32Void, None)?
-                        }
-                        "PFIELD@" => {
-                            self.call_extern("PFIELD@", ExternArgMode::Pop2I32RetI32Push, None)?
-                        }
-
-                        // Float32-on-cell words from bootstrap treated as primitives.
-                        "PREAD-F32" => {
-                            self.call_extern("PREAD-F32", ExternArgMode::RetI32Push, None)?
-                        }
-                        "FADD" => {
-                            self.call_extern("FADD", ExternArgMode::Pop2I32RetI32Push, None)?
-                        }
-                        "FSUB" => {
-                            self.call_extern("FSUB", ExternArgMode::Pop2I32RetI32Push, None)?
-                        }
-                        "FMUL" => {
-                            self.call_extern("FMUL", ExternArgMode::Pop2I32RetI32Push, None)?
-                        }
-                        "FDIV" => {
-                            self.call_extern("FDIV", ExternArgMode::Pop2I32RetI32Push, None)?
-                        }
-                        "FNEGATE" => {
-                            self.call_extern("FNEGATE", ExternArgMode::PopI32RetI32Push, None)?
-                        }
-                        "FABS" => {
-                            self.call_extern("FABS", ExternArgMode::PopI32RetI32Push, None)?
-                        }
-                        "F=" => self.call_extern("F=", ExternArgMode::Pop2I32RetI32Push, None)?,
-                        "F<" => self.call_extern("F<", ExternArgMode::Pop2I32RetI32Push, None)?,
-                        "F<=" => self.call_extern("F<=", ExternArgMode::Pop2I32RetI32Push, None)?,
-                        "FZERO?" | "F0=" => {
-                            self.call_extern("FZERO?", ExternArgMode::PopI32RetI32Push, None)?
-                        }
-                        "FINF?" => {
-                            self.call_extern("FINF?", ExternArgMode::PopI32RetI32Push, None)?
-                        }
-                        "FNAN?" => {
-                            self.call_extern("FNAN?", ExternArgMode::PopI32RetI32Push, None)?
-                        }
-                        "FFINITE?" => {
-                            self.call_extern("FFINITE?", ExternArgMode::PopI32RetI32Push, None)?
-                        }
-                        "S>F" => self.call_extern("S>F", ExternArgMode::PopI32RetI32Push, None)?,
-                        "F>S" => self.call_extern("F>S", ExternArgMode::PopI32RetI32Push, None)?,
-                        "Q16.16>F" => {
-                            self.call_extern("Q16.16>F", ExternArgMode::PopI32RetI32Push, None)?
-                        }
-                        "F>Q16.16" => {
-                            self.call_extern("F>Q16.16", ExternArgMode::PopI32RetI32Push, None)?
-                        }
-                        "FROUND-I32" => {
-                            self.call_extern("FROUND-I32", ExternArgMode::PopI32RetI32Push, None)?
-                        }
-                        "F." | "WRITE-F32" | "PWRITE-F32" => {
-                            self.call_extern("PWRITE-F32", ExternArgMode::PopI32Void, None)?
-                        }
-                        "F+INF" => self.push_i32(&(f32::INFINITY.to_bits() as i32).to_string()),
-                        "F-INF" => self.push_i32(&(f32::NEG_INFINITY.to_bits() as i32).to_string()),
-                        "FNAN" => self.push_i32(&(f32::NAN.to_bits() as i32).to_string()),
-
-                        // Minimal compile-time dictionary words used by generated IL.
-                        "CONSTANT" => {
-                            let val = resolve_prev_compile_time_value(
-                                toks,
-                                i,
-                                self.here,
-                                &self.constant_words,
-                                &self.created_words,
-                            )
-                            .ok_or_else(|| {
-                                "CONSTANT currently requires a compile-time value before it"
-                                    .to_string()
-                            })?;
-                            let _ = self.pop_i32();
-                            let name = match toks.get(i + 1) {
-                                Some(Tok::Word(name)) => name.clone(),
-                                _ => return Err("CONSTANT requires a following name".into()),
-                            };
-                            self.constant_words.insert(name, val);
-                            i += 1; // consume name
-                        }
-                        "CREATE" => {
-                            let name = match toks.get(i + 1) {
-                                Some(Tok::Word(name)) => name.clone(),
-                                _ => return Err("CREATE requires a following name".into()),
-                            };
-                            self.created_words.insert(name, self.here);
-                            i += 1; // consume name
-                        }
-                        "HERE" => self.call_extern("HERE", ExternArgMode::RetI32Push, None)?,
-                        "," => {
-                            // Forth comma allocates one 32-bit cell (4 bytes).
-                            let _ = self.pop_i32();
-                            self.here = self.here.wrapping_add(4);
-                        }
-                        "ALLOT" => self.call_extern("ALLOT", ExternArgMode::PopI32Void, None)?,
-
-                        _ if self.known_defs.contains(w) => self.call_word(w),
-                        _ => return Err(format!("Unknown word: {}", w)),
-                    }
+                Term::TailCall(w) if *w == f.name => {
+                    self.flush_pending();
+                    self.b.emit_line("  br label %start");
                 }
-                Tok::Colon | Tok::Semi => {
-                    return Err(
-                        "Unexpected ':' or ';' inside body (top-level parser should split defs)"
-                            .into(),
-                    )
+                Term::TailCall(w) => {
+                    self.flush_pending();
+                    self.b.emit_line(&format!(
+                        "  musttail call void @{}({})",
+                        llvm_word_sym(w),
+                        self.word_args(self.stack_base, self.sp_ptr)
+                    ));
+                    self.b.emit_line("  ret void");
+                }
+                Term::Jump(t) => {
+                    self.flush_pending();
+                    self.b.emit_line(&format!("  br label %{}", f.label(*t)));
+                }
+                Term::Branch { nonzero, zero } => {
+                    let cond = self.pop_i32();
+                    self.flush_pending();
+                    self.emit_br_cond_zero_to(&cond, &f.label(*zero), &f.label(*nonzero));
                 }
             }
-            i += 1;
         }
+        self.end_func();
+        Ok(())
+    }
 
-        if !self.ctrl.is_empty() {
-            return Err(format!("Unclosed control structure(s): {:?}", self.ctrl));
+    fn emit_inst(&mut self, inst: &Inst) -> Result<(), String> {
+        match inst {
+            Inst::Lit(v) => self.push_i32(&v.to_string()),
+            Inst::Op(op) => self.emit_op(*op),
+            Inst::PickConst(n) => self.pick(&n.to_string()),
+            Inst::RollConst(n) => self.roll_const(*n),
+            Inst::Call(w) => self.call_word(w),
+            Inst::Service(svc) => self.call_extern(svc.name, svc.mode, None)?,
+            Inst::Type(s) => self.call_extern("TYPE", ExternArgMode::StrVoid, Some(s.clone()))?,
+            Inst::Line(n) => {
+                if let Some(d) = &mut self.debug {
+                    self.b.dbg = Some(d.location(*n));
+                }
+            }
+            Inst::MemCheck(c) => self.mem_check(c),
         }
         Ok(())
     }
+
+    fn emit_op(&mut self, op: Op) {
+        match op {
+            // stack ops
+            Op::Dup => self.dup(),
+            Op::Drop => self.drop(),
+            Op::Swap => {
+                let b = self.pop_i32();
+                let a = self.pop_i32();
+                self.push_i32(&b);
+                self.push_i32(&a);
+            }
+            Op::Over => {
+                let b = self.pop_i32();
+                let a = self.pop_i32();
+                self.push_i32(&a);
+                self.push_i32(&b);
+                self.push_i32(&a);
+            }
+            Op::Rot => self.permute(3, &[1, 2, 0]),
+            Op::MinusRot => self.permute(3, &[2, 0, 1]),
+            Op::Nip => self.permute(2, &[1]),
+            Op::Tuck => self.permute(2, &[1, 0, 1]),
+            Op::QDup => self.qdup(),
+            Op::TwoDup => self.permute(2, &[0, 1, 0, 1]),
+            Op::TwoDrop => self.permute(2, &[]),
+            Op::TwoSwap => self.permute(4, &[2, 3, 0, 1]),
+            Op::TwoOver => self.permute(4, &[0, 1, 2, 3, 0, 1]),
+            Op::Pick => {
+                let n = self.pop_i32();
+                self.pick(&n);
+            }
+            Op::Roll => self.roll_dynamic(),
+            Op::Depth => {
+                self.flush_pending();
+                let sp = self.load_sp();
+                self.push_i32(&sp);
+            }
+            Op::ToR => {
+                let v = self.pop_i32();
+                self.rpush_i32(&v);
+            }
+            Op::TwoToR => {
+                let b = self.pop_i32();
+                let a = self.pop_i32();
+                self.rpush_i32(&a);
+                self.rpush_i32(&b);
+            }
+            Op::TwoRFrom => {
+                let b = self.rpop_i32();
+                let a = self.rpop_i32();
+                self.push_i32(&a);
+                self.push_i32(&b);
+            }
+            Op::TwoRFetch => {
+                let b = self.rpop_i32();
+                let a = self.rpeek_i32();
+                self.rpush_i32(&b);
+                self.push_i32(&a);
+                self.push_i32(&b);
+            }
+            Op::RFrom => {
+                let v = self.rpop_i32();
+                self.push_i32(&v);
+            }
+            Op::RFetch => {
+                let v = self.rpeek_i32();
+                self.push_i32(&v);
+            }
+
+            // arithmetic / logic (wrap semantics by default)
+            Op::Add => self.binop("add"),
+            Op::Sub => self.binop("sub"),
+            Op::Mul => self.binop("mul"),
+            Op::Div => self.div_mod(false, true, false),
+            Op::Mod => self.div_mod(true, false, false),
+            Op::DivMod => self.div_mod(true, true, false),
+            Op::Cells => {
+                self.push_i32("4");
+                self.binop("mul");
+            }
+            Op::CellPlus => {
+                self.push_i32("4");
+                self.binop("add");
+            }
+            Op::OnePlus => self.binop_imm("add", 1),
+            Op::OneMinus => self.binop_imm("sub", 1),
+            Op::TwoStar => self.binop_imm("shl", 1),
+            Op::TwoSlash => self.binop_imm("ashr", 1),
+            Op::Abs => self.abs(),
+            Op::Min => self.select_cmp("slt"),
+            Op::Max => self.select_cmp("sgt"),
+            Op::Invert => self.binop_imm("xor", -1),
+            Op::StarSlash => self.star_slash(false),
+            Op::StarSlashMod => self.star_slash(true),
+            Op::FmMod => self.mixed_div(true),
+            Op::SmRem => self.mixed_div(false),
+            Op::Arshift => self.shift("ashr"),
+            Op::SToD => {
+                let a = self.pop_i32();
+                let d = self.sext64(&a);
+                self.push_double(&d);
+            }
+            Op::DAdd => self.dbinop("add"),
+            Op::DSub => self.dbinop("sub"),
+            Op::DNegate => {
+                let a = self.pop_double();
+                let r = self.b.fresh_tmp();
+                self.b.emit_line(&format!("  {} = sub i64 0, {}", r, a));
+                self.push_double(&r);
+            }
+            Op::DAbs => self.dabs(),
+            Op::DEq => self.dcmp("eq", false),
+            Op::DLt => self.dcmp("slt", false),
+            Op::DZeroEq => self.dcmp("eq", true),
+            Op::MStar => self.m_star(),
+            Op::MPlus => self.m_plus(),
+            Op::DTwoStar => self.dunop_imm("shl", 1),
+            Op::DTwoSlash => self.dunop_imm("ashr", 1),
+            Op::TwoFetch => self.two_fetch(),
+            Op::TwoStore => self.two_store(),
+            Op::UDiv => self.div_mod(false, true, true),
+            Op::UMod => self.div_mod(true, false, true),
+            Op::UmStar => self.um_star(),
+            Op::UmSlashMod => self.um_slash_mod(),
+            Op::Negate => self.unary_negate(),
+            Op::And => self.and(),
+            Op::Or => self.binop("or"),
+            Op::Xor => self.binop("xor"),
+            Op::Lshift => self.shift("shl"),
+            Op::Rshift => self.shift("lshr"),
+
+            // comparisons: return -1/0
+            Op::Eq => self.cmp_to_bool_minus1("eq"),
+            Op::Ne => self.cmp_to_bool_minus1("ne"),
+            Op::Lt => self.cmp_to_bool_minus1("slt"),
+            Op::Le => self.cmp_to_bool_minus1("sle"),
+            Op::Gt => self.cmp_to_bool_minus1("sgt"),
+            Op::Ge => self.cmp_to_bool_minus1("sge"),
+            Op::ULt => self.cmp_to_bool_minus1("ult"),
+            Op::UGt => self.cmp_to_bool_minus1("ugt"),
+            Op::ZeroEq => self.zero_eq(),
+            Op::ZeroLt => self.zero_lt(),
+        }
+    }
+}
+
+struct DefiningWord {
+    // Tokens between the start of the definition and DOES>; run at compile
+    // time each time a child word is defined.
+    create_body: Vec<Tok>,
+    // Synthesized definition holding the DOES> tokens.
+    does_def: String,
+}
+
+// A definition body; `lines` holds the source line of each token.
+#[derive(Debug, Clone, Default)]
+struct Body {
+    toks: Vec<Tok>,
+    lines: Vec<u32>,
+    // Line of the definition's `:`.
+    line: u32,
 }
 
-#[derive(Copy, Clone)]
-enum ExternArgMode {
-    PopI32Void,
-    Void,
-    RetI32Push,
-    PopI32RetI32Push,
-    StrVoid,
-    Pop2I32Void,
-    Pop2I32RetI32Push,
-    Pop3I32Void,
+impl Body {
+    fn push(&mut self, tok: Tok, line: u32) {
+        self.toks.push(tok);
+        self.lines.push(line);
+    }
+
+    fn extend_from(&mut self, other: &Body, range: std::ops::Range<usize>) {
+        self.toks.extend_from_slice(&other.toks[range.clone()]);
+        self.lines.extend_from_slice(&other.lines[range]);
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.toks.truncate(len);
+        self.lines.truncate(len);
+    }
 }
 
 struct ParsedProgram {
-    defs: Vec<(String, Vec<Tok>)>,
+    defs: Vec<(String, Body)>,
     created_words: HashMap<String, i32>,
     constant_words: HashMap<String, i32>,
+    // 2CONSTANT name -> (lo, hi)
+    double_constant_words: HashMap<String, (i32, i32)>,
+    value_words: HashMap<String, i32>,
+    // byte address -> initial cell value for statically initialized storage
+    static_inits: BTreeMap<i32, i32>,
+    defining_words: HashMap<String, DefiningWord>,
+    // child word -> (data address, DOES> definition)
+    does_words: HashMap<String, (i32, String)>,
     here: i32,
     entry_call: Option<String>,
+    division: DivisionMode,
+    // Definitions marked with a top-level `INLINE` after their `;`.
+    inline_words: HashSet<String>,
+}
+
+// Compile-time view of a top-level symbol (literal-like words only).
+fn ct_symbol_value(p: &ParsedProgram, w: &str) -> Option<i32> {
+    if w == "HERE" {
+        return Some(p.here);
+    }
+    p.constant_words
+        .get(w)
+        .copied()
+        .or_else(|| p.created_words.get(w).copied())
+}
+
+fn run_defining_word(
+    p: &mut ParsedProgram,
+    ct: &mut CtMachine,
+    word: &str,
+    child: &str,
+) -> Result<(), String> {
+    let (create_body, does_def) = match p.defining_words.get(word) {
+        Some(d) => (d.create_body.clone(), d.does_def.clone()),
+        None => return Err(format!("{} is not a defining word", word)),
+    };
+    let mut created = false;
+    for tok in &create_body {
+        match tok {
+            Tok::Num(v) => ct.push(*v),
+            Tok::Word(w) if w == "CREATE" => {
+                if created {
+                    return Err(format!("Defining word {} uses CREATE more than once", word));
+                }
+                p.does_words
+                    .insert(child.to_string(), (p.here, does_def.clone()));
+                created = true;
+            }
+            Tok::Word(w) if w == "," => {
+                let v = ct.pop(w)?;
+                p.static_inits.insert(p.here, v);
+                p.here = p.here.wrapping_add(4);
+            }
+            Tok::Word(w) if w == "ALLOT" => {
+                let n = ct.pop(w)?;
+                p.here = p.here.wrapping_add(n);
+            }
+            Tok::Word(w) => {
+                if ct.exec_pure(w)? {
+                    continue;
+                }
+                match ct_symbol_value(p, w) {
+                    Some(v) => ct.push(v),
+                    None => {
+                        return Err(format!(
+                            "Word {} is not supported at compile time in defining word {}",
+                            w, word
+                        ))
+                    }
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "Unsupported token in defining word {} before DOES>",
+                    word
+                ))
+            }
+        }
+    }
+    if !created {
+        return Err(format!("Defining word {} did not CREATE", word));
+    }
+    Ok(())
 }
 
-fn parse_program(toks: &[Tok]) -> Result<ParsedProgram, String> {
-    let mut defs = Vec::new();
-    let mut created_words = HashMap::new();
-    let mut constant_words = HashMap::new();
-    let mut here: i32 = 0;
-    let mut entry_call: Option<String> = None;
+// Runs a DOES> child at top-level: its data address, then the DOES> body,
+// which must consist of compile-time words.
+fn run_does_word(p: &ParsedProgram, ct: &mut CtMachine, child: &str) -> Result<(), String> {
+    let (addr, does_def) = &p.does_words[child];
+    let body = p
+        .defs
+        .iter()
+        .find(|(name, _)| name == does_def)
+        .map(|(_, body)| body)
+        .ok_or_else(|| format!("Missing DOES> body for {}", child))?;
+    ct.push(*addr);
+    for tok in &body.toks {
+        match tok {
+            Tok::Num(v) => ct.push(*v),
+            Tok::Word(w) => {
+                if ct.exec_pure(w)? {
+                    continue;
+                }
+                match ct_symbol_value(p, w) {
+                    Some(v) => ct.push(v),
+                    None => {
+                        return Err(format!(
+                            "Word {} is not supported at compile time in {} at top-level",
+                            w, child
+                        ))
+                    }
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "Unsupported token in DOES> body of {} at top-level",
+                    child
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_program(
+    toks: &[Tok],
+    lines: &[u32],
+    division: DivisionMode,
+) -> Result<ParsedProgram, String> {
+    let mut p = ParsedProgram {
+        defs: Vec::new(),
+        created_words: HashMap::new(),
+        constant_words: HashMap::new(),
+        double_constant_words: HashMap::new(),
+        value_words: HashMap::new(),
+        static_inits: BTreeMap::new(),
+        defining_words: HashMap::new(),
+        does_words: HashMap::new(),
+        here: 0,
+        entry_call: None,
+        division,
+        inline_words: HashSet::new(),
+    };
+    // Top-level code is interpreted at compile time; layout words consume
+    // values from this stack.
+    let mut ct = CtMachine::new(division);
     let mut i = 0usize;
 
     while i < toks.len() {
         match &toks[i] {
             Tok::Colon => {
+                let mut body = Body {
+                    line: lines[i],
+                    ..Body::default()
+                };
                 i += 1;
                 let name = match toks.get(i) {
                     Some(Tok::Word(w)) => w.clone(),
@@ -1228,26 +2189,58 @@ fn parse_program(toks: &[Tok]) -> Result<ParsedProgram, String> {
                 };
                 i += 1;
 
-                let mut body = Vec::new();
                 while i < toks.len() {
-                    if matches!(toks[i], Tok::Semi) {
-                        break;
+                    match toks[i] {
+                        Tok::Semi => break,
+                        // `: NAME ( INLINE ) ...` marks NAME for inlining.
+                        Tok::InlineHint => {
+                            p.inline_words.insert(name.clone());
+                        }
+                        _ => body.push(toks[i].clone(), lines[i]),
                     }
-                    body.push(toks[i].clone());
                     i += 1;
                 }
                 if i >= toks.len() || !matches!(toks[i], Tok::Semi) {
                     return Err(format!("Definition {} missing ';'", name));
                 }
                 i += 1; // consume ';'
-                defs.push((name, body));
+
+                let does_pos = body
+                    .toks
+                    .iter()
+                    .position(|t| matches!(t, Tok::Word(w) if w == "DOES>"));
+                if let Some(pos) = does_pos {
+                    let create_body = body.toks[..pos].to_vec();
+                    if !create_body
+                        .iter()
+                        .any(|t| matches!(t, Tok::Word(w) if w == "CREATE"))
+                    {
+                        return Err(format!("DOES> without CREATE in definition {}", name));
+                    }
+                    let does_def = format!("{} DOES>", name);
+                    let mut does_body = Body {
+                        line: body.lines[pos],
+                        ..Body::default()
+                    };
+                    does_body.extend_from(&body, pos + 1..body.toks.len());
+                    p.defs.push((does_def.clone(), does_body));
+                    p.defining_words.insert(
+                        name,
+                        DefiningWord {
+                            create_body,
+                            does_def,
+                        },
+                    );
+                } else {
+                    p.defs.push((name, body));
+                }
             }
             Tok::Word(w) if w == "CREATE" => {
                 let name = match toks.get(i + 1) {
                     Some(Tok::Word(name)) => name.clone(),
                     _ => return Err("CREATE requires a following name at top-level".into()),
                 };
-                created_words.insert(name, here);
+                p.created_words.insert(name, p.here);
                 i += 2;
             }
             Tok::Word(w) if w == "VARIABLE" => {
@@ -1255,109 +2248,565 @@ fn parse_program(toks: &[Tok]) -> Result<ParsedProgram, String> {
                     Some(Tok::Word(name)) => name.clone(),
                     _ => return Err("VARIABLE requires a following name at top-level".into()),
                 };
-                created_words.insert(name, here);
-                here = here.wrapping_add(4);
+                p.created_words.insert(name, p.here);
+                p.here = p.here.wrapping_add(4);
                 i += 2;
             }
             Tok::Word(w) if w == "," => {
-                here = here.wrapping_add(4);
+                // Forth comma allocates one 32-bit cell (4 bytes) and records its
+                // value in the static data image.
+                let v = ct.pop("Top-level ,")?;
+                p.static_inits.insert(p.here, v);
+                p.here = p.here.wrapping_add(4);
                 i += 1;
             }
-            Tok::Word(w) if w == "HERE" => {
+            Tok::Word(w) if w == "INLINE" => {
+                // `: NAME ... ; INLINE` marks the preceding definition.
+                let (name, _) = p
+                    .defs
+                    .last()
+                    .ok_or("INLINE requires a preceding definition")?;
+                p.inline_words.insert(name.clone());
                 i += 1;
             }
             Tok::Word(w) if w == "ALLOT" => {
-                let n =
-                    resolve_prev_compile_time_value(toks, i, here, &constant_words, &created_words)
-                        .ok_or_else(|| {
-                            "Top-level ALLOT requires a compile-time value before it".to_string()
-                        })?;
-                here = here.wrapping_add(n);
+                let n = ct.pop("Top-level ALLOT")?;
+                p.here = p.here.wrapping_add(n);
                 i += 1;
             }
             Tok::Word(w) if w == "CONSTANT" => {
-                let val =
-                    resolve_prev_compile_time_value(toks, i, here, &constant_words, &created_words)
-                        .ok_or_else(|| {
-                            "Top-level CONSTANT requires a compile-time value before it".to_string()
-                        })?;
+                let val = ct.pop("Top-level CONSTANT")?;
                 let name = match toks.get(i + 1) {
                     Some(Tok::Word(name)) => name.clone(),
                     _ => return Err("CONSTANT requires a following name at top-level".into()),
                 };
-                constant_words.insert(name, val);
+                p.constant_words.insert(name, val);
+                i += 2;
+            }
+            Tok::Word(w) if w == "2VARIABLE" => {
+                let name = match toks.get(i + 1) {
+                    Some(Tok::Word(name)) => name.clone(),
+                    _ => return Err("2VARIABLE requires a following name at top-level".into()),
+                };
+                p.created_words.insert(name, p.here);
+                p.here = p.here.wrapping_add(8);
+                i += 2;
+            }
+            Tok::Word(w) if w == "2CONSTANT" => {
+                let hi = ct.pop("Top-level 2CONSTANT")?;
+                let lo = ct.pop("Top-level 2CONSTANT")?;
+                let name = match toks.get(i + 1) {
+                    Some(Tok::Word(name)) => name.clone(),
+                    _ => return Err("2CONSTANT requires a following name at top-level".into()),
+                };
+                p.double_constant_words.insert(name, (lo, hi));
+                i += 2;
+            }
+            Tok::Word(w) if w == "VALUE" => {
+                let val = ct.pop("Top-level VALUE")?;
+                let name = match toks.get(i + 1) {
+                    Some(Tok::Word(name)) => name.clone(),
+                    _ => return Err("VALUE requires a following name at top-level".into()),
+                };
+                // A VALUE owns one cell in the static layout, like VARIABLE.
+                p.value_words.insert(name, p.here);
+                p.static_inits.insert(p.here, val);
+                p.here = p.here.wrapping_add(4);
+                i += 2;
+            }
+            Tok::Word(w) if w == "TO" || w == "+TO" => {
+                let val = ct.pop(&format!("Top-level {}", w))?;
+                let addr = match toks.get(i + 1) {
+                    Some(Tok::Word(name)) => p
+                        .value_words
+                        .get(name)
+                        .copied()
+                        .ok_or_else(|| format!("{} target is not a VALUE: {}", w, name))?,
+                    _ => {
+                        return Err(format!(
+                            "{} requires a following VALUE name at top-level",
+                            w
+                        ))
+                    }
+                };
+                let val = if w == "+TO" {
+                    let old = p.static_inits.get(&addr).copied().unwrap_or(0);
+                    old.wrapping_add(val)
+                } else {
+                    val
+                };
+                p.static_inits.insert(addr, val);
+                i += 2;
+            }
+            Tok::Word(w) if p.defining_words.contains_key(w) => {
+                let child = match toks.get(i + 1) {
+                    Some(Tok::Word(name)) => name.clone(),
+                    _ => return Err(format!("{} requires a following name at top-level", w)),
+                };
+                run_defining_word(&mut p, &mut ct, w, &child)?;
                 i += 2;
             }
+            Tok::Word(w) if w == "[" || w == "]" => {
+                // Top-level code is already interpreted.
+                i += 1;
+            }
+            Tok::Word(w) if w == "LITERAL" => {
+                return Err("LITERAL is only valid inside a definition".into());
+            }
             Tok::Word(w) => {
-                // kpascal output usually ends with `MAIN` invocation.
-                entry_call = Some(w.clone());
+                if ct.exec_pure(w)? {
+                    // pure compile-time word
+                } else if let Some(v) = ct_symbol_value(&p, w) {
+                    ct.push(v);
+                } else if let Some(&(lo, hi)) = p.double_constant_words.get(w) {
+                    ct.push(lo);
+                    ct.push(hi);
+                } else if let Some(&addr) = p.value_words.get(w) {
+                    ct.push(p.static_inits.get(&addr).copied().unwrap_or(0));
+                } else if p.does_words.contains_key(w) {
+                    run_does_word(&p, &mut ct, w)?;
+                } else if p.defs.iter().any(|(name, _)| name == w) {
+                    // kpascal output usually ends with `MAIN` invocation.
+                    p.entry_call = Some(w.clone());
+                } else {
+                    return Err(format!("Word {} is not supported at top-level", w));
+                }
+                i += 1;
+            }
+            Tok::Num(v) => {
+                ct.push(*v);
                 i += 1;
             }
-            Tok::Num(_) | Tok::Str(_) | Tok::Semi => {
+            Tok::Str(_) | Tok::Semi | Tok::InlineHint => {
+                i += 1;
+            }
+        }
+    }
+
+    Ok(p)
+}
+
+// ---------------- peephole optimizer ----------------
+// Token-level pass between parse_program and codegen. It substitutes
+// top-level constants, folds pure words whose operands are all literals
+// (reusing CtMachine, so results match the runtime), removes no-op sequences
+// and keeps only the taken branch of an IF with a literal condition.
+// Divisions by a literal zero are left alone so they still trap at run time.
+
+// Words whose following token is a name rather than something to execute.
+fn takes_name(w: &str) -> bool {
+    matches!(
+        w,
+        "CONSTANT" | "2CONSTANT" | "CREATE" | "VARIABLE" | "2VARIABLE" | "VALUE" | "TO" | "+TO"
+    )
+}
+
+// Index just past the `]` closing the `[` at `i` (or the end of the body).
+fn bracket_end(toks: &[Tok], i: usize) -> usize {
+    toks[i..]
+        .iter()
+        .position(|t| matches!(t, Tok::Word(w) if w == "]"))
+        .map_or(toks.len(), |k| i + k + 1)
+}
+
+// Tokens copied verbatim starting at `i`: `[ ... ]` blocks and `WORD name`.
+fn verbatim_end(toks: &[Tok], i: usize) -> Option<usize> {
+    match &toks[i] {
+        Tok::Word(w) if w == "[" => Some(bracket_end(toks, i)),
+        Tok::Word(w) if takes_name(w) => Some((i + 2).min(toks.len())),
+        _ => None,
+    }
+}
+
+// (ELSE index, THEN index) of the IF at `i`.
+fn if_extent(toks: &[Tok], i: usize) -> Option<(Option<usize>, usize)> {
+    let mut depth = 0usize;
+    let mut else_at = None;
+    let mut j = i + 1;
+    while j < toks.len() {
+        if let Some(end) = verbatim_end(toks, j) {
+            j = end;
+            continue;
+        }
+        if let Tok::Word(w) = &toks[j] {
+            match w.as_str() {
+                "IF" => depth += 1,
+                "ELSE" if depth == 0 => else_at = Some(j),
+                "THEN" if depth == 0 => return Some((else_at, j)),
+                "THEN" => depth -= 1,
+                _ => {}
+            }
+        }
+        j += 1;
+    }
+    None
+}
+
+fn substitute_constants(p: &ParsedProgram, body: &Body) -> Body {
+    let toks = &body.toks;
+    let mut out = Body {
+        line: body.line,
+        ..Body::default()
+    };
+    let mut i = 0usize;
+    while i < toks.len() {
+        if let Some(end) = verbatim_end(toks, i) {
+            out.extend_from(body, i..end);
+            i = end;
+            continue;
+        }
+        let line = body.lines[i];
+        match &toks[i] {
+            Tok::Word(w) => {
+                if let Some(&v) = p.constant_words.get(w).or_else(|| p.created_words.get(w)) {
+                    out.push(Tok::Num(v), line);
+                } else if let Some(&(lo, hi)) = p.double_constant_words.get(w) {
+                    out.push(Tok::Num(lo), line);
+                    out.push(Tok::Num(hi), line);
+                } else {
+                    out.push(toks[i].clone(), line);
+                }
+            }
+            t => out.push(t.clone(), line),
+        }
+        i += 1;
+    }
+    out
+}
+
+// Runs `w` on the literals at the end of `out`; replaces them on success.
+// The results take the line of `w`.
+fn fold_literals(out: &mut Body, w: &str, line: u32, division: DivisionMode) -> bool {
+    if w == "DEPTH" {
+        return false;
+    }
+    let n = out
+        .toks
+        .iter()
+        .rev()
+        .take_while(|t| matches!(t, Tok::Num(_)))
+        .count();
+    if n == 0 {
+        return false;
+    }
+    let mut ct = CtMachine::new(division);
+    for t in &out.toks[out.toks.len() - n..] {
+        if let Tok::Num(v) = t {
+            ct.push(*v);
+        }
+    }
+    if !matches!(ct.exec_pure(w), Ok(true)) {
+        return false;
+    }
+    out.truncate(out.toks.len() - n);
+    for v in ct.stack {
+        out.push(Tok::Num(v), line);
+    }
+    true
+}
+
+// Drops `prev w` pairs that leave the stack unchanged.
+fn drop_noop(out: &mut Body, w: &str) -> bool {
+    let noop = match out.toks.last() {
+        Some(Tok::Num(0)) => matches!(
+            w,
+            "+" | "-" | "OR" | "XOR" | "LSHIFT" | "RSHIFT" | "ARSHIFT"
+        ),
+        Some(Tok::Num(1)) => matches!(w, "*" | "/"),
+        Some(Tok::Num(-1)) => w == "AND",
+        Some(Tok::Word(prev)) => matches!(
+            (prev.as_str(), w),
+            ("DUP", "DROP")
+                | ("OVER", "DROP")
+                | ("2DUP", "2DROP")
+                | ("SWAP", "SWAP")
+                | ("2SWAP", "2SWAP")
+                | (">R", "R>")
+                | ("NEGATE", "NEGATE")
+                | ("INVERT", "INVERT")
+        ),
+        _ => false,
+    };
+    if noop {
+        out.truncate(out.toks.len() - 1);
+    }
+    noop
+}
+
+// Whether toks[from..to] defines names or runs `[ ... ]` at compile time;
+// lowering does that even in a branch that never runs, so it must stay.
+fn has_compile_time_effect(toks: &[Tok], from: usize, to: usize) -> bool {
+    toks[from..to]
+        .iter()
+        .any(|t| matches!(t, Tok::Word(w) if w == "[" || takes_name(w)))
+}
+
+// One rewrite sweep; returns None once nothing changed.
+fn peephole_pass(body: &Body, division: DivisionMode) -> Option<Body> {
+    let toks = &body.toks;
+    let mut out = Body {
+        toks: Vec::with_capacity(toks.len()),
+        lines: Vec::with_capacity(toks.len()),
+        line: body.line,
+    };
+    let mut changed = false;
+    let mut i = 0usize;
+    while i < toks.len() {
+        if let Some(end) = verbatim_end(toks, i) {
+            out.extend_from(body, i..end);
+            i = end;
+            continue;
+        }
+        if let Tok::Word(w) = &toks[i] {
+            if w == "IF" {
+                if let (Some(&Tok::Num(c)), Some((else_at, then_at))) =
+                    (out.toks.last(), if_extent(toks, i))
+                {
+                    if has_compile_time_effect(toks, i, then_at) {
+                        out.push(toks[i].clone(), body.lines[i]);
+                        i += 1;
+                        continue;
+                    }
+                    out.truncate(out.toks.len() - 1);
+                    let (from, to) = if c != 0 {
+                        (i + 1, else_at.unwrap_or(then_at))
+                    } else {
+                        (else_at.map_or(then_at, |e| e + 1), then_at)
+                    };
+                    out.extend_from(body, from..to);
+                    changed = true;
+                    i = then_at + 1;
+                    continue;
+                }
+            }
+            if fold_literals(&mut out, w, body.lines[i], division) || drop_noop(&mut out, w) {
+                changed = true;
                 i += 1;
+                continue;
             }
         }
+        out.push(toks[i].clone(), body.lines[i]);
+        i += 1;
+    }
+    changed.then_some(out)
+}
+
+fn optimize_body(p: &ParsedProgram, body: &Body) -> Body {
+    let mut body = substitute_constants(p, body);
+    while let Some(next) = peephole_pass(&body, p.division) {
+        body = next;
+    }
+    body
+}
+
+// Output format selected by --emit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Emit {
+    Llvm,
+    Kir,
+    C,
+    Wat,
+    Bytecode,
+    Asm,
+}
+
+fn parse_emit(s: &str) -> Option<Emit> {
+    match s {
+        "llvm" => Some(Emit::Llvm),
+        "kir" => Some(Emit::Kir),
+        "c" => Some(Emit::C),
+        "wat" => Some(Emit::Wat),
+        "bytecode" => Some(Emit::Bytecode),
+        "asm" => Some(Emit::Asm),
+        _ => None,
     }
+}
 
-    Ok(ParsedProgram {
-        defs,
-        created_words,
-        constant_words,
-        here,
-        entry_call,
+// Typed pointers, readable by llc-14.
+const DEFAULT_LLVM_VERSION: u32 = 14;
+const OPAQUE_POINTERS_SINCE: u32 = 15;
+
+struct Options {
+    input: String,
+    // Empty for `kforthc run`.
+    output: String,
+    // `kforthc run`: interpret instead of writing output.
+    run: bool,
+    division: Option<DivisionMode>,
+    // Peephole pass and literal operands (disable with --no-opt).
+    opt: bool,
+    inline_threshold: usize,
+    emit: Emit,
+    target: Option<&'static target::Target>,
+    // DWARF line tables (-g).
+    debug: bool,
+    // Trap on out-of-range or misaligned memory accesses (--check-memory).
+    check_memory: bool,
+    // Oldest LLVM that must read the output; 15 and later get opaque pointers.
+    llvm_version: u32,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let usage = format!(
+        "Usage: {} [--division=floored|symmetric] [--no-opt] [--inline-threshold=N] [--emit=llvm|kir|c|wat|bytecode|asm] [--target=x86_64|i686|aarch64|riscv64] [--llvm-version=N] [-g] [--check-memory] <input.fth> <output>\n       {} run [--division=floored|symmetric] [--no-opt] [--inline-threshold=N] [--check-memory] <input.fth>\n       {} test [--emit=llvm|c|wat|bytecode|asm] [--kpascal=PATH] [--runtime=DIR] [--build-dir=DIR] [compiler options] <file|dir>...",
+        args[0], args[0], args[0]
+    );
+    let mut positional = Vec::new();
+    let mut division = None;
+    let mut opt = true;
+    let mut emit = Emit::Llvm;
+    let mut target = target::host();
+    let mut debug = false;
+    let mut check_memory = false;
+    let mut llvm_version = DEFAULT_LLVM_VERSION;
+    let mut inline_threshold = passes::DEFAULT_INLINE_THRESHOLD;
+    let run = args.get(1).is_some_and(|a| a == "run");
+    let rest = if run { &args[2..] } else { &args[1..] };
+    for arg in rest {
+        if arg == "--no-opt" {
+            opt = false;
+        } else if arg == "-g" {
+            debug = true;
+        } else if arg == "--check-memory" {
+            check_memory = true;
+        } else if let Some(v) = arg.strip_prefix("--division=") {
+            division = Some(
+                parse_division_mode(v)
+                    .ok_or_else(|| format!("Unknown --division value: {}\n{}", v, usage))?,
+            );
+        } else if let Some(v) = arg.strip_prefix("--inline-threshold=") {
+            inline_threshold = v
+                .parse()
+                .map_err(|_| format!("Invalid --inline-threshold value: {}\n{}", v, usage))?;
+        } else if let Some(v) = arg.strip_prefix("--emit=") {
+            emit =
+                parse_emit(v).ok_or_else(|| format!("Unknown --emit value: {}\n{}", v, usage))?;
+        } else if let Some(v) = arg.strip_prefix("--llvm-version=") {
+            llvm_version = v
+                .split('.')
+                .next()
+                .and_then(|major| major.parse().ok())
+                .ok_or_else(|| format!("Invalid --llvm-version value: {}\n{}", v, usage))?;
+        } else if let Some(v) = arg.strip_prefix("--target=") {
+            target = Some(
+                target::parse_target(v)
+                    .ok_or_else(|| format!("Unknown --target value: {}\n{}", v, usage))?,
+            );
+        } else if arg.starts_with("--") {
+            return Err(format!("Unknown option: {}\n{}", arg, usage));
+        } else {
+            positional.push(arg.clone());
+        }
+    }
+    if positional.len() != if run { 1 } else { 2 } {
+        return Err(usage);
+    }
+    Ok(Options {
+        input: positional[0].clone(),
+        output: positional.get(1).cloned().unwrap_or_default(),
+        run,
+        division,
+        opt,
+        inline_threshold,
+        emit,
+        target,
+        debug,
+        check_memory,
+        llvm_version,
     })
 }
 
-fn main() -> Result<(), String> {
+fn main() {
+    if let Err(e) = try_main() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn try_main() -> Result<(), String> {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
-        return Err(format!("Usage: {} <input.fth> <output.ll>", args[0]));
+    if args.get(1).is_some_and(|a| a == "test") {
+        std::process::exit(testrunner::run(&args)?);
     }
-    let input = fs::read_to_string(&args[1]).map_err(|e| format!("Read error: {}", e))?;
+    let opts = parse_args(&args)?;
+    let input = fs::read_to_string(&opts.input).map_err(|e| format!("Read error: {}", e))?;
     let routine_aliases = extract_routine_aliases(&input);
-    let toks = tokenize(&input)?;
-    let parsed = parse_program(&toks)?;
-    let defs = parsed.defs;
-    let mut known_defs = HashSet::new();
-    for (name, _) in &defs {
-        known_defs.insert(name.clone());
+    // The command line wins over the pragma. kPascal IL (recognized by its
+    // ROUTINE annotations) keeps Pascal div/mod.
+    let pragma = extract_division_pragma(&input)?;
+    let division = opts.division.or(pragma).unwrap_or(DivisionMode::Symmetric);
+    if !routine_aliases.is_empty() && division == DivisionMode::Floored {
+        return Err(
+            "Floored division is not available for kPascal IL (ROUTINE annotations), which keeps Pascal div/mod"
+                .into(),
+        );
+    }
+    let (toks, lines) = tokenize(&input)?;
+    let mut parsed = parse_program(&toks, &lines, division)?;
+    let mut defs = std::mem::take(&mut parsed.defs);
+    if opts.opt {
+        for (_, body) in defs.iter_mut() {
+            *body = optimize_body(&parsed, body);
+        }
+    }
+    let mut kir = kir::lower_program(
+        &parsed,
+        &defs,
+        &routine_aliases,
+        opts.debug,
+        opts.check_memory,
+    )?;
+    if opts.opt {
+        passes::inline_small_words(&mut kir, opts.inline_threshold, &parsed.inline_words)?;
+    }
+    passes::mark_tail_calls(&mut kir);
+    if opts.run {
+        std::process::exit(interp::run(&kir));
+    }
+    if opts.emit == Emit::Kir {
+        return fs::write(&opts.output, kir::dump(&kir)).map_err(|e| format!("Write error: {}", e));
+    }
+    if opts.emit == Emit::C {
+        let source = opts.debug.then_some(opts.input.as_str());
+        let c = cbackend::emit(&kir, source)?;
+        return fs::write(&opts.output, c).map_err(|e| format!("Write error: {}", e));
+    }
+    if opts.emit == Emit::Wat {
+        let wat = watbackend::emit(&kir)?;
+        return fs::write(&opts.output, wat).map_err(|e| format!("Write error: {}", e));
+    }
+    if opts.emit == Emit::Asm {
+        if opts.target.map(|t| t.name) != Some("x86_64") {
+            return Err("--emit=asm only supports --target=x86_64".into());
+        }
+        let source = opts.debug.then_some(opts.input.as_str());
+        let asm = asmbackend::emit(&kir, source)?;
+        return fs::write(&opts.output, asm).map_err(|e| format!("Write error: {}", e));
+    }
+    if opts.emit == Emit::Bytecode {
+        let image = bytecode::emit(&kir)?;
+        return fs::write(&opts.output, image).map_err(|e| format!("Write error: {}", e));
     }
 
     let mut cg = Codegen::new();
+    cg.target = opts.target;
+    cg.b.opaque_ptrs = opts.llvm_version >= OPAQUE_POINTERS_SINCE;
+    if opts.debug {
+        cg.debug = Some(debuginfo::DebugInfo::new(&opts.input));
+    }
     cg.emit_prelude();
-    cg.set_program_symbols(
-        parsed.created_words,
-        parsed.constant_words,
-        known_defs,
-        parsed.here,
-    );
-
-    // Compile all defs
-    for (name, body) in &defs {
-        cg.begin_func(name);
-        let alias = routine_aliases.get(name).map(|s| s.as_str());
-        if !cg.try_emit_native_pascal_routine(alias)? {
-            cg.compile_body(body)?;
-        }
-        cg.end_func();
-    }
-
-    // If there is a word named MAIN, create @main wrapper calling it.
-    // Otherwise, if exactly one def exists, call it.
-    let entry = if let Some(entry) = parsed.entry_call {
-        entry
-    } else if defs.iter().any(|(n, _)| n == "MAIN") {
-        "MAIN".to_string()
-    } else if defs.len() == 1 {
-        defs[0].0.clone()
-    } else {
-        return Err(
-            "No entry point. Define : MAIN ... ; or provide exactly one definition.".into(),
-        );
-    };
-    cg.emit_main_wrapper(&entry);
+    cg.set_module_info(&kir);
+    cg.opt = opts.opt;
+    for f in &kir.funcs {
+        cg.emit_func(f)?;
+    }
+    cg.emit_main_wrapper(&kir.entry);
 
-    let module = format!("{}\n{}", cg.b.out, cg.b.globals);
-    fs::write(&args[2], module).map_err(|e| format!("Write error: {}", e))?;
+    let mut module = format!("{}\n{}", cg.b.out, cg.b.globals);
+    if let Some(d) = cg.debug.take() {
+        module.push('\n');
+        module.push_str(&d.finish());
+    }
+    fs::write(&opts.output, module).map_err(|e| format!("Write error: {}", e))?;
     Ok(())
 }
@@ -0,0 +1,321 @@
+// Transformations over kIR, run after lowering and before any backend.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::kir::{Block, Func, Inst, Module, Op, Term};
+
+// Default size limit (instructions plus branches) for automatic inlining.
+pub const DEFAULT_INLINE_THRESHOLD: usize = 8;
+
+fn callees(f: &Func) -> impl Iterator<Item = &str> {
+    f.blocks.iter().flat_map(|b| {
+        b.insts.iter().filter_map(|i| match i {
+            Inst::Call(w) => Some(w.as_str()),
+            _ => None,
+        })
+    })
+}
+
+// Words that can reach themselves through calls.
+fn recursive_words(m: &Module, index: &HashMap<&str, usize>) -> HashSet<String> {
+    let mut out = HashSet::new();
+    for f in &m.funcs {
+        let mut seen = HashSet::new();
+        let mut work: Vec<&str> = callees(f).collect();
+        while let Some(w) = work.pop() {
+            if w == f.name {
+                out.insert(f.name.clone());
+                break;
+            }
+            if seen.insert(w) {
+                if let Some(&k) = index.get(w) {
+                    work.extend(callees(&m.funcs[k]));
+                }
+            }
+        }
+    }
+    out
+}
+
+fn size(f: &Func) -> usize {
+    let insts = f.blocks.iter().flat_map(|b| &b.insts);
+    insts.filter(|i| !matches!(i, Inst::Line(_))).count() + f.blocks.len() - 1
+}
+
+// Checked divisions and memory accesses report the definition they are
+// compiled into, so words containing them keep their own frame unless
+// explicitly marked INLINE.
+fn has_runtime_check(f: &Func) -> bool {
+    f.blocks.iter().flat_map(|b| &b.insts).any(|i| {
+        matches!(
+            i,
+            Inst::Op(
+                Op::Div
+                    | Op::Mod
+                    | Op::DivMod
+                    | Op::StarSlash
+                    | Op::StarSlashMod
+                    | Op::FmMod
+                    | Op::SmRem
+                    | Op::UDiv
+                    | Op::UMod
+                    | Op::UmSlashMod
+            ) | Inst::MemCheck(_)
+        )
+    })
+}
+
+// The return stack is local to each definition. Inlined code shares the
+// caller's, which is only safe when the callee leaves it as it found it.
+fn return_stack_balanced(f: &Func) -> bool {
+    let effect = |i: &Inst| match i {
+        Inst::Op(Op::ToR) => Some((0, 1)),
+        Inst::Op(Op::RFrom) => Some((1, -1)),
+        Inst::Op(Op::RFetch) => Some((1, 0)),
+        Inst::Op(Op::TwoToR) => Some((0, 2)),
+        Inst::Op(Op::TwoRFrom) => Some((2, -2)),
+        Inst::Op(Op::TwoRFetch) => Some((2, 0)),
+        _ => None,
+    };
+    let uses = |b: &Block| b.insts.iter().any(|i| effect(i).is_some());
+    match f.blocks.as_slice() {
+        [single] => {
+            let mut depth = 0i32;
+            for (need, delta) in single.insts.iter().filter_map(effect) {
+                if depth < need {
+                    return false;
+                }
+                depth += delta;
+            }
+            depth == 0
+        }
+        blocks => !blocks.iter().any(uses),
+    }
+}
+
+// Call-graph post-order so callees are finished before their callers.
+fn post_order(m: &Module, index: &HashMap<&str, usize>) -> Vec<usize> {
+    fn visit(
+        k: usize,
+        m: &Module,
+        index: &HashMap<&str, usize>,
+        seen: &mut [bool],
+        out: &mut Vec<usize>,
+    ) {
+        if seen[k] {
+            return;
+        }
+        seen[k] = true;
+        for w in callees(&m.funcs[k]) {
+            if let Some(&c) = index.get(w) {
+                visit(c, m, index, seen, out);
+            }
+        }
+        out.push(k);
+    }
+    let mut seen = vec![false; m.funcs.len()];
+    let mut out = Vec::with_capacity(m.funcs.len());
+    for k in 0..m.funcs.len() {
+        visit(k, m, index, &mut seen, &mut out);
+    }
+    out
+}
+
+// Replaces calls to inlinable words in `f` by their bodies. Single-block
+// callees are spliced in place; larger ones become blocks that jump to a
+// continuation block instead of returning. Inlined code keeps the line of
+// the call.
+fn inline_into(f: &Func, inlinable: &HashMap<String, Func>) -> Func {
+    let callee_of = |i: &Inst| match i {
+        Inst::Call(w) => inlinable.get(w).filter(|c| c.blocks.len() > 1),
+        _ => None,
+    };
+    // New index of the first piece of every original block.
+    let mut start = Vec::with_capacity(f.blocks.len());
+    let mut next = 0usize;
+    for b in &f.blocks {
+        start.push(next);
+        next += 1 + b
+            .insts
+            .iter()
+            .filter_map(callee_of)
+            .map(|c| c.blocks.len() + 1)
+            .sum::<usize>();
+    }
+    let mut blocks: Vec<Block> = Vec::with_capacity(next);
+    for b in &f.blocks {
+        let mut cur = Block {
+            hint: b.hint,
+            insts: Vec::new(),
+            term: Term::Return,
+        };
+        for inst in &b.insts {
+            let callee = match inst {
+                Inst::Call(w) => inlinable.get(w),
+                _ => None,
+            };
+            match callee {
+                Some(c) if c.blocks.len() == 1 => cur.insts.extend(without_lines(&c.blocks[0])),
+                Some(c) => {
+                    let base = blocks.len() + 1;
+                    let cont = base + c.blocks.len();
+                    cur.term = Term::Jump(base);
+                    blocks.push(cur);
+                    for (j, cb) in c.blocks.iter().enumerate() {
+                        let term = match &cb.term {
+                            Term::Return => Term::Jump(cont),
+                            t => t.map_targets(|x| base + x),
+                        };
+                        blocks.push(Block {
+                            hint: if j == 0 { "inline" } else { cb.hint },
+                            insts: without_lines(cb).collect(),
+                            term,
+                        });
+                    }
+                    cur = Block {
+                        hint: "cont",
+                        insts: Vec::new(),
+                        term: Term::Return,
+                    };
+                }
+                None => cur.insts.push(inst.clone()),
+            }
+        }
+        cur.term = b.term.map_targets(|x| start[x]);
+        blocks.push(cur);
+    }
+    Func {
+        name: f.name.clone(),
+        blocks,
+        line: f.line,
+    }
+}
+
+fn without_lines(b: &Block) -> impl Iterator<Item = Inst> + '_ {
+    b.insts
+        .iter()
+        .filter(|i| !matches!(i, Inst::Line(_)))
+        .cloned()
+}
+
+// Inlines calls to small non-recursive words (size <= `threshold`, 0 turns
+// automatic inlining off) and to every word in `forced`. Words that were
+// inlined and are no longer called are dropped.
+pub fn inline_small_words(
+    m: &mut Module,
+    threshold: usize,
+    forced: &HashSet<String>,
+) -> Result<(), String> {
+    let names: Vec<String> = m.funcs.iter().map(|f| f.name.clone()).collect();
+    let index: HashMap<&str, usize> = names
+        .iter()
+        .enumerate()
+        .map(|(k, n)| (n.as_str(), k))
+        .collect();
+    let recursive = recursive_words(m, &index);
+    let mut forced_sorted: Vec<&String> = forced.iter().collect();
+    forced_sorted.sort();
+    for w in forced_sorted {
+        if !index.contains_key(w.as_str()) {
+            return Err(format!("INLINE names an unknown word: {}", w));
+        }
+        if recursive.contains(w) {
+            return Err(format!(
+                "INLINE word {} is recursive and cannot be inlined",
+                w
+            ));
+        }
+        if !return_stack_balanced(&m.funcs[index[w.as_str()]]) {
+            return Err(format!(
+                "INLINE word {} leaves the return stack unbalanced and cannot be inlined",
+                w
+            ));
+        }
+    }
+
+    let mut inlinable: HashMap<String, Func> = HashMap::new();
+    let mut inlined = HashSet::new();
+    for k in post_order(m, &index) {
+        let f = &m.funcs[k];
+        if callees(f).any(|w| inlinable.contains_key(w)) {
+            for w in callees(f) {
+                if inlinable.contains_key(w) {
+                    inlined.insert(w.to_string());
+                }
+            }
+            m.funcs[k] = inline_into(f, &inlinable);
+        }
+        let f = &m.funcs[k];
+        let small = threshold > 0 && size(f) <= threshold && !has_runtime_check(f);
+        if !recursive.contains(&f.name)
+            && (forced.contains(&f.name) || small)
+            && return_stack_balanced(f)
+        {
+            inlinable.insert(f.name.clone(), f.clone());
+        }
+    }
+
+    let called: HashSet<String> = m
+        .funcs
+        .iter()
+        .flat_map(|f| callees(f).map(str::to_string))
+        .collect();
+    let entry = m.entry.clone();
+    m.funcs
+        .retain(|f| f.name == entry || called.contains(&f.name) || !inlined.contains(&f.name));
+    Ok(())
+}
+
+// Jumps to an empty block that only returns or jumps on are replaced by
+// that block's terminator; this puts calls before `THEN ;` in tail position.
+fn thread_jumps(f: &mut Func) {
+    loop {
+        let mut changed = false;
+        for k in 0..f.blocks.len() {
+            if let Term::Jump(t) = f.blocks[k].term {
+                let target = &f.blocks[t];
+                if target.insts.is_empty()
+                    && matches!(target.term, Term::Return | Term::Jump(_))
+                    && target.term != Term::Jump(t)
+                {
+                    f.blocks[k].term = target.term.clone();
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+}
+
+// Drops blocks no longer reachable from the entry, keeping layout order.
+fn prune_unreachable(f: &mut Func) {
+    let mut live = vec![false; f.blocks.len()];
+    let mut work = vec![0];
+    while let Some(k) = work.pop() {
+        if !std::mem::replace(&mut live[k], true) {
+            work.extend(f.blocks[k].term.targets());
+        }
+    }
+    let order: Vec<usize> = (0..f.blocks.len()).filter(|&k| live[k]).collect();
+    if order.len() != f.blocks.len() {
+        f.reorder(&order);
+    }
+}
+
+// Turns `call X` followed by a return into a `TailCall` terminator so
+// backends can reuse the frame (a loop for self calls, a jump otherwise).
+pub fn mark_tail_calls(m: &mut Module) {
+    for f in &mut m.funcs {
+        thread_jumps(f);
+        for b in &mut f.blocks {
+            if b.term == Term::Return && matches!(b.insts.last(), Some(Inst::Call(_))) {
+                if let Some(Inst::Call(w)) = b.insts.pop() {
+                    b.term = Term::TailCall(w);
+                }
+            }
+        }
+        prune_unreachable(f);
+    }
+}
@@ -0,0 +1,64 @@
+// Code generation targets selectable with --target.
+//
+// Forth cells are always 32-bit and addresses in cells are byte offsets
+// into the runtime's mem_cells, never machine pointers, so the pointer
+// width only shows up in the data layout and the C calling convention.
+
+#[derive(Debug, PartialEq)]
+pub struct Target {
+    pub name: &'static str,
+    pub triple: &'static str,
+    // Matches what LLVM 14 uses for the triple.
+    pub datalayout: &'static str,
+    // The C ABI requires callers to sign-extend 32-bit arguments and
+    // callees to sign-extend 32-bit results (riscv64).
+    pub sign_extend_i32: bool,
+}
+
+pub const TARGETS: &[Target] = &[
+    Target {
+        name: "x86_64",
+        triple: "x86_64-unknown-linux-gnu",
+        datalayout: "e-m:e-p270:32:32-p271:32:32-p272:64:64-i64:64-f80:128-n8:16:32:64-S128",
+        sign_extend_i32: false,
+    },
+    Target {
+        name: "i686",
+        triple: "i686-unknown-linux-gnu",
+        datalayout: "e-m:e-p:32:32-p270:32:32-p271:32:32-p272:64:64-f64:32:64-f80:32-n8:16:32-S128",
+        sign_extend_i32: false,
+    },
+    Target {
+        name: "aarch64",
+        triple: "aarch64-unknown-linux-gnu",
+        datalayout: "e-m:e-i8:8:32-i16:16:32-i64:64-i128:128-n32:64-S128",
+        sign_extend_i32: false,
+    },
+    Target {
+        name: "riscv64",
+        triple: "riscv64-unknown-linux-gnu",
+        datalayout: "e-m:e-p:64:64-i64:64-i128:128-n64-S128",
+        sign_extend_i32: true,
+    },
+];
+
+// Accepts a short name (`aarch64`) or a full triple whose architecture
+// and OS match one of TARGETS (`aarch64-linux-gnu`).
+pub fn parse_target(s: &str) -> Option<&'static Target> {
+    let arch = s.split('-').next().unwrap_or("");
+    let arch = match arch {
+        "amd64" => "x86_64",
+        "x86" | "i386" | "i486" | "i586" => "i686",
+        "arm64" => "aarch64",
+        a => a,
+    };
+    if s.contains('-') && !s.contains("linux") {
+        return None;
+    }
+    TARGETS.iter().find(|t| t.name == arch)
+}
+
+// The target matching the machine kforthc itself was built for, if any.
+pub fn host() -> Option<&'static Target> {
+    parse_target(std::env::consts::ARCH)
+}
@@ -0,0 +1,529 @@
+// `kforthc test`: builds and runs programs against their sidecar files
+// and prints unified diffs and a summary. A case is NAME.fth (or NAME.pas
+// with --kpascal) next to:
+//   NAME.expected         stdout (required)
+//   NAME.flags            extra compiler options
+//   NAME.stdin            stdin (otherwise empty)
+//   NAME.status           exit status (otherwise 0)
+//   NAME.stderr.expected  stderr
+//   NAME.kir.expected     --emit=kir output, checked without extra options
+//
+// Programs run through `kforthc run`, or with --emit through that backend
+// and its tools (CC, LLC, WAT2WASM, node and RUNNER from the environment,
+// as in the scripts).
+
+use std::env;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use crate::{parse_emit, Emit, OPAQUE_POINTERS_SINCE};
+
+const CONTEXT: usize = 3;
+// Beyond this many line pairs the diff shows a plain replacement.
+const DIFF_LIMIT: usize = 4_000_000;
+
+struct Runner {
+    exe: PathBuf,
+    // None runs the interpreter.
+    emit: Option<Emit>,
+    // Compiler options given to every case after its .flags.
+    forward: Vec<String>,
+    kpascal: Option<String>,
+    runtime: PathBuf,
+    build: PathBuf,
+    cc: String,
+    runner: Vec<String>,
+    // (llc, extra llc flags, --llvm-version to pass) for --emit=llvm.
+    llc: Option<(String, Vec<String>, Option<String>)>,
+    wat2wasm: String,
+    vm: Option<PathBuf>,
+}
+
+enum Outcome {
+    Pass,
+    Fail(Vec<String>),
+    Skip(String),
+}
+
+fn tool(cmd: &mut Command) -> Result<Output, String> {
+    let name = cmd.get_program().to_string_lossy().into_owned();
+    cmd.output()
+        .map_err(|e| format!("cannot run {}: {}", name, e))
+}
+
+// Runs a build step; a non-zero exit is an error carrying its stderr.
+fn build_step(cmd: &mut Command) -> Result<(), String> {
+    let name = cmd.get_program().to_string_lossy().into_owned();
+    let out = tool(cmd)?;
+    if out.status.success() {
+        return Ok(());
+    }
+    Err(format!(
+        "{} failed ({})\n{}",
+        name,
+        out.status,
+        String::from_utf8_lossy(&out.stderr).trim_end()
+    ))
+}
+
+fn sidecar(src: &Path, ext: &str) -> Option<String> {
+    fs::read_to_string(src.with_extension(ext)).ok()
+}
+
+fn is_source(p: &Path) -> bool {
+    p.extension().is_some_and(|e| e == "fth" || e == "pas")
+}
+
+// Sources with a NAME.expected in each directory (sorted), files as given.
+fn discover(paths: &[String]) -> Result<Vec<PathBuf>, String> {
+    let mut cases = Vec::new();
+    for p in paths {
+        let path = Path::new(p);
+        if !path.is_dir() {
+            if !path.is_file() {
+                return Err(format!("No such test file or directory: {}", p));
+            }
+            cases.push(path.to_path_buf());
+            continue;
+        }
+        let mut found: Vec<_> = fs::read_dir(path)
+            .map_err(|e| format!("Read error: {}: {}", p, e))?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|f| is_source(f) && f.with_extension("expected").is_file())
+            .collect();
+        found.sort();
+        cases.extend(found);
+    }
+    Ok(cases)
+}
+
+fn find_llc() -> Option<String> {
+    if let Ok(llc) = env::var("LLC") {
+        return Some(llc);
+    }
+    ["llc", "llc-14"].into_iter().map(String::from).find(|llc| {
+        Command::new(llc)
+            .arg("--version")
+            .stdout(Stdio::null())
+            .status()
+            .is_ok()
+    })
+}
+
+fn llc_major(llc: &str) -> Result<u32, String> {
+    let out = tool(Command::new(llc).arg("--version"))?;
+    let text = String::from_utf8_lossy(&out.stdout);
+    text.split("LLVM version ")
+        .nth(1)
+        .and_then(|v| v.split('.').next())
+        .and_then(|major| major.trim().parse().ok())
+        .ok_or_else(|| format!("Cannot read the LLVM version of {}", llc))
+}
+
+// Splits a sequence into runs of equal and differing lines: ' ', '-', '+'.
+fn edit_script<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(char, &'a str)> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+
+    let mut ops: Vec<(char, &str)> = old[..prefix].iter().map(|l| (' ', *l)).collect();
+    if a.len().saturating_mul(b.len()) > DIFF_LIMIT {
+        ops.extend(a.iter().map(|l| ('-', *l)));
+        ops.extend(b.iter().map(|l| ('+', *l)));
+    } else {
+        // lcs[i * w + j]: longest common subsequence of a[i..] and b[j..].
+        let w = b.len() + 1;
+        let mut lcs = vec![0u32; (a.len() + 1) * w];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lcs[i * w + j] = if a[i] == b[j] {
+                    lcs[(i + 1) * w + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * w + j].max(lcs[i * w + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() || j < b.len() {
+            if i < a.len() && j < b.len() && a[i] == b[j] {
+                ops.push((' ', a[i]));
+                i += 1;
+                j += 1;
+            } else if j == b.len() || (i < a.len() && lcs[(i + 1) * w + j] >= lcs[i * w + j + 1]) {
+                ops.push(('-', a[i]));
+                i += 1;
+            } else {
+                ops.push(('+', b[j]));
+                j += 1;
+            }
+        }
+    }
+    ops.extend(old[old.len() - suffix..].iter().map(|l| (' ', *l)));
+    ops
+}
+
+fn hunk_range(start: usize, count: usize) -> String {
+    // An empty range names the line before it, as diff -u does.
+    let first = if count == 0 { start } else { start + 1 };
+    if count == 1 {
+        first.to_string()
+    } else {
+        format!("{},{}", first, count)
+    }
+}
+
+fn unified_diff(old_name: &str, new_name: &str, old: &str, new: &str) -> String {
+    let old_lines: Vec<&str> = old.split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new.split_inclusive('\n').collect();
+    let ops = edit_script(&old_lines, &new_lines);
+    let changes: Vec<usize> = (0..ops.len()).filter(|&k| ops[k].0 != ' ').collect();
+    let mut out = format!("--- {}\n+++ {}\n", old_name, new_name);
+
+    let mut k = 0;
+    while k < changes.len() {
+        let start = changes[k].saturating_sub(CONTEXT);
+        let mut end = (changes[k] + 1 + CONTEXT).min(ops.len());
+        k += 1;
+        while k < changes.len() && changes[k].saturating_sub(CONTEXT) <= end {
+            end = (changes[k] + 1 + CONTEXT).min(ops.len());
+            k += 1;
+        }
+        let before = &ops[..start];
+        let hunk = &ops[start..end];
+        let old_at = before.iter().filter(|(c, _)| *c != '+').count();
+        let new_at = before.iter().filter(|(c, _)| *c != '-').count();
+        let old_count = hunk.iter().filter(|(c, _)| *c != '+').count();
+        let new_count = hunk.iter().filter(|(c, _)| *c != '-').count();
+        out += &format!(
+            "@@ -{} +{} @@\n",
+            hunk_range(old_at, old_count),
+            hunk_range(new_at, new_count)
+        );
+        for (c, line) in hunk {
+            out.push(*c);
+            out += line;
+            if !line.ends_with('\n') {
+                out += "\n\\ No newline at end of file\n";
+            }
+        }
+    }
+    out
+}
+
+impl Runner {
+    fn compile(&self, flags: &[String], src: &Path, emit: &str, out: &Path) -> Result<(), String> {
+        let mut cmd = Command::new(&self.exe);
+        cmd.args(flags).args(&self.forward);
+        if !emit.is_empty() {
+            cmd.arg(format!("--emit={}", emit));
+        }
+        if let Some((_, _, Some(version))) = self.llc.as_ref().filter(|_| emit.is_empty()) {
+            cmd.arg(format!("--llvm-version={}", version));
+        }
+        build_step(cmd.arg(src).arg(out))
+    }
+
+    fn link(&self, obj: &Path, bin: &Path, no_pie: bool) -> Result<(), String> {
+        let mut cmd = Command::new(&self.cc);
+        if no_pie {
+            cmd.arg("-no-pie");
+        }
+        build_step(
+            cmd.arg(obj)
+                .arg(self.runtime.join("runtime.c"))
+                .arg("-o")
+                .arg(bin)
+                .arg("-lm"),
+        )
+    }
+
+    fn native(&self, bin: &Path) -> Command {
+        let mut cmd = match self.runner.split_first() {
+            Some((first, rest)) => {
+                let mut cmd = Command::new(first);
+                cmd.args(rest);
+                cmd
+            }
+            None => return Command::new(bin),
+        };
+        cmd.arg(bin);
+        cmd
+    }
+
+    // Compiles the Forth source for the selected backend; returns the
+    // command that runs it.
+    fn build(&self, flags: &[String], fth: &Path, base: &Path) -> Result<Command, String> {
+        let file = |ext: &str| base.with_extension(ext);
+        match self.emit {
+            None => {
+                let mut cmd = Command::new(&self.exe);
+                cmd.arg("run").args(flags).args(&self.forward).arg(fth);
+                Ok(cmd)
+            }
+            Some(Emit::C) => {
+                self.compile(flags, fth, "c", &file("c"))?;
+                self.link(&file("c"), &file("out"), false)?;
+                Ok(self.native(&file("out")))
+            }
+            Some(Emit::Asm) => {
+                self.compile(flags, fth, "asm", &file("s"))?;
+                build_step(Command::new("as").arg(file("s")).arg("-o").arg(file("o")))?;
+                self.link(&file("o"), &file("out"), false)?;
+                Ok(self.native(&file("out")))
+            }
+            Some(Emit::Bytecode) => {
+                self.compile(flags, fth, "bytecode", &file("kfb"))?;
+                let mut cmd = self.native(self.vm.as_ref().unwrap());
+                cmd.arg(file("kfb"));
+                Ok(cmd)
+            }
+            Some(Emit::Wat) => {
+                self.compile(flags, fth, "wat", &file("wat"))?;
+                build_step(
+                    Command::new(&self.wat2wasm)
+                        .arg(file("wat"))
+                        .arg("-o")
+                        .arg(file("wasm")),
+                )?;
+                let mut cmd = Command::new("node");
+                cmd.arg(self.runtime.join("wasm_host.mjs"))
+                    .arg(file("wasm"));
+                Ok(cmd)
+            }
+            Some(Emit::Llvm) => {
+                let (llc, llc_flags, _) = self.llc.as_ref().unwrap();
+                self.compile(flags, fth, "", &file("ll"))?;
+                build_step(
+                    Command::new(llc)
+                        .args(llc_flags)
+                        .arg("-filetype=obj")
+                        .arg(file("ll"))
+                        .arg("-o")
+                        .arg(file("o")),
+                )?;
+                self.link(&file("o"), &file("out"), true)?;
+                Ok(self.native(&file("out")))
+            }
+            Some(Emit::Kir) => unreachable!(),
+        }
+    }
+
+    fn check(&self, src: &Path, base: &Path) -> Result<Vec<String>, String> {
+        let expected = sidecar(src, "expected").ok_or("missing .expected file")?;
+        let flags: Vec<String> = sidecar(src, "flags")
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(String::from)
+            .collect();
+
+        let mut fth = src.to_path_buf();
+        if src.extension().is_some_and(|e| e == "pas") {
+            let kpascal = self.kpascal.as_ref().unwrap();
+            let pas = File::open(src).map_err(|e| format!("Read error: {}", e))?;
+            let out = tool(Command::new(kpascal).stdin(pas))?;
+            if !out.status.success() {
+                return Err(format!(
+                    "{} failed ({})\n{}",
+                    kpascal,
+                    out.status,
+                    String::from_utf8_lossy(&out.stderr).trim_end()
+                ));
+            }
+            fth = base.with_extension("fth");
+            fs::write(&fth, &out.stdout).map_err(|e| format!("Write error: {}", e))?;
+        }
+
+        let mut cmd = self.build(&flags, &fth, base)?;
+        let stdin = match File::open(src.with_extension("stdin")) {
+            Ok(f) => Stdio::from(f),
+            Err(_) => Stdio::null(),
+        };
+        let out = tool(cmd.stdin(stdin))?;
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+
+        let mut failures = Vec::new();
+        let status: i32 = match sidecar(src, "status") {
+            Some(s) => s
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid .status file: {}", s.trim()))?,
+            None => 0,
+        };
+        if out.status.code() != Some(status) {
+            let got = out
+                .status
+                .code()
+                .map_or(out.status.to_string(), |c| c.to_string());
+            let mut msg = format!("exit status {}, expected {}", got, status);
+            if !stderr.is_empty() {
+                msg += &format!("\n{}", stderr.trim_end());
+            }
+            failures.push(msg);
+        }
+        if stdout != expected {
+            let name = src.with_extension("expected");
+            let name = name.display().to_string();
+            failures.push(unified_diff(&name, "stdout", &expected, &stdout));
+        }
+        if let Some(expected) = sidecar(src, "stderr.expected") {
+            if stderr != expected {
+                let name = src.with_extension("stderr.expected");
+                let name = name.display().to_string();
+                failures.push(unified_diff(&name, "stderr", &expected, &stderr));
+            }
+        }
+        if let Some(expected) = sidecar(src, "kir.expected").filter(|_| self.forward.is_empty()) {
+            let kir = base.with_extension("kir");
+            self.compile(&flags, &fth, "kir", &kir)?;
+            let actual = fs::read_to_string(&kir).map_err(|e| format!("Read error: {}", e))?;
+            if actual != expected {
+                let name = src.with_extension("kir.expected");
+                let name = name.display().to_string();
+                failures.push(unified_diff(&name, "--emit=kir", &expected, &actual));
+            }
+        }
+        Ok(failures)
+    }
+
+    fn case(&self, src: &Path, base: &Path) -> Outcome {
+        if src.extension().is_some_and(|e| e == "pas") && self.kpascal.is_none() {
+            return Outcome::Skip("Pascal source, no --kpascal".into());
+        }
+        match self.check(src, base) {
+            Ok(failures) if failures.is_empty() => Outcome::Pass,
+            Ok(failures) => Outcome::Fail(failures),
+            Err(e) => Outcome::Fail(vec![e]),
+        }
+    }
+}
+
+// `kforthc test ...`; returns the exit status (0 when every case passed).
+pub fn run(args: &[String]) -> Result<i32, String> {
+    let usage = format!(
+        "Usage: {} test [--emit=llvm|c|wat|bytecode|asm] [--kpascal=PATH] [--runtime=DIR] [--build-dir=DIR] [compiler options] <file|dir>...",
+        args[0]
+    );
+    let mut emit = None;
+    let mut kpascal = None;
+    let mut runtime = PathBuf::from("runtime");
+    let mut build_dir = None;
+    let mut forward = Vec::new();
+    let mut paths = Vec::new();
+    for arg in &args[2..] {
+        if let Some(v) = arg.strip_prefix("--emit=") {
+            emit = Some(
+                parse_emit(v)
+                    .filter(|e| *e != Emit::Kir)
+                    .ok_or_else(|| format!("Unknown --emit value: {}\n{}", v, usage))?,
+            );
+        } else if let Some(v) = arg.strip_prefix("--kpascal=") {
+            kpascal = Some(v.to_string());
+        } else if let Some(v) = arg.strip_prefix("--runtime=") {
+            runtime = PathBuf::from(v);
+        } else if let Some(v) = arg.strip_prefix("--build-dir=") {
+            build_dir = Some(PathBuf::from(v));
+        } else if arg.starts_with('-') {
+            forward.push(arg.clone());
+        } else {
+            paths.push(arg.clone());
+        }
+    }
+    if paths.is_empty() {
+        return Err(usage);
+    }
+    let cases = discover(&paths)?;
+
+    let keep = build_dir.is_some();
+    let build = build_dir
+        .unwrap_or_else(|| env::temp_dir().join(format!("kforthc-test-{}", std::process::id())));
+    fs::create_dir_all(&build).map_err(|e| format!("Cannot create {}: {}", build.display(), e))?;
+
+    let mut runner = Runner {
+        exe: env::current_exe().map_err(|e| format!("Cannot locate kforthc: {}", e))?,
+        emit,
+        forward,
+        kpascal,
+        runtime,
+        build,
+        cc: env::var("CC").unwrap_or_else(|_| "cc".into()),
+        runner: env::var("RUNNER")
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(String::from)
+            .collect(),
+        llc: None,
+        wat2wasm: env::var("WAT2WASM").unwrap_or_else(|_| "wat2wasm".into()),
+        vm: None,
+    };
+    if emit == Some(Emit::Llvm) {
+        let llc = find_llc().ok_or("llc not found (tried: llc, llc-14; set LLC)")?;
+        let major = llc_major(&llc)?;
+        let given = runner
+            .forward
+            .iter()
+            .find_map(|a| a.strip_prefix("--llvm-version="))
+            .map(|v| v.split('.').next().unwrap_or(v).parse::<u32>());
+        let pass = given.is_none().then(|| major.to_string());
+        let version = match given {
+            Some(v) => v.map_err(|_| format!("Invalid --llvm-version value\n{}", usage))?,
+            None => major,
+        };
+        // llc-14 reads the opaque-pointer dialect with -opaque-pointers.
+        let mut llc_flags = Vec::new();
+        if major == 14 && version >= OPAQUE_POINTERS_SINCE {
+            llc_flags.push("-opaque-pointers".to_string());
+        }
+        runner.llc = Some((llc, llc_flags, pass));
+    }
+    if emit == Some(Emit::Bytecode) {
+        let vm = runner.build.join("kfvm");
+        build_step(
+            Command::new(&runner.cc)
+                .arg(runner.runtime.join("vm.c"))
+                .arg(runner.runtime.join("runtime.c"))
+                .arg("-o")
+                .arg(&vm)
+                .arg("-lm"),
+        )?;
+        runner.vm = Some(vm);
+    }
+
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for (k, src) in cases.iter().enumerate() {
+        let stem = src.file_stem().unwrap_or_default().to_string_lossy();
+        let base = runner.build.join(format!("{}.{}", k, stem));
+        match runner.case(src, &base) {
+            Outcome::Pass => {
+                passed += 1;
+                println!("{}: PASS", src.display());
+            }
+            Outcome::Skip(why) => {
+                skipped += 1;
+                println!("{}: SKIP ({})", src.display(), why);
+            }
+            Outcome::Fail(failures) => {
+                failed += 1;
+                println!("{}: FAIL", src.display());
+                for f in failures {
+                    println!("{}", f.trim_end());
+                }
+            }
+        }
+    }
+    if !keep {
+        let _ = fs::remove_dir_all(&runner.build);
+    }
+    println!(
+        "test result: {} passed, {} failed, {} skipped",
+        passed, failed, skipped
+    );
+    Ok(if failed == 0 { 0 } else { 1 })
+}
@@ -0,0 +1,754 @@
+// `--emit=wat`: kIR as a WebAssembly text module.
+//
+// Linear memory holds the data stack, the return stack and mem_cells at
+// fixed offsets, followed by string literals. Memory and Float32 services
+// are implemented in the module; I/O, the division-by-zero trap and the
+// transcendental kPascal routines are imported from "kforth" under their
+// runtime.c names (runtime/wasm_host.mjs implements them for node).
+//
+// Each definition dispatches over its kIR blocks with a loop and a
+// br_table and, like the C backend, returns the table index of its tail
+// callee (0 = none) to kf_run.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use crate::kir::{ExternArgMode, Func, Inst, MemAddr, Module, Op, Term};
+use crate::{llvm_word_sym, DivisionMode, RUNTIME_SYMBOLS};
+
+const MEM_CELLS: i64 = 134217728;
+const DS_BASE: i64 = 0x1000;
+const RS_BASE: i64 = DS_BASE + 1024 * 4;
+// Shared by all active definitions; each restores it on exit.
+const RS_BYTES: i64 = 65536 * 4;
+const MEM_BASE: i64 = RS_BASE + RS_BYTES;
+const STR_BASE: i64 = MEM_BASE + MEM_CELLS * 4;
+
+// Services implemented inside the module (see PRELUDE).
+const INTERNAL: &[&str] = &[
+    "rt_here",
+    "rt_allot",
+    "rt_comma",
+    "pvar_get",
+    "pvar_set",
+    "pfield_get",
+    "pfield_set",
+    "pbool",
+    "fadd_bits",
+    "fsub_bits",
+    "fmul_bits",
+    "fdiv_bits",
+    "fnegate_bits",
+    "fabs_bits",
+    "feq_bits",
+    "flt_bits",
+    "fle_bits",
+    "fzero_bits",
+    "finf_bits",
+    "fnan_bits",
+    "ffinite_bits",
+    "s_to_f_bits",
+    "f_bits_to_s",
+    "q16_16_to_f_bits",
+    "f_bits_to_q16_16",
+    "fround_i32_bits",
+    "kp_fabs_f32_bits",
+    "kp_fsqrt_f32_bits",
+    "kp_ffloor_f32_bits",
+    "kp_fceil_f32_bits",
+    "kp_fx_sqrt_i32",
+];
+
+const PRELUDE: &str = r#"  (type $word (func (result i32)))
+
+  (func $push (param $v i32)
+    (i32.store (global.get $sp) (local.get $v))
+    (global.set $sp (i32.add (global.get $sp) (i32.const 4))))
+  (func $pop (result i32)
+    (global.set $sp (i32.sub (global.get $sp) (i32.const 4)))
+    (i32.load (global.get $sp)))
+  ;; Doubles are ( lo hi ) with the high cell on top.
+  (func $push64 (param $d i64)
+    (call $push (i32.wrap_i64 (local.get $d)))
+    (call $push (i32.wrap_i64 (i64.shr_u (local.get $d) (i64.const 32)))))
+  (func $pop64 (result i64)
+    (local $hi i64)
+    (local.set $hi (i64.extend_i32_u (call $pop)))
+    (i64.or (i64.shl (local.get $hi) (i64.const 32)) (i64.extend_i32_u (call $pop))))
+  (func $rpush (param $v i32)
+    (i32.store (global.get $rsp) (local.get $v))
+    (global.set $rsp (i32.add (global.get $rsp) (i32.const 4))))
+  (func $rpop (result i32)
+    (global.set $rsp (i32.sub (global.get $rsp) (i32.const 4)))
+    (i32.load (global.get $rsp)))
+  ;; 0/1 -> 0/-1
+  (func $flag (param $c i32) (result i32)
+    (i32.sub (i32.const 0) (local.get $c)))
+
+  ;; Symmetric or floored (remainder, quotient); MIN / -1 wraps.
+  (func $sdivrem (param $d i64) (param $n i64) (param $floored i32) (param $word i32)
+    (result i64 i64)
+    (local $r i64) (local $q i64)
+    (if (i64.eqz (local.get $n))
+      (then (call $rt_div_zero (local.get $word)) (unreachable)))
+    (if (i64.eq (local.get $n) (i64.const -1))
+      (then (local.set $q (i64.sub (i64.const 0) (local.get $d))))
+      (else
+        (local.set $q (i64.div_s (local.get $d) (local.get $n)))
+        (local.set $r (i64.rem_s (local.get $d) (local.get $n)))))
+    (if (i32.and (local.get $floored)
+          (i32.and (i64.ne (local.get $r) (i64.const 0))
+                   (i64.lt_s (i64.xor (local.get $r) (local.get $n)) (i64.const 0))))
+      (then
+        (local.set $q (i64.sub (local.get $q) (i64.const 1)))
+        (local.set $r (i64.add (local.get $r) (local.get $n)))))
+    (local.get $r) (local.get $q))
+  (func $udivrem (param $d i64) (param $n i64) (param $word i32) (result i64 i64)
+    (if (i64.eqz (local.get $n))
+      (then (call $rt_div_zero (local.get $word)) (unreachable)))
+    (i64.rem_u (local.get $d) (local.get $n))
+    (i64.div_u (local.get $d) (local.get $n)))
+
+  ;; Byte address of the cell holding `addr`, clamped as in runtime.c.
+  (func $cell (param $addr i32) (result i32)
+    (local $idx i32)
+    (local.set $idx (i32.div_s (local.get $addr) (i32.const 4)))
+    (if (i32.lt_s (local.get $idx) (i32.const 0)) (then (local.set $idx (i32.const 0))))
+    (if (i32.gt_s (local.get $idx) (i32.const MEM_LAST))
+      (then (local.set $idx (i32.const MEM_LAST))))
+    (i32.add (i32.const MEM_BASE) (i32.shl (local.get $idx) (i32.const 2))))
+  (func $pvar_get (param $addr i32) (result i32)
+    (i32.load (call $cell (local.get $addr))))
+  (func $pvar_set (param $v i32) (param $addr i32)
+    (i32.store (call $cell (local.get $addr)) (local.get $v)))
+  (func $pfield_get (param $obj i32) (param $off i32) (result i32)
+    (i32.load (call $cell (i32.add (local.get $obj) (local.get $off)))))
+  (func $pfield_set (param $v i32) (param $obj i32) (param $off i32)
+    (i32.store (call $cell (i32.add (local.get $obj) (local.get $off))) (local.get $v)))
+  (func $rt_here (result i32)
+    (global.get $here))
+  (func $rt_allot (param $n i32)
+    (local $next i64)
+    (local.set $next (i64.add (i64.extend_i32_s (global.get $here)) (i64.extend_i32_s (local.get $n))))
+    (if (i64.lt_s (local.get $next) (i64.const 0)) (then (local.set $next (i64.const 0))))
+    (if (i64.gt_s (local.get $next) (i64.const MEM_BYTES))
+      (then (local.set $next (i64.const MEM_BYTES))))
+    (global.set $here (i32.wrap_i64 (local.get $next))))
+  (func $rt_comma (param $v i32)
+    (i32.store (call $cell (global.get $here)) (local.get $v))
+    (call $rt_allot (i32.const 4)))
+  (func $pbool (param $x i32) (result i32)
+    (i32.ne (local.get $x) (i32.const 0)))
+
+  ;; float -> int32_t as x86 converts it: NaN and out of range give INT32_MIN.
+  (func $f2i (param $x f32) (result i32)
+    (if (result i32)
+      (i32.and (f32.ge (local.get $x) (f32.const -2147483648))
+               (f32.lt (local.get $x) (f32.const 2147483648)))
+      (then (i32.trunc_f32_s (local.get $x)))
+      (else (i32.const 0x80000000))))
+  (func $round (param $x f32) (result i32)
+    (if (result i32) (f32.ge (local.get $x) (f32.const 0))
+      (then (call $f2i (f32.add (local.get $x) (f32.const 0.5))))
+      (else (call $f2i (f32.sub (local.get $x) (f32.const 0.5))))))
+  (func $fadd_bits (param $a i32) (param $b i32) (result i32)
+    (i32.reinterpret_f32 (f32.add (f32.reinterpret_i32 (local.get $a)) (f32.reinterpret_i32 (local.get $b)))))
+  (func $fsub_bits (param $a i32) (param $b i32) (result i32)
+    (i32.reinterpret_f32 (f32.sub (f32.reinterpret_i32 (local.get $a)) (f32.reinterpret_i32 (local.get $b)))))
+  (func $fmul_bits (param $a i32) (param $b i32) (result i32)
+    (i32.reinterpret_f32 (f32.mul (f32.reinterpret_i32 (local.get $a)) (f32.reinterpret_i32 (local.get $b)))))
+  (func $fdiv_bits (param $a i32) (param $b i32) (result i32)
+    (i32.reinterpret_f32 (f32.div (f32.reinterpret_i32 (local.get $a)) (f32.reinterpret_i32 (local.get $b)))))
+  (func $fnegate_bits (param $a i32) (result i32)
+    (i32.xor (local.get $a) (i32.const 0x80000000)))
+  (func $fabs_bits (param $a i32) (result i32)
+    (i32.and (local.get $a) (i32.const 0x7FFFFFFF)))
+  (func $feq_bits (param $a i32) (param $b i32) (result i32)
+    (call $flag (f32.eq (f32.reinterpret_i32 (local.get $a)) (f32.reinterpret_i32 (local.get $b)))))
+  (func $flt_bits (param $a i32) (param $b i32) (result i32)
+    (call $flag (f32.lt (f32.reinterpret_i32 (local.get $a)) (f32.reinterpret_i32 (local.get $b)))))
+  (func $fle_bits (param $a i32) (param $b i32) (result i32)
+    (call $flag (f32.le (f32.reinterpret_i32 (local.get $a)) (f32.reinterpret_i32 (local.get $b)))))
+  (func $fzero_bits (param $a i32) (result i32)
+    (call $flag (i32.eqz (i32.and (local.get $a) (i32.const 0x7FFFFFFF)))))
+  (func $finf_bits (param $a i32) (result i32)
+    (call $flag (i32.eq (i32.and (local.get $a) (i32.const 0x7FFFFFFF)) (i32.const 0x7F800000))))
+  (func $fnan_bits (param $a i32) (result i32)
+    (call $flag (i32.gt_u (i32.and (local.get $a) (i32.const 0x7FFFFFFF)) (i32.const 0x7F800000))))
+  (func $ffinite_bits (param $a i32) (result i32)
+    (call $flag (i32.lt_u (i32.and (local.get $a) (i32.const 0x7FFFFFFF)) (i32.const 0x7F800000))))
+  (func $s_to_f_bits (param $a i32) (result i32)
+    (i32.reinterpret_f32 (f32.convert_i32_s (local.get $a))))
+  (func $f_bits_to_s (param $a i32) (result i32)
+    (call $f2i (f32.reinterpret_i32 (local.get $a))))
+  (func $q16_16_to_f_bits (param $a i32) (result i32)
+    (i32.reinterpret_f32 (f32.div (f32.convert_i32_s (local.get $a)) (f32.const 65536))))
+  (func $f_bits_to_q16_16 (param $a i32) (result i32)
+    (call $f2i (f32.mul (f32.reinterpret_i32 (local.get $a)) (f32.const 65536))))
+  (func $fround_i32_bits (param $a i32) (result i32)
+    (call $round (f32.reinterpret_i32 (local.get $a))))
+  (func $kp_fabs_f32_bits (param $a i32) (result i32)
+    (i32.reinterpret_f32 (f32.abs (f32.reinterpret_i32 (local.get $a)))))
+  (func $kp_fsqrt_f32_bits (param $a i32) (result i32)
+    (i32.reinterpret_f32 (f32.sqrt (f32.reinterpret_i32 (local.get $a)))))
+  (func $kp_ffloor_f32_bits (param $a i32) (result i32)
+    (i32.reinterpret_f32 (f32.floor (f32.reinterpret_i32 (local.get $a)))))
+  (func $kp_fceil_f32_bits (param $a i32) (result i32)
+    (i32.reinterpret_f32 (f32.ceil (f32.reinterpret_i32 (local.get $a)))))
+  (func $kp_fx_sqrt_i32 (param $x i32) (result i32)
+    (if (result i32) (i32.le_s (local.get $x) (i32.const 0))
+      (then (i32.const 0))
+      (else (call $f2i (f32.floor (f32.sqrt (f32.convert_i32_s (local.get $x))))))))
+
+  ;; Runs word `w` and the chain of tail calls it returns.
+  (func $kf_run (param $w i32)
+    (block $done
+      (loop $next
+        (br_if $done (i32.eqz (local.get $w)))
+        (local.set $w (call_indirect (type $word) (local.get $w)))
+        (br $next))))
+"#;
+
+// Import signature of a runtime service.
+fn signature(mode: ExternArgMode) -> &'static str {
+    match mode {
+        ExternArgMode::PopI32Void | ExternArgMode::StrVoid => "(param i32)",
+        ExternArgMode::Void => "",
+        ExternArgMode::RetI32Push => "(result i32)",
+        ExternArgMode::PopI32RetI32Push => "(param i32) (result i32)",
+        ExternArgMode::Pop2I32Void => "(param i32 i32)",
+        ExternArgMode::Pop2I32RetI32Push => "(param i32 i32) (result i32)",
+        ExternArgMode::Pop3I32Void => "(param i32 i32 i32)",
+        ExternArgMode::PopI64Void => "(param i64)",
+        ExternArgMode::PopI64I32Void => "(param i64 i32)",
+    }
+}
+
+// Printable ASCII as is, everything else as `\hh`.
+fn wat_string(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &b in bytes {
+        if (0x20..0x7f).contains(&b) && b != b'"' && b != b'\\' {
+            out.push(b as char);
+        } else {
+            let _ = write!(out, "\\{:02x}", b);
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn bin(expr: &str) -> String {
+    format!(
+        "(local.set $b (call $pop)) (local.set $a (call $pop)) (call $push {})",
+        expr
+    )
+}
+
+fn un(expr: &str) -> String {
+    format!("(local.set $a (call $pop)) (call $push {})", expr)
+}
+
+fn cmp(instr: &str) -> String {
+    bin(&format!(
+        "(call $flag ({} (local.get $a) (local.get $b)))",
+        instr
+    ))
+}
+
+fn dbin(expr: &str) -> String {
+    format!(
+        "(local.set $e (call $pop64)) (local.set $d (call $pop64)) (call $push64 {})",
+        expr
+    )
+}
+
+fn dcmp(instr: &str) -> String {
+    format!(
+        "(local.set $e (call $pop64)) (local.set $d (call $pop64)) \
+         (call $push (call $flag ({} (local.get $d) (local.get $e))))",
+        instr
+    )
+}
+
+// Replaces the top `depth` (at most 4) cells by `order` (0 = deepest).
+fn permute(depth: usize, order: &[usize]) -> String {
+    let mut s = String::new();
+    for k in 0..depth {
+        if order.contains(&k) {
+            let _ = write!(
+                s,
+                "(local.set $x{} (i32.load (i32.sub (global.get $sp) (i32.const {})))) ",
+                k,
+                4 * (depth - k)
+            );
+        }
+    }
+    let _ = write!(
+        s,
+        "(global.set $sp (i32.sub (global.get $sp) (i32.const {})))",
+        4 * depth
+    );
+    for k in order {
+        let _ = write!(s, " (call $push (local.get $x{}))", k);
+    }
+    s
+}
+
+struct WatEmitter<'m> {
+    out: String,
+    // Runtime symbol -> import line, for the imported services used.
+    imports: BTreeMap<String, String>,
+    symbols: HashMap<&'static str, &'static str>,
+    index: HashMap<&'m str, usize>,
+    // String literal -> address, in allocation order.
+    strings: Vec<(Vec<u8>, i64)>,
+    str_end: i64,
+    floored: bool,
+    labels: usize,
+}
+
+impl<'m> WatEmitter<'m> {
+    fn line(&mut self, s: &str) {
+        self.out.push_str(s);
+        self.out.push('\n');
+    }
+
+    fn index_of(&self, w: &str) -> Result<usize, String> {
+        self.index
+            .get(w)
+            .copied()
+            .ok_or_else(|| format!("Call to unknown word: {}", w))
+    }
+
+    // Address of a NUL-terminated copy of `s` in linear memory.
+    fn string(&mut self, s: &str) -> i64 {
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.push(0);
+        if let Some((_, addr)) = self.strings.iter().find(|(b, _)| *b == bytes) {
+            return *addr;
+        }
+        let addr = self.str_end;
+        self.str_end += bytes.len() as i64;
+        self.strings.push((bytes, addr));
+        addr
+    }
+
+    fn runtime(&mut self, key: &str, mode: ExternArgMode) -> Result<&'static str, String> {
+        let sym = *self
+            .symbols
+            .get(key)
+            .ok_or_else(|| format!("Unknown extern service word: {}", key))?;
+        if !INTERNAL.contains(&sym) {
+            self.imports.entry(sym.to_string()).or_insert_with(|| {
+                format!(
+                    "  (import \"kforth\" \"{}\" (func ${} {}))",
+                    sym,
+                    sym,
+                    signature(mode)
+                )
+            });
+        }
+        Ok(sym)
+    }
+
+    // Pushes the (remainder, quotient) left by $sdivrem/$udivrem as selected.
+    fn divrem_push(push_rem: bool, push_quo: bool) -> String {
+        let mut s = String::from(" (local.set $q) (local.set $r)");
+        if push_rem {
+            s.push_str(" (call $push (i32.wrap_i64 (local.get $r)))");
+        }
+        if push_quo {
+            s.push_str(" (call $push (i32.wrap_i64 (local.get $q)))");
+        }
+        s
+    }
+
+    fn div(&self, word: i64, push_rem: bool, push_quo: bool, unsigned: bool) -> String {
+        let call = if unsigned {
+            format!(
+                "(call $udivrem (i64.extend_i32_u (local.get $a)) (i64.extend_i32_u (local.get $b)) (i32.const {}))",
+                word
+            )
+        } else {
+            format!(
+                "(call $sdivrem (i64.extend_i32_s (local.get $a)) (i64.extend_i32_s (local.get $b)) (i32.const {}) (i32.const {}))",
+                self.floored as i32, word
+            )
+        };
+        format!(
+            "(local.set $b (call $pop)) (local.set $a (call $pop)) {}{}",
+            call,
+            Self::divrem_push(push_rem, push_quo)
+        )
+    }
+
+    fn op(&mut self, op: Op, word: i64) -> String {
+        match op {
+            Op::Dup => permute(1, &[0, 0]),
+            Op::Drop => permute(1, &[]),
+            Op::Swap => permute(2, &[1, 0]),
+            Op::Over => permute(2, &[0, 1, 0]),
+            Op::Rot => permute(3, &[1, 2, 0]),
+            Op::MinusRot => permute(3, &[2, 0, 1]),
+            Op::Nip => permute(2, &[1]),
+            Op::Tuck => permute(2, &[1, 0, 1]),
+            Op::QDup => "(local.set $a (call $pop)) (call $push (local.get $a)) \
+                         (if (local.get $a) (then (call $push (local.get $a))))"
+                .into(),
+            Op::TwoDup => permute(2, &[0, 1, 0, 1]),
+            Op::TwoDrop => permute(2, &[]),
+            Op::TwoSwap => permute(4, &[2, 3, 0, 1]),
+            Op::TwoOver => permute(4, &[0, 1, 2, 3, 0, 1]),
+            Op::Pick => "(local.set $a (call $pop)) (call $push (i32.load (i32.sub (global.get $sp) \
+                         (i32.shl (i32.add (local.get $a) (i32.const 1)) (i32.const 2)))))"
+                .into(),
+            Op::Roll => {
+                // ( xu ... x0 u -- xu-1 ... x0 xu ): shift cells down.
+                self.labels += 1;
+                let n = self.labels;
+                format!(
+                    "(local.set $a (call $pop)) (local.set $c (i32.sub (global.get $sp) (i32.const 4))) \
+                     (local.set $b (i32.sub (local.get $c) (i32.shl (local.get $a) (i32.const 2)))) \
+                     (local.set $a (i32.load (local.get $b))) \
+                     (block $roll_done{n} (loop $roll{n} \
+                     (br_if $roll_done{n} (i32.ge_s (local.get $b) (local.get $c))) \
+                     (i32.store (local.get $b) (i32.load offset=4 (local.get $b))) \
+                     (local.set $b (i32.add (local.get $b) (i32.const 4))) (br $roll{n}))) \
+                     (i32.store (local.get $c) (local.get $a))"
+                )
+            }
+            Op::Depth => format!(
+                "(call $push (i32.shr_s (i32.sub (global.get $sp) (i32.const {})) (i32.const 2)))",
+                DS_BASE
+            ),
+            Op::ToR => "(call $rpush (call $pop))".into(),
+            Op::RFrom => "(call $push (call $rpop))".into(),
+            Op::RFetch => "(local.set $a (call $rpop)) (call $rpush (local.get $a)) \
+                           (call $push (local.get $a))"
+                .into(),
+            Op::TwoToR => "(local.set $b (call $pop)) (call $rpush (call $pop)) \
+                           (call $rpush (local.get $b))"
+                .into(),
+            Op::TwoRFrom => "(local.set $b (call $rpop)) (call $push (call $rpop)) \
+                             (call $push (local.get $b))"
+                .into(),
+            Op::TwoRFetch => "(local.set $b (call $rpop)) (local.set $a (call $rpop)) \
+                              (call $rpush (local.get $a)) (call $rpush (local.get $b)) \
+                              (call $push (local.get $a)) (call $push (local.get $b))"
+                .into(),
+            Op::Add => bin("(i32.add (local.get $a) (local.get $b))"),
+            Op::Sub => bin("(i32.sub (local.get $a) (local.get $b))"),
+            Op::Mul => bin("(i32.mul (local.get $a) (local.get $b))"),
+            Op::Div => self.div(word, false, true, false),
+            Op::Mod => self.div(word, true, false, false),
+            Op::DivMod => self.div(word, true, true, false),
+            Op::UDiv => self.div(word, false, true, true),
+            Op::UMod => self.div(word, true, false, true),
+            Op::StarSlash | Op::StarSlashMod => format!(
+                "(local.set $c (call $pop)) (local.set $b (call $pop)) (local.set $a (call $pop)) \
+                 (call $sdivrem (i64.mul (i64.extend_i32_s (local.get $a)) (i64.extend_i32_s (local.get $b))) \
+                 (i64.extend_i32_s (local.get $c)) (i32.const {}) (i32.const {})){}",
+                self.floored as i32,
+                word,
+                Self::divrem_push(op == Op::StarSlashMod, true)
+            ),
+            Op::FmMod | Op::SmRem => format!(
+                "(local.set $a (call $pop)) (local.set $d (call $pop64)) \
+                 (call $sdivrem (local.get $d) (i64.extend_i32_s (local.get $a)) (i32.const {}) (i32.const {})){}",
+                (op == Op::FmMod) as i32,
+                word,
+                Self::divrem_push(true, true)
+            ),
+            Op::UmSlashMod => format!(
+                "(local.set $a (call $pop)) (local.set $d (call $pop64)) \
+                 (call $udivrem (local.get $d) (i64.extend_i32_u (local.get $a)) (i32.const {})){}",
+                word,
+                Self::divrem_push(true, true)
+            ),
+            Op::Negate => un("(i32.sub (i32.const 0) (local.get $a))"),
+            Op::Abs => un("(select (i32.sub (i32.const 0) (local.get $a)) (local.get $a) \
+                           (i32.lt_s (local.get $a) (i32.const 0)))"),
+            Op::Min => bin("(select (local.get $a) (local.get $b) (i32.lt_s (local.get $a) (local.get $b)))"),
+            Op::Max => bin("(select (local.get $a) (local.get $b) (i32.gt_s (local.get $a) (local.get $b)))"),
+            Op::OnePlus => un("(i32.add (local.get $a) (i32.const 1))"),
+            Op::OneMinus => un("(i32.sub (local.get $a) (i32.const 1))"),
+            Op::TwoStar => un("(i32.shl (local.get $a) (i32.const 1))"),
+            Op::TwoSlash => un("(i32.shr_s (local.get $a) (i32.const 1))"),
+            Op::And => bin("(i32.and (local.get $a) (local.get $b))"),
+            Op::Or => bin("(i32.or (local.get $a) (local.get $b))"),
+            Op::Xor => bin("(i32.xor (local.get $a) (local.get $b))"),
+            Op::Invert => un("(i32.xor (local.get $a) (i32.const -1))"),
+            // wasm shifts already take the count modulo 32.
+            Op::Lshift => bin("(i32.shl (local.get $a) (local.get $b))"),
+            Op::Rshift => bin("(i32.shr_u (local.get $a) (local.get $b))"),
+            Op::Arshift => bin("(i32.shr_s (local.get $a) (local.get $b))"),
+            Op::Cells => un("(i32.shl (local.get $a) (i32.const 2))"),
+            Op::CellPlus => un("(i32.add (local.get $a) (i32.const 4))"),
+            Op::Eq => cmp("i32.eq"),
+            Op::Ne => cmp("i32.ne"),
+            Op::Lt => cmp("i32.lt_s"),
+            Op::Le => cmp("i32.le_s"),
+            Op::Gt => cmp("i32.gt_s"),
+            Op::Ge => cmp("i32.ge_s"),
+            Op::ULt => cmp("i32.lt_u"),
+            Op::UGt => cmp("i32.gt_u"),
+            Op::ZeroEq => un("(call $flag (i32.eqz (local.get $a)))"),
+            Op::ZeroLt => un("(call $flag (i32.lt_s (local.get $a) (i32.const 0)))"),
+            Op::UmStar => "(local.set $b (call $pop)) (local.set $a (call $pop)) (call $push64 \
+                           (i64.mul (i64.extend_i32_u (local.get $a)) (i64.extend_i32_u (local.get $b))))"
+                .into(),
+            Op::MStar => "(local.set $b (call $pop)) (local.set $a (call $pop)) (call $push64 \
+                          (i64.mul (i64.extend_i32_s (local.get $a)) (i64.extend_i32_s (local.get $b))))"
+                .into(),
+            Op::SToD => "(call $push64 (i64.extend_i32_s (call $pop)))".into(),
+            Op::DAdd => dbin("(i64.add (local.get $d) (local.get $e))"),
+            Op::DSub => dbin("(i64.sub (local.get $d) (local.get $e))"),
+            Op::DNegate => "(call $push64 (i64.sub (i64.const 0) (call $pop64)))".into(),
+            Op::DAbs => "(local.set $d (call $pop64)) (call $push64 (select (i64.sub (i64.const 0) \
+                         (local.get $d)) (local.get $d) (i64.lt_s (local.get $d) (i64.const 0))))"
+                .into(),
+            Op::DEq => dcmp("i64.eq"),
+            Op::DLt => dcmp("i64.lt_s"),
+            Op::DZeroEq => "(call $push (call $flag (i64.eqz (call $pop64))))".into(),
+            Op::MPlus => "(local.set $a (call $pop)) (call $push64 (i64.add (call $pop64) \
+                          (i64.extend_i32_s (local.get $a))))"
+                .into(),
+            Op::DTwoStar => "(call $push64 (i64.shl (call $pop64) (i64.const 1)))".into(),
+            Op::DTwoSlash => "(call $push64 (i64.shr_s (call $pop64) (i64.const 1)))".into(),
+            Op::TwoFetch => "(local.set $a (call $pop)) \
+                             (call $push (call $pvar_get (i32.add (local.get $a) (i32.const 4)))) \
+                             (call $push (call $pvar_get (local.get $a)))"
+                .into(),
+            Op::TwoStore => "(local.set $a (call $pop)) (local.set $b (call $pop)) (local.set $c (call $pop)) \
+                             (call $pvar_set (local.get $b) (local.get $a)) \
+                             (call $pvar_set (local.get $c) (i32.add (local.get $a) (i32.const 4)))"
+                .into(),
+        }
+    }
+
+    fn inst(&mut self, inst: &Inst, word: i64) -> Result<String, String> {
+        Ok(match inst {
+            Inst::Lit(v) => format!("(call $push (i32.const {}))", v),
+            Inst::Op(op) => self.op(*op, word),
+            Inst::PickConst(n) => format!(
+                "(call $push (i32.load (i32.sub (global.get $sp) (i32.const {}))))",
+                ((*n as i64 + 1) * 4) as i32
+            ),
+            Inst::RollConst(n) if *n <= 0 => String::new(),
+            Inst::RollConst(n) if *n <= 3 => {
+                let depth = *n as usize + 1;
+                let mut order: Vec<usize> = (1..depth).collect();
+                order.push(0);
+                permute(depth, &order)
+            }
+            Inst::RollConst(n) => {
+                let roll = self.op(Op::Roll, word);
+                format!("(call $push (i32.const {})) {}", n, roll)
+            }
+            // As in the C backend: a direct call, then its tail-call chain.
+            Inst::Call(w) => {
+                self.index_of(w)?;
+                format!("(call $kf_run (call ${}))", llvm_word_sym(w))
+            }
+            Inst::Service(svc) => {
+                let sym = self.runtime(svc.name, svc.mode)?;
+                match svc.mode {
+                    ExternArgMode::PopI32Void => format!("(call ${} (call $pop))", sym),
+                    ExternArgMode::Void => format!("(call ${})", sym),
+                    ExternArgMode::RetI32Push => format!("(call $push (call ${}))", sym),
+                    ExternArgMode::PopI32RetI32Push => {
+                        format!("(call $push (call ${} (call $pop)))", sym)
+                    }
+                    ExternArgMode::Pop2I32Void => format!(
+                        "(local.set $b (call $pop)) (local.set $a (call $pop)) \
+                         (call ${} (local.get $a) (local.get $b))",
+                        sym
+                    ),
+                    ExternArgMode::Pop2I32RetI32Push => {
+                        bin(&format!("(call ${} (local.get $a) (local.get $b))", sym))
+                    }
+                    ExternArgMode::Pop3I32Void => format!(
+                        "(local.set $c (call $pop)) (local.set $b (call $pop)) (local.set $a (call $pop)) \
+                         (call ${} (local.get $a) (local.get $b) (local.get $c))",
+                        sym
+                    ),
+                    ExternArgMode::PopI64Void => format!("(call ${} (call $pop64))", sym),
+                    ExternArgMode::PopI64I32Void => format!(
+                        "(local.set $a (call $pop)) (call ${} (call $pop64) (local.get $a))",
+                        sym
+                    ),
+                    ExternArgMode::StrVoid => {
+                        return Err(format!("Service {} needs a string operand", svc.name))
+                    }
+                }
+            }
+            Inst::Type(s) => {
+                let sym = self.runtime("TYPE", ExternArgMode::StrVoid)?;
+                let addr = self.string(s);
+                format!("(call ${} (i32.const {}))", sym, addr)
+            }
+            Inst::Line(n) => format!(";; line {}", n),
+            Inst::MemCheck(c) => {
+                self.imports.entry("rt_mem_check".into()).or_insert_with(|| {
+                    "  (import \"kforth\" \"rt_mem_check\" (func $rt_mem_check (param i32 i32 i32)))"
+                        .into()
+                });
+                let top = "(i32.load (i32.sub (global.get $sp) (i32.const 4)))";
+                let addr = match c.addr {
+                    MemAddr::Top(k) => format!("(i32.add {} (i32.const {}))", top, k),
+                    MemAddr::Field => format!(
+                        "(i32.add (i32.load (i32.sub (global.get $sp) (i32.const 8))) {})",
+                        top
+                    ),
+                };
+                format!(
+                    "(call $rt_mem_check {} (i32.const {}) (i32.const {}))",
+                    addr, c.access as i32, word
+                )
+            }
+        })
+    }
+
+    fn func(&mut self, f: &Func) -> Result<(), String> {
+        let word = self.string(&f.name);
+        let rstack = f.uses_return_stack();
+        self.line(&format!("  (func ${} (type $word)", llvm_word_sym(&f.name)));
+        self.line("    (local $blk i32) (local $a i32) (local $b i32) (local $c i32)");
+        self.line("    (local $x0 i32) (local $x1 i32) (local $x2 i32) (local $x3 i32)");
+        self.line("    (local $d i64) (local $e i64) (local $r i64) (local $q i64)");
+        // The return stack is released on every exit and on self tail calls.
+        let release = if rstack {
+            self.line("    (local $rbase i32)");
+            self.line("    (local.set $rbase (global.get $rsp))");
+            "(global.set $rsp (local.get $rbase)) "
+        } else {
+            ""
+        };
+        let n = f.blocks.len();
+        self.line("    (loop $dispatch");
+        for id in (0..n).rev() {
+            self.line(&format!("    (block ${}", f.label(id)));
+        }
+        let targets: Vec<String> = (0..n).map(|id| format!("${}", f.label(id))).collect();
+        self.line(&format!(
+            "      (br_table {} (local.get $blk)))",
+            targets.join(" ")
+        ));
+        for (id, block) in f.blocks.iter().enumerate() {
+            for inst in &block.insts {
+                let s = self.inst(inst, word)?;
+                if !s.is_empty() {
+                    self.line(&format!("      {}", s));
+                }
+            }
+            let term = match &block.term {
+                Term::Return => format!("{}(return (i32.const 0))", release),
+                Term::Jump(t) => format!("(local.set $blk (i32.const {})) (br $dispatch)", t),
+                Term::Branch { nonzero, zero } => format!(
+                    "(local.set $blk (select (i32.const {}) (i32.const {}) (call $pop))) (br $dispatch)",
+                    nonzero, zero
+                ),
+                Term::TailCall(w) if *w == f.name => {
+                    format!("{}(local.set $blk (i32.const 0)) (br $dispatch)", release)
+                }
+                Term::TailCall(w) => {
+                    format!("{}(return (i32.const {}))", release, self.index_of(w)?)
+                }
+            };
+            let close = if id + 1 < n { ")" } else { "" };
+            self.line(&format!("      {}{}", term, close));
+        }
+        self.line("    )");
+        self.line("    (unreachable))");
+        self.line("");
+        Ok(())
+    }
+}
+
+pub fn emit(m: &Module) -> Result<String, String> {
+    let mut e = WatEmitter {
+        out: String::new(),
+        imports: BTreeMap::new(),
+        symbols: RUNTIME_SYMBOLS.iter().copied().collect(),
+        index: m
+            .funcs
+            .iter()
+            .enumerate()
+            .map(|(k, f)| (f.name.as_str(), k + 1))
+            .collect(),
+        strings: Vec::new(),
+        str_end: STR_BASE,
+        floored: m.division == DivisionMode::Floored,
+        labels: 0,
+    };
+    for f in &m.funcs {
+        e.func(f)?;
+    }
+    let entry = e.index_of(&m.entry)?;
+    let body = std::mem::take(&mut e.out);
+
+    e.line(";; Generated by kforthc; run with runtime/wasm_host.mjs.");
+    e.line("(module");
+    e.line("  (import \"kforth\" \"rt_div_zero\" (func $rt_div_zero (param i32)))");
+    let imports: Vec<String> = e.imports.values().cloned().collect();
+    for i in imports {
+        e.line(&i);
+    }
+    let pages = (e.str_end + 0xFFFF) / 0x10000;
+    e.line(&format!("  (memory (export \"memory\") {})", pages));
+    e.line(&format!("  (global $sp (mut i32) (i32.const {}))", DS_BASE));
+    e.line(&format!(
+        "  (global $rsp (mut i32) (i32.const {}))",
+        RS_BASE
+    ));
+    // rt_heap_reset: HERE starts after the static data. The host's
+    // ALLOCATE / FREE / RESIZE work on HERE and the cells directly.
+    e.line(&format!(
+        "  (global $here (export \"here\") (mut i32) (i32.const {}))",
+        (m.here as i64).clamp(0, MEM_CELLS * 4)
+    ));
+    e.line(&format!(
+        "  (global (export \"mem_base\") i32 (i32.const {}))",
+        MEM_BASE
+    ));
+    e.out.push_str(
+        &PRELUDE
+            .replace("MEM_LAST", &(MEM_CELLS - 1).to_string())
+            .replace("MEM_BYTES", &(MEM_CELLS * 4).to_string())
+            .replace("MEM_BASE", &MEM_BASE.to_string()),
+    );
+    e.line("");
+    e.out.push_str(&body);
+
+    let table: Vec<String> = m
+        .funcs
+        .iter()
+        .map(|f| format!("${}", llvm_word_sym(&f.name)))
+        .collect();
+    e.line(&format!("  (table {} funcref)", m.funcs.len() + 1));
+    e.line(&format!("  (elem (i32.const 1) func {})", table.join(" ")));
+    // The data image, applied in order like rt_heap_reset.
+    for (&addr, &v) in &m.static_inits {
+        let idx = (addr / 4).clamp(0, (MEM_CELLS - 1) as i32) as i64;
+        e.line(&format!(
+            "  (data (i32.const {}) {})",
+            MEM_BASE + idx * 4,
+            wat_string(&v.to_le_bytes())
+        ));
+    }
+    let strings = std::mem::take(&mut e.strings);
+    for (bytes, addr) in &strings {
+        e.line(&format!(
+            "  (data (i32.const {}) {})",
+            addr,
+            wat_string(bytes)
+        ));
+    }
+    e.line(&format!(
+        "  (func (export \"main\") (call $kf_run (i32.const {})))",
+        entry
+    ));
+    e.line(")");
+    Ok(e.out)
+}
@@ -0,0 +1,95 @@
+// Compile errors that `kforthc test` cannot express (it needs a build).
+
+use std::fs;
+use std::process::Command;
+
+fn compile_error(name: &str, src: &str, args: &[&str]) -> String {
+    let dir = std::env::temp_dir().join(format!("kforthc-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("in.fth");
+    fs::write(&input, src).unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_kforthc"))
+        .args(args)
+        .arg(&input)
+        .arg(dir.join("out.ll"))
+        .output()
+        .unwrap();
+    let _ = fs::remove_dir_all(&dir);
+    assert_eq!(out.status.code(), Some(1));
+    String::from_utf8_lossy(&out.stderr).into_owned()
+}
+
+const KPASCAL_IL: &str =
+    "( ROUTINE program::half => HALF )\n: HALF 2 / ;\n: MAIN -7 HALF . ;\nMAIN\n";
+
+#[test]
+fn kpascal_il_rejects_floored_option() {
+    let err = compile_error("div-opt", KPASCAL_IL, &["--division=floored"]);
+    assert!(
+        err.starts_with("Floored division is not available for kPascal IL"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn kpascal_il_rejects_floored_pragma() {
+    let src = format!("( DIVISION FLOORED )\n{}", KPASCAL_IL);
+    let err = compile_error("div-pragma", &src, &[]);
+    assert!(
+        err.starts_with("Floored division is not available for kPascal IL"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn kpascal_il_symmetric_option_overrides_floored_pragma() {
+    let dir = std::env::temp_dir().join(format!("kforthc-div-override-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("in.fth");
+    fs::write(&input, format!("( DIVISION FLOORED )\n{}", KPASCAL_IL)).unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_kforthc"))
+        .arg("--division=symmetric")
+        .arg(&input)
+        .arg(dir.join("out.ll"))
+        .output()
+        .unwrap();
+    let _ = fs::remove_dir_all(&dir);
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+}
+
+#[test]
+fn top_level_does_child_needs_compile_time_body() {
+    let src = ": CELLV CREATE , DOES> PVAR@ ;\n5 CELLV X\nX CONSTANT Y\n: MAIN Y . ;\nMAIN\n";
+    let err = compile_error("does-top", src, &[]);
+    assert!(
+        err.starts_with("Word PVAR@ is not supported at compile time in X at top-level"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn top_level_comma_needs_a_value() {
+    let err = compile_error("comma", "CREATE T , 5 ,\n: MAIN T PVAR@ . ;\nMAIN\n", &[]);
+    assert!(
+        err.starts_with("Top-level , requires a compile-time value before it"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn usage_errors_are_plain_text() {
+    let err = compile_error("usage", ": MAIN ; MAIN\n", &["--target=sparc"]);
+    assert!(
+        err.starts_with("Unknown --target value: sparc\nUsage: "),
+        "{}",
+        err
+    );
+}
@@ -0,0 +1,40 @@
+// Runs samples/forth and required_words_test.fth through `kforthc test` (the
+// interpreter), with and without --no-opt, against the sidecar files
+// described in SPEC.md.
+
+use std::path::Path;
+use std::process::Command;
+
+fn forth_test(extra: &[&str]) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let out = Command::new(env!("CARGO_BIN_EXE_kforthc"))
+        .arg("test")
+        .args(extra)
+        .arg(root.join("samples/forth"))
+        .arg(root.join("required_words_test.fth"))
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(
+        out.status.success(),
+        "{}{}",
+        stdout,
+        String::from_utf8_lossy(&out.stderr)
+    );
+    assert!(
+        stdout.contains(" passed, 0 failed, 0 skipped"),
+        "{}",
+        stdout
+    );
+    assert!(!stdout.contains("test result: 0 passed"), "{}", stdout);
+}
+
+#[test]
+fn forth_samples() {
+    forth_test(&[]);
+}
+
+#[test]
+fn forth_samples_no_opt() {
+    forth_test(&["--no-opt"]);
+}
@@ -0,0 +1,44 @@
+// `kforthc run` shows output written before a stdin read while the read blocks.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn prompt_is_flushed_before_read() {
+    let dir = std::env::temp_dir().join(format!("kforthc-prompt-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("in.fth");
+    fs::write(
+        &input,
+        ": MAIN S\" n? \" TYPE PREAD-I32 . PWRITELN ;\nMAIN\n",
+    )
+    .unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_kforthc"))
+        .arg("run")
+        .arg(&input)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut prompt = [0u8; 3];
+        stdout.read_exact(&mut prompt).unwrap();
+        tx.send(prompt).unwrap();
+        let _ = stdout.read_to_end(&mut Vec::new());
+    });
+    // stdin is still open, so the prompt must arrive on its own.
+    let prompt = rx.recv_timeout(Duration::from_secs(10));
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"5\n").unwrap();
+    drop(stdin);
+    let status = child.wait().unwrap();
+    let _ = fs::remove_dir_all(&dir);
+    assert_eq!(prompt.as_ref().map(|p| &p[..]), Ok(&b"n? "[..]));
+    assert!(status.success());
+}
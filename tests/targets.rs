@@ -0,0 +1,44 @@
+// --target: the `target` header and the runtime `declare` lines (riscv64
+// adds signext) against tests/targets/NAME.expected.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn target_header(name: &str) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let ir =
+        std::env::temp_dir().join(format!("kforthc-target-{}-{}.ll", name, std::process::id()));
+    let out = Command::new(env!("CARGO_BIN_EXE_kforthc"))
+        .arg(format!("--target={}", name))
+        .arg("--llvm-version=14")
+        .arg(root.join("samples/forth/01_value_to.fth"))
+        .arg(&ir)
+        .output()
+        .unwrap();
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    let text = fs::read_to_string(&ir).unwrap();
+    let _ = fs::remove_file(&ir);
+    let actual: String = text
+        .lines()
+        .filter(|l| l.starts_with("target ") || l.starts_with("declare "))
+        .map(|l| format!("{}\n", l))
+        .collect();
+    let expected =
+        fs::read_to_string(root.join(format!("tests/targets/{}.expected", name))).unwrap();
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn riscv64_header() {
+    target_header("riscv64");
+}
+
+#[test]
+fn i686_header() {
+    target_header("i686");
+}